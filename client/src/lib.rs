@@ -1,4 +1,4 @@
-use std::io;
+use std::{collections::BTreeMap, io, time::Duration};
 
 use common::{
     command::{Command, Response},
@@ -26,17 +26,57 @@ impl Client {
         }
     }
 
+    /// Fails with `ErrorKind::InvalidInput` if the server refused the write
     pub fn put(&mut self, key: &[u8], value: Vec<u8>) -> io::Result<()> {
         self.connection
-            .send_command(Command::Put((key.to_vec(), value)))
+            .send_command(Command::Put((key.to_vec(), value)))?;
+        self.read_write_response()
     }
 
+    pub fn put_with_ttl(&mut self, key: &[u8], value: Vec<u8>, ttl: Duration) -> io::Result<()> {
+        self.connection
+            .send_command(Command::PutWithTtl((key.to_vec(), value, ttl)))?;
+        self.read_write_response()
+    }
+
+    fn read_write_response(&mut self) -> io::Result<()> {
+        loop {
+            match self.connection.read_response() {
+                Ok(Response::Ok) => return Ok(()),
+                Ok(Response::Error(message)) => {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, message))
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Fails with the server's message if it couldn't read the key
     pub fn get(&mut self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
         self.connection.send_command(Command::Get(key.to_vec()))?;
-        loop {
-            if let Ok(Response::Value(value)) = self.connection.read_response() {
+        let response = self
+            .connection
+            .read_response()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        match response {
+            Response::Value(value) => {
                 println!("Got value from server for key {:?}", key);
-                return Ok(value);
+                Ok(value)
+            }
+            Response::Error(message) => Err(io::Error::other(message)),
+            response => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Unexpected response to a get: {:?}", response),
+            )),
+        }
+    }
+
+    /// The server's store statistics, e.g. `puts` or `get_micros.p99`
+    pub fn stats(&mut self) -> io::Result<BTreeMap<String, u64>> {
+        self.connection.send_command(Command::Stats)?;
+        loop {
+            if let Ok(Response::Stats(stats)) = self.connection.read_response() {
+                return Ok(stats);
             }
         }
     }
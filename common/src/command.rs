@@ -1,14 +1,24 @@
+use std::{collections::BTreeMap, time::Duration};
+
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Command {
     Ping,
     Put((Vec<u8>, Vec<u8>)),
+    PutWithTtl((Vec<u8>, Vec<u8>, Duration)),
     Get(Vec<u8>),
+    Stats,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Response {
     Pong,
     Value(Option<Vec<u8>>),
+    /// The write was applied
+    Ok,
+    /// The command failed, e.g. because a key or value was over the store's size limits
+    Error(String),
+    /// The store's statistics, named as in `StoreStats::to_map`
+    Stats(BTreeMap<String, u64>),
 }
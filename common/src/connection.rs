@@ -32,14 +32,14 @@ impl Connection {
 
     pub fn send_command(&mut self, cmd: Command) -> io::Result<()> {
         let json = serde_json::to_string(&cmd)?;
-        self.writer.write_all(&json.as_bytes())?;
+        self.writer.write_all(json.as_bytes())?;
         self.writer.flush()?;
         Ok(())
     }
 
     pub fn send_response(&mut self, resp: Response) -> io::Result<()> {
         let json = serde_json::to_string(&resp)?;
-        self.writer.write_all(&json.as_bytes())?;
+        self.writer.write_all(json.as_bytes())?;
         self.writer.flush()?;
         Ok(())
     }
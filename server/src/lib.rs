@@ -1,24 +1,31 @@
 use std::{
+    io,
     net::{SocketAddr, TcpListener},
     path::Path,
-    sync::{Arc, RwLock},
+    sync::Arc,
 };
 
 use common::{
     command::{Command, Response},
     connection::Connection,
 };
-use store::Store;
+use store::{Store, StoreOptions};
 
 pub struct Server {
     pub listener: TcpListener,
-    pub store: Arc<RwLock<Store>>,
+    pub store: Arc<Store>,
 }
 
 impl Server {
     pub fn new(addr: SocketAddr, store_dir: &Path, keep_existing_dir: bool) -> Self {
-        let listener = TcpListener::bind(&addr).unwrap();
-        let store = Arc::new(RwLock::new(Store::new(&store_dir, keep_existing_dir)));
+        let listener = TcpListener::bind(addr).unwrap();
+        let store = Arc::new(Store::new(store_dir, keep_existing_dir));
+        Self { store, listener }
+    }
+
+    pub fn with_options(addr: SocketAddr, store_dir: &Path, options: &StoreOptions) -> Self {
+        let listener = TcpListener::bind(addr).unwrap();
+        let store = Arc::new(Store::open(store_dir, options).unwrap());
         Self { store, listener }
     }
 
@@ -31,7 +38,7 @@ impl Server {
         }
     }
 
-    fn handle_client(mut connection: Connection, store: Arc<RwLock<Store>>) {
+    fn handle_client(mut connection: Connection, store: Arc<Store>) {
         println!("Client connected from {}", connection.addr);
 
         loop {
@@ -39,13 +46,37 @@ impl Server {
                 dbg!("Got command {:?}", &cmd);
                 match cmd {
                     Command::Ping => connection.send_response(Response::Pong).unwrap(),
-                    Command::Put((key, value)) => store.write().unwrap().put(&key, &value),
+                    Command::Put((key, value)) => {
+                        let result = store.put(&key, &value);
+                        connection
+                            .send_response(Self::write_response(result))
+                            .unwrap()
+                    }
+                    Command::PutWithTtl((key, value, ttl)) => {
+                        let result = store.put_with_ttl(&key, &value, ttl);
+                        connection
+                            .send_response(Self::write_response(result))
+                            .unwrap()
+                    }
                     Command::Get(key) => {
-                        let value = store.read().unwrap().get(&key);
-                        connection.send_response(Response::Value(value)).unwrap()
+                        let response = match store.get(&key) {
+                            Ok(value) => Response::Value(value),
+                            Err(e) => Response::Error(e.to_string()),
+                        };
+                        connection.send_response(response).unwrap()
                     }
+                    Command::Stats => connection
+                        .send_response(Response::Stats(store.stats().to_map()))
+                        .unwrap(),
                 }
             }
         }
     }
+
+    fn write_response(result: io::Result<()>) -> Response {
+        match result {
+            Ok(()) => Response::Ok,
+            Err(e) => Response::Error(e.to_string()),
+        }
+    }
 }
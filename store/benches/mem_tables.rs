@@ -0,0 +1,82 @@
+use std::{fs, path::Path, thread};
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use store::{MemTableKind, Store, StoreOptions};
+
+const MEM_TABLE_KINDS: [MemTableKind; 2] = [MemTableKind::BTreeMap, MemTableKind::SkipList];
+
+fn store_for(name: &str, mem_table_kind: MemTableKind) -> Store {
+    let dir = format!("tmp_bench_files/mem_tables/{}_{:?}", name, mem_table_kind);
+    let _ = fs::remove_dir_all(&dir);
+    // Keep everything in the mem table, so we're only measuring the mem table
+    let options = StoreOptions::new()
+        .mem_table_kind(mem_table_kind)
+        .mem_table_size_limit_in_bytes(1024 * 1024 * 100);
+    Store::open(Path::new(&dir), &options).unwrap()
+}
+
+fn puts(c: &mut Criterion) {
+    let n = 100_000;
+    let keys: Vec<Vec<u8>> = (0_u32..n).map(|v| v.to_be_bytes().to_vec()).collect();
+
+    let mut group = c.benchmark_group("100000 puts");
+    for mem_table_kind in MEM_TABLE_KINDS {
+        let store = store_for("puts", mem_table_kind);
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", mem_table_kind)),
+            &keys,
+            |b, keys| {
+                b.iter(|| {
+                    for key in keys.iter() {
+                        store.put(black_box(key), black_box(key)).unwrap();
+                    }
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+fn gets_while_writing(c: &mut Criterion) {
+    let n = 100_000;
+    let readers = 4;
+    let keys: Vec<Vec<u8>> = (0_u32..n).map(|v| v.to_be_bytes().to_vec()).collect();
+
+    let mut group = c.benchmark_group("100000 gets per reader, 4 readers and a writer");
+    for mem_table_kind in MEM_TABLE_KINDS {
+        let store = store_for("gets_while_writing", mem_table_kind);
+        for key in keys.iter() {
+            store.put(key, key).unwrap();
+        }
+        group.bench_with_input(
+            BenchmarkId::from_parameter(format!("{:?}", mem_table_kind)),
+            &keys,
+            |b, keys| {
+                b.iter(|| {
+                    thread::scope(|scope| {
+                        scope.spawn(|| {
+                            for key in keys.iter() {
+                                store.put(key, key).unwrap();
+                            }
+                        });
+                        for _ in 0..readers {
+                            scope.spawn(|| {
+                                for key in keys.iter() {
+                                    black_box(store.get(key).unwrap());
+                                }
+                            });
+                        }
+                    });
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(
+    name = benches;
+    config = Criterion::default().sample_size(10);
+    targets = puts, gets_while_writing
+);
+criterion_main!(benches);
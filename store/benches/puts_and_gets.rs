@@ -1,51 +1,87 @@
-use std::path::Path;
+use std::{fs, path::Path};
 
 use criterion::{black_box, criterion_group, criterion_main, Criterion};
-use store::Store;
+use store::{Store, StoreOptions};
 
-fn insert_records(store: &mut Store, keys: &[Vec<u8>]) {
+fn open_fresh(dir: &str, options: StoreOptions) -> Store {
+    let _ = fs::remove_dir_all(dir);
+    Store::open(Path::new(dir), &options).unwrap()
+}
+
+fn insert_records(store: &Store, keys: &[Vec<u8>]) {
     for key in keys.iter() {
-        store.put(key, key);
+        store.put(key, key).unwrap();
     }
 }
 
 fn insert_1_000_000_fit_in_memory(c: &mut Criterion) {
-    let mut store = Store::new(
-        Path::new("tmp_bench_files/insert_1_000_000_will_fit_in_memory"),
-        false,
+    let store = open_fresh(
+        "tmp_bench_files/insert_1_000_000_will_fit_in_memory",
+        StoreOptions::new().mem_table_size_limit_in_bytes(1024 * 1024 * 100),
     );
-    store.mem_table_size_limit_in_bytes = 1024 * 1024 * 100;
     let n = 1_000_000;
     let keys: Vec<Vec<u8>> = (0_u32..n)
-        .into_iter()
         .map(|v| (v + 5000000).to_le_bytes().to_vec())
         .collect();
 
     c.bench_function("1000000 records, all fit in memory", |b| {
-        b.iter(|| insert_records(black_box(&mut store), black_box(&keys)))
+        b.iter(|| insert_records(black_box(&store), black_box(&keys)))
     });
 }
 
 fn insert_1_000_000_wont_fit_in_memory(c: &mut Criterion) {
-    let mut store = Store::new(
-        Path::new("tmp_bench_files/insert_1_000_000_wont_fit_in_memory"),
-        false,
+    let store = open_fresh(
+        "tmp_bench_files/insert_1_000_000_wont_fit_in_memory",
+        StoreOptions::new().mem_table_size_limit_in_bytes(1024 * 1024 * 4),
     );
-    store.mem_table_size_limit_in_bytes = 1024 * 1024 * 4;
     let n = 1_000_000;
-    let keys: Vec<Vec<u8>> = (0_u32..n)
-        .into_iter()
-        .map(|v| (v).to_le_bytes().to_vec())
-        .collect();
+    let keys: Vec<Vec<u8>> = (0_u32..n).map(|v| (v).to_le_bytes().to_vec()).collect();
 
     c.bench_function("1000000 records, wont all fit in memory", |b| {
-        b.iter(|| insert_records(black_box(&mut store), black_box(&keys)))
+        b.iter(|| insert_records(black_box(&store), black_box(&keys)))
     });
 }
 
+fn get_records(store: &Store, keys: &[Vec<u8>]) {
+    for key in keys.iter() {
+        black_box(store.get(key).unwrap());
+    }
+}
+
+/// Every read goes to a store file, with the block cache off so each one reads its block from
+/// the file. Compares opening the file on every read against keeping it open
+fn get_100_000_from_store_files(c: &mut Criterion) {
+    let n = 100_000;
+    let keys: Vec<Vec<u8>> = (0_u32..n).map(|v| v.to_le_bytes().to_vec()).collect();
+
+    for max_open_files in [0, 1000] {
+        let store = open_fresh(
+            &format!(
+                "tmp_bench_files/get_100_000_max_open_files_{}",
+                max_open_files
+            ),
+            StoreOptions::new()
+                .block_cache_size_in_bytes(0)
+                .max_open_files(max_open_files),
+        );
+        insert_records(&store, &keys);
+        store.flush_pending_writes().unwrap();
+
+        c.bench_function(
+            &format!(
+                "100000 gets from store files, max_open_files {}",
+                max_open_files
+            ),
+            |b| b.iter(|| get_records(black_box(&store), black_box(&keys))),
+        );
+    }
+}
+
 criterion_group!(
     name = benches;
     config = Criterion::default().sample_size(10);
-    targets = insert_1_000_000_fit_in_memory, insert_1_000_000_wont_fit_in_memory
+    targets = insert_1_000_000_fit_in_memory,
+        insert_1_000_000_wont_fit_in_memory,
+        get_100_000_from_store_files
 );
 criterion_main!(benches);
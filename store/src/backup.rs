@@ -0,0 +1,400 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{create_temp_dir_for, now_in_millis, sync_dir, Store, STORE_FILENAME_SUFFIX};
+
+/// Files from every backup, named after their name in the store and their contents, so backups
+/// that share a file share it here
+const SHARED_DIRNAME: &str = "shared";
+
+/// One file per backup, listing the files it is made of
+const META_DIRNAME: &str = "meta";
+
+/// Where each backup's checkpoint is taken, before its files are moved into shared
+const CHECKPOINT_DIRNAME: &str = "checkpoint";
+
+/// How much of two files is read at a time when comparing them
+const COMPARE_CHUNK_SIZE: u64 = 64 * 1024;
+
+/// A backup as listed by `BackupEngine::backups`
+#[derive(Clone, Debug, PartialEq)]
+pub struct BackupInfo {
+    pub id: u32,
+    /// Milliseconds since the unix epoch
+    pub created_at: u64,
+    /// Size of every file in the backup, including those shared with other backups
+    pub size_in_bytes: u64,
+    pub file_count: usize,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupMeta {
+    id: u32,
+    created_at: u64,
+    files: Vec<BackupFile>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct BackupFile {
+    /// Where the file goes in a restored store directory, e.g. `column_families/users/4.store.kv`
+    path: PathBuf,
+    /// Its name in the shared directory
+    shared_name: String,
+    size_in_bytes: u64,
+    crc32: u32,
+}
+
+impl BackupMeta {
+    fn info(&self) -> BackupInfo {
+        BackupInfo {
+            id: self.id,
+            created_at: self.created_at,
+            size_in_bytes: self.files.iter().map(|file| file.size_in_bytes).sum(),
+            file_count: self.files.len(),
+        }
+    }
+}
+
+fn corrupt_backup(id: u32, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Backup {} is corrupt: {}", id, reason),
+    )
+}
+
+fn backup_not_found(id: u32) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("No backup {}", id))
+}
+
+/// Size and crc32 of a file, read in one pass
+fn size_and_crc32(path: &Path) -> io::Result<(u64, u32)> {
+    let mut hasher = crc32fast::Hasher::new();
+    let mut file = File::open(path)?;
+    let size_in_bytes = io::copy(&mut file, &mut HasherWriter(&mut hasher))?;
+    Ok((size_in_bytes, hasher.finalize()))
+}
+
+struct HasherWriter<'a>(&'a mut crc32fast::Hasher);
+
+impl io::Write for HasherWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Whether the two files hold exactly the same bytes
+fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut a, mut b) = (File::open(a)?, File::open(b)?);
+    let (mut a_chunk, mut b_chunk) = (Vec::new(), Vec::new());
+    loop {
+        a_chunk.clear();
+        b_chunk.clear();
+        (&mut a)
+            .take(COMPARE_CHUNK_SIZE)
+            .read_to_end(&mut a_chunk)?;
+        (&mut b)
+            .take(COMPARE_CHUNK_SIZE)
+            .read_to_end(&mut b_chunk)?;
+        if a_chunk != b_chunk {
+            return Ok(false);
+        }
+        if a_chunk.is_empty() {
+            return Ok(true);
+        }
+    }
+}
+
+/// Paths of every file under dir, relative to it
+fn files_under(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![PathBuf::new()];
+    while let Some(relative_dir) = dirs.pop() {
+        for entry in fs::read_dir(dir.join(&relative_dir))? {
+            let entry = entry?;
+            let relative_path = relative_dir.join(entry.file_name());
+            if entry.file_type()?.is_dir() {
+                dirs.push(relative_path);
+            } else {
+                files.push(relative_path);
+            }
+        }
+    }
+    files.sort();
+    return Ok(files);
+}
+
+/// Keeps any number of backups of a store in one directory. Each backup starts as a checkpoint of
+/// the store, and only the files that no earlier backup already has are kept from it, so backing
+/// up again after a few flushes only adds the new store files and the WAL.
+///
+/// Every file is stored under its name in the store, size and crc32, which restores and
+/// `verify_backup` check it against. A crc32 can't tell files apart on its own, so a backup only
+/// shares a file already in the backup directory once their contents have been compared. The
+/// backup directory is locked while a BackupEngine has it open
+pub struct BackupEngine {
+    dir: PathBuf,
+    /// Released when the BackupEngine is dropped
+    _lock_file: File,
+}
+
+impl BackupEngine {
+    /// Opens the backup directory, creating it if it doesn't exist yet
+    pub fn open(backup_dir: &Path) -> io::Result<Self> {
+        fs::create_dir_all(backup_dir.join(SHARED_DIRNAME))?;
+        fs::create_dir_all(backup_dir.join(META_DIRNAME))?;
+        let lock_file = Store::lock_dir(backup_dir).map_err(|e| match e.kind() {
+            io::ErrorKind::WouldBlock => io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("The backups in {:?} are already open elsewhere", backup_dir),
+            ),
+            _ => e,
+        })?;
+        Ok(BackupEngine {
+            dir: backup_dir.to_path_buf(),
+            _lock_file: lock_file,
+        })
+    }
+
+    /// Backs up the store while it carries on serving reads and writes
+    pub fn create_new_backup(&self, store: &Store) -> io::Result<BackupInfo> {
+        let checkpoint_dir = self.dir.join(CHECKPOINT_DIRNAME);
+        // Left behind by a backup that didn't finish
+        if let Err(e) = fs::remove_dir_all(&checkpoint_dir) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        store.checkpoint(&checkpoint_dir)?;
+
+        let shared_dir = self.dir.join(SHARED_DIRNAME);
+        // Store files never change once written, so one that an earlier backup has under the same
+        // path and size is taken to be the same file without reading it again
+        let backed_up_store_files: HashMap<_, _> = self
+            .load_metas()?
+            .into_iter()
+            .flat_map(|meta| meta.files)
+            .filter(|file| file.path.to_string_lossy().ends_with(STORE_FILENAME_SUFFIX))
+            .map(|file| {
+                (
+                    (file.path, file.size_in_bytes),
+                    (file.shared_name, file.crc32),
+                )
+            })
+            .collect();
+        let mut files = Vec::new();
+        for path in files_under(&checkpoint_dir)? {
+            let checkpoint_path = checkpoint_dir.join(&path);
+            let size_in_bytes = fs::metadata(&checkpoint_path)?.len();
+            let (shared_name, crc32) =
+                match backed_up_store_files.get(&(path.clone(), size_in_bytes)) {
+                    Some((shared_name, crc32)) => (shared_name.clone(), *crc32),
+                    None => {
+                        let (_, crc32) = size_and_crc32(&checkpoint_path)?;
+                        let shared_name = Self::share_file(
+                            &shared_dir,
+                            &checkpoint_path,
+                            &path,
+                            size_in_bytes,
+                            crc32,
+                        )?;
+                        (shared_name, crc32)
+                    }
+                };
+            files.push(BackupFile {
+                path,
+                shared_name,
+                size_in_bytes,
+                crc32,
+            });
+        }
+        sync_dir(&shared_dir)?;
+        fs::remove_dir_all(&checkpoint_dir)?;
+
+        let meta = BackupMeta {
+            id: self.last_backup_id()? + 1,
+            created_at: now_in_millis(),
+            files,
+        };
+        self.save_meta(&meta)?;
+        Ok(meta.info())
+    }
+
+    /// Moves a file from the checkpoint into the shared directory, unless a file with the same
+    /// contents is already there, and returns its name there. Different files that happen to have
+    /// the same name, size and crc32 are told apart by a counter on the end
+    fn share_file(
+        shared_dir: &Path,
+        checkpoint_path: &Path,
+        path: &Path,
+        size_in_bytes: u64,
+        crc32: u32,
+    ) -> io::Result<String> {
+        let name = format!(
+            "{}_{:08x}_{}",
+            path.file_name().unwrap().to_string_lossy(),
+            crc32,
+            size_in_bytes
+        );
+        let mut shared_name = name.clone();
+        let mut collisions = 0;
+        loop {
+            let shared_path = shared_dir.join(&shared_name);
+            if !shared_path.exists() {
+                // Moved rather than copied again. Store files that were hard linked into the
+                // checkpoint stay linked to the store's own, which is safe since they never
+                // change once written
+                fs::rename(checkpoint_path, &shared_path)?;
+                return Ok(shared_name);
+            }
+            if same_contents(&shared_path, checkpoint_path)? {
+                return Ok(shared_name);
+            }
+            collisions += 1;
+            shared_name = format!("{}_{}", name, collisions);
+        }
+    }
+
+    /// Every backup, oldest first
+    pub fn backups(&self) -> io::Result<Vec<BackupInfo>> {
+        Ok(self.load_metas()?.iter().map(|meta| meta.info()).collect())
+    }
+
+    /// Checks that every file in the backup is there, with the size and checksum it was backed up
+    /// with. Fails with `ErrorKind::InvalidData` if any file is missing or different
+    pub fn verify_backup(&self, backup_id: u32) -> io::Result<()> {
+        let meta = self.load_meta(backup_id)?;
+        for file in &meta.files {
+            let shared_path = self.dir.join(SHARED_DIRNAME).join(&file.shared_name);
+            let (size_in_bytes, crc32) = match size_and_crc32(&shared_path) {
+                Ok(size_and_crc32) => size_and_crc32,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    return Err(corrupt_backup(
+                        backup_id,
+                        &format!("{:?} is missing", file.path),
+                    ))
+                }
+                Err(e) => return Err(e),
+            };
+            if size_in_bytes != file.size_in_bytes || crc32 != file.crc32 {
+                return Err(corrupt_backup(
+                    backup_id,
+                    &format!("{:?} doesn't match its checksum", file.path),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes all but the newest num_backups_to_keep backups, along with any files that only they
+    /// were using
+    pub fn purge_old_backups(&self, num_backups_to_keep: usize) -> io::Result<()> {
+        let metas = self.load_metas()?;
+        let purge_count = metas.len().saturating_sub(num_backups_to_keep);
+        for meta in &metas[..purge_count] {
+            fs::remove_file(self.meta_path(meta.id))?;
+        }
+        sync_dir(&self.dir.join(META_DIRNAME))?;
+
+        // Also cleans up after backups that didn't finish
+        let used: HashSet<_> = metas[purge_count..]
+            .iter()
+            .flat_map(|meta| meta.files.iter().map(|file| file.shared_name.clone()))
+            .collect();
+        for entry in fs::read_dir(self.dir.join(SHARED_DIRNAME))? {
+            let entry = entry?;
+            if !used.contains(entry.file_name().to_string_lossy().as_ref()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Restores a backup into dest_dir, which must not exist yet, as a store that opens like any
+    /// other. Files are copied rather than linked, since the store will go on to change its WAL.
+    /// Every file is checked against its checksum on the way, and nothing is left at dest_dir
+    /// unless the whole backup was restored
+    pub fn restore(&self, backup_id: u32, dest_dir: &Path) -> io::Result<()> {
+        let meta = self.load_meta(backup_id)?;
+        let unfinished_dir = create_temp_dir_for(dest_dir)?;
+        let temp_dir = unfinished_dir.path();
+        for file in &meta.files {
+            let restored_path = temp_dir.join(&file.path);
+            if let Some(parent) = restored_path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::copy(
+                self.dir.join(SHARED_DIRNAME).join(&file.shared_name),
+                &restored_path,
+            )?;
+            let (size_in_bytes, crc32) = size_and_crc32(&restored_path)?;
+            if size_in_bytes != file.size_in_bytes || crc32 != file.crc32 {
+                return Err(corrupt_backup(
+                    backup_id,
+                    &format!("{:?} doesn't match its checksum", file.path),
+                ));
+            }
+            File::open(&restored_path)?.sync_all()?;
+        }
+        for dir in meta
+            .files
+            .iter()
+            .filter_map(|file| file.path.parent())
+            .collect::<HashSet<_>>()
+        {
+            sync_dir(&temp_dir.join(dir))?;
+        }
+        unfinished_dir.move_into_place(dest_dir)
+    }
+
+    fn meta_path(&self, backup_id: u32) -> PathBuf {
+        self.dir.join(META_DIRNAME).join(backup_id.to_string())
+    }
+
+    fn load_meta(&self, backup_id: u32) -> io::Result<BackupMeta> {
+        match fs::read(self.meta_path(backup_id)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Err(backup_not_found(backup_id)),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Oldest first
+    fn load_metas(&self) -> io::Result<Vec<BackupMeta>> {
+        let mut metas = Vec::new();
+        for entry in fs::read_dir(self.dir.join(META_DIRNAME))? {
+            let entry = entry?;
+            // Skips metas that were never finished being written
+            if let Ok(id) = entry.file_name().to_string_lossy().parse() {
+                metas.push(self.load_meta(id)?);
+            }
+        }
+        metas.sort_by_key(|meta| meta.id);
+        return Ok(metas);
+    }
+
+    fn last_backup_id(&self) -> io::Result<u32> {
+        Ok(self.load_metas()?.last().map_or(0, |meta| meta.id))
+    }
+
+    /// Written to a temporary file first, same as the manifest, so a backup is either listed in
+    /// full or not at all
+    fn save_meta(&self, meta: &BackupMeta) -> io::Result<()> {
+        let meta_dir = self.dir.join(META_DIRNAME);
+        let temp_path = meta_dir.join(meta.id.to_string() + ".temp");
+        fs::write(&temp_path, serde_json::to_vec_pretty(meta)?)?;
+        File::open(&temp_path)?.sync_all()?;
+        fs::rename(temp_path, self.meta_path(meta.id))?;
+        sync_dir(&meta_dir)
+    }
+}
@@ -0,0 +1,120 @@
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex,
+};
+
+use crate::lru::Lru;
+
+/// Splitting the cache up means reads of different blocks rarely wait on each other's locks
+const SHARD_COUNT: usize = 16;
+
+/// Block offsets are only unique within a file, and file ids are only unique within a column
+/// family, so every open store file gets its own id from the cache to key its blocks with
+type CacheKey = (u64, u64);
+
+/// Hit and miss counts since the Store was opened, for sizing `block_cache_size_in_bytes`
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BlockCacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// Bytes of decompressed blocks currently held
+    pub usage_in_bytes: u64,
+    pub capacity_in_bytes: u64,
+}
+
+/// Decompressed data blocks, shared by every store file in a Store. Evicts whichever blocks in a
+/// shard were used least recently once the shard is over its share of the capacity
+pub(crate) struct BlockCache {
+    shards: Vec<Mutex<Lru<CacheKey, Arc<Vec<u8>>>>>,
+    capacity_in_bytes: u64,
+    shard_capacity_in_bytes: u64,
+    next_file_id: AtomicU64,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlockCache {
+    /// A capacity of 0 turns caching off
+    pub(crate) fn new(capacity_in_bytes: u64) -> Self {
+        BlockCache {
+            shards: (0..SHARD_COUNT).map(|_| Mutex::new(Lru::new())).collect(),
+            capacity_in_bytes,
+            shard_capacity_in_bytes: capacity_in_bytes / SHARD_COUNT as u64,
+            next_file_id: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    /// Called once per store file as it is opened
+    pub(crate) fn new_file_id(&self) -> u64 {
+        self.next_file_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    fn shard_for(&self, key: CacheKey) -> &Mutex<Lru<CacheKey, Arc<Vec<u8>>>> {
+        // Blocks of one file are spread out by their offsets, which are rarely multiples of the
+        // shard count apart
+        let hash = key.0.wrapping_mul(0x9e37_79b9_7f4a_7c15) ^ key.1;
+        &self.shards[(hash % SHARD_COUNT as u64) as usize]
+    }
+
+    pub(crate) fn get(&self, file_id: u64, block_offset: u64) -> Option<Arc<Vec<u8>>> {
+        let key = (file_id, block_offset);
+        let block = self.shard_for(key).lock().unwrap().get(key);
+        let counter = if block.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        return block;
+    }
+
+    pub(crate) fn insert(&self, file_id: u64, block_offset: u64, block: Arc<Vec<u8>>) {
+        if self.shard_capacity_in_bytes == 0 {
+            return;
+        }
+        let key = (file_id, block_offset);
+        let charge = block.len() as u64;
+        self.shard_for(key).lock().unwrap().insert(
+            key,
+            block,
+            charge,
+            self.shard_capacity_in_bytes,
+        );
+    }
+
+    pub(crate) fn stats(&self) -> BlockCacheStats {
+        BlockCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            usage_in_bytes: self
+                .shards
+                .iter()
+                .map(|shard| shard.lock().unwrap().usage)
+                .sum(),
+            capacity_in_bytes: self.capacity_in_bytes,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hits_and_misses_are_counted() {
+        let cache = BlockCache::new(1024 * 1024);
+        let file_id = cache.new_file_id();
+        assert!(cache.get(file_id, 0).is_none());
+        cache.insert(file_id, 0, Arc::new(vec![7; 100]));
+        assert_eq!(*cache.get(file_id, 0).unwrap(), vec![7; 100]);
+        assert!(cache.get(cache.new_file_id(), 0).is_none());
+
+        let stats = cache.stats();
+        assert_eq!(stats.hits, 1);
+        assert_eq!(stats.misses, 2);
+        assert_eq!(stats.usage_in_bytes, 100);
+        assert_eq!(stats.capacity_in_bytes, 1024 * 1024);
+    }
+}
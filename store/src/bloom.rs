@@ -0,0 +1,105 @@
+/// A bloom filter over the keys in a store file, so most reads for keys the file doesn't have
+/// never need to read a data block
+#[derive(Debug, PartialEq)]
+pub(crate) struct BloomFilter {
+    hash_count: u32,
+    bits: Vec<u8>,
+}
+
+/// Hashes a key into the first of the bit positions to set. The rest are found by repeatedly
+/// adding a delta derived from the same hash, so each key only needs hashing once
+fn bloom_hash(key: &[u8]) -> u64 {
+    // FNV-1a
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in key {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    return hash;
+}
+
+impl BloomFilter {
+    pub(crate) fn build<'a>(
+        keys: impl ExactSizeIterator<Item = &'a [u8]>,
+        bits_per_key: u32,
+    ) -> Self {
+        // Rounding ln(2) * bits_per_key gives the fewest false positives
+        let hash_count = ((bits_per_key as f64 * 0.69) as u32).clamp(1, 30);
+        let bit_count = (keys.len() * bits_per_key as usize).max(64);
+        let mut filter = BloomFilter {
+            hash_count,
+            bits: vec![0; bit_count.div_ceil(8)],
+        };
+        for key in keys {
+            let bit_count = filter.bit_count();
+            let mut hash = bloom_hash(key);
+            let delta = hash.rotate_left(17);
+            for _ in 0..filter.hash_count {
+                let bit = hash % bit_count;
+                filter.bits[(bit / 8) as usize] |= 1 << (bit % 8);
+                hash = hash.wrapping_add(delta);
+            }
+        }
+        return filter;
+    }
+
+    fn bit_count(&self) -> u64 {
+        self.bits.len() as u64 * 8
+    }
+
+    /// False means the key is definitely not in the file
+    pub(crate) fn may_contain(&self, key: &[u8]) -> bool {
+        let bit_count = self.bit_count();
+        let mut hash = bloom_hash(key);
+        let delta = hash.rotate_left(17);
+        for _ in 0..self.hash_count {
+            let bit = hash % bit_count;
+            if self.bits[(bit / 8) as usize] & (1 << (bit % 8)) == 0 {
+                return false;
+            }
+            hash = hash.wrapping_add(delta);
+        }
+        return true;
+    }
+
+    /// Encoded as:
+    ///     hash_count (4 bytes)
+    ///     bits (the rest)
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(4 + self.bits.len());
+        bytes.extend_from_slice(&self.hash_count.to_le_bytes());
+        bytes.extend_from_slice(&self.bits);
+        return bytes;
+    }
+
+    /// Returns None if bytes isn't an encoded filter
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() <= 4 {
+            return None;
+        }
+        let hash_count = u32::from_le_bytes(bytes[0..4].try_into().unwrap());
+        Some(BloomFilter {
+            hash_count,
+            bits: bytes[4..].to_vec(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filters_have_no_false_negatives_and_few_false_positives() {
+        let keys: Vec<Vec<u8>> = (0..10_000_u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let filter = BloomFilter::build(keys.iter().map(|key| key.as_slice()), 10);
+        let filter = BloomFilter::decode(&filter.encode()).unwrap();
+
+        assert!(keys.iter().all(|key| filter.may_contain(key)));
+        let false_positives = (10_000..20_000_u32)
+            .filter(|i| filter.may_contain(&i.to_be_bytes()))
+            .count();
+        // About 1% is expected with 10 bits per key
+        assert!(false_positives < 300, "{} false positives", false_positives);
+    }
+}
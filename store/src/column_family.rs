@@ -0,0 +1,750 @@
+use std::{
+    cmp::Ordering as KeyOrdering,
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::Instant,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comparator::{Comparator, OrderedKey},
+    event_listener::{CompactionBeginInfo, CompactionInfo, EventListeners},
+    is_expired, link_or_copy,
+    mem_table::MemTable,
+    options::invalid_option,
+    range_tombstone::RangeTombstone,
+    store_file::{StoreFile, StoreFileCaches, StoreFileStats, StoreFileWriter},
+    sync_dir,
+    value_log::{ValueLog, ValueLogSegment},
+    Store, StoreOptions, TableEntry, COLUMN_FAMILIES_DIRNAME, DEFAULT_COLUMN_FAMILY,
+};
+
+/// When a column family's store files should be compacted without being asked to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum CompactionPolicy {
+    /// Only compact when `Store::compact` is called
+    #[default]
+    Manual,
+    /// Compact after a flush leaves the column family with at least this many store files
+    FileCount(usize),
+}
+
+impl CompactionPolicy {
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if *self == CompactionPolicy::FileCount(0) {
+            return Err(invalid_option(
+                "CompactionPolicy::FileCount needs a file count of at least 1",
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct ColumnFamilyOptions {
+    pub compaction_policy: CompactionPolicy,
+}
+
+/// Store files are written under these names, and only renamed to their real name once complete
+const FLUSH_TEMP_FILENAME: &str = "flush.temp";
+const COMPACTION_TEMP_FILENAME: &str = "compaction.temp";
+const INGEST_TEMP_FILENAME_PREFIX: &str = "ingest.temp";
+
+/// Compaction moves the live values out of value log segments that are less than 1 / this live,
+/// so the rest of the segment can be deleted
+const VALUE_LOG_GC_DIVISOR: u64 = 2;
+
+/// What a column family held at one point in time, for reading everything in it
+pub(crate) struct ColumnFamilySnapshot {
+    /// Newest first
+    mem_tables: Vec<Arc<dyn MemTable>>,
+    store_files: Arc<Vec<Arc<StoreFile>>>,
+}
+
+struct MemTables {
+    active: Arc<dyn MemTable>,
+    /// The previously active mem table while it is being written to disk. Reads still check it
+    /// until its store file is part of the file set
+    flushing: Option<Arc<dyn MemTable>>,
+}
+
+/// A logically separate keyspace within a Store. Each column family has its own mem table and
+/// store files, but writes for every column family go through the Store's shared WAL
+pub(crate) struct ColumnFamily {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) options: ColumnFamilyOptions,
+    pub(crate) comparator: Arc<dyn Comparator>,
+    pub(crate) dir: PathBuf,
+    current_file_id: AtomicU64,
+    store_options: StoreOptions,
+    caches: Arc<StoreFileCaches>,
+    value_log: ValueLog,
+    mem_tables: RwLock<MemTables>,
+    // TODO: Sparse index for keys in the store. Since the keys are
+    //     sorted, we only need to keep a subset of keys indexed. We can scan for the key in the
+    //     file if it isn't indexed already (keys are sorted, so we know at least what key the
+    //     requested key comes AFTER)
+    /// Newest first. Flushes and compactions swap in a new list rather than changing this one, so
+    /// readers can keep using whichever list they started with
+    pub(crate) store_files: RwLock<Arc<Vec<Arc<StoreFile>>>>,
+    /// Only one compaction runs per column family at a time
+    compaction_lock: Mutex<()>,
+}
+
+impl ColumnFamily {
+    /// The default column family keeps its store files directly in the store directory, so stores
+    /// created before column families existed open as they always did
+    pub(crate) fn dir_for(store_dir: &Path, name: &str) -> PathBuf {
+        if name == DEFAULT_COLUMN_FAMILY {
+            return store_dir.to_path_buf();
+        }
+        return store_dir.join(COLUMN_FAMILIES_DIRNAME).join(name);
+    }
+
+    pub(crate) fn open(
+        id: u32,
+        name: &str,
+        options: ColumnFamilyOptions,
+        dir: PathBuf,
+        store_options: &StoreOptions,
+        caches: &Arc<StoreFileCaches>,
+    ) -> io::Result<Self> {
+        if !store_options.read_only {
+            fs::create_dir_all(&dir)?;
+        }
+        let comparator = store_options.comparators.for_column_family(name);
+        let value_log = ValueLog::open(&dir, store_options.read_only, caches)?;
+        let (store_files, current_file_id) = Store::build_store_from_dir(
+            &dir,
+            store_options.read_only,
+            caches,
+            &value_log,
+            &comparator,
+        )?;
+        if !store_options.read_only {
+            let used_segment_ids: Vec<_> = store_files
+                .iter()
+                .flat_map(|file| file.properties.value_log_segment_ids.iter().copied())
+                .collect();
+            value_log.remove_unused_segments(&used_segment_ids)?;
+        }
+        Ok(ColumnFamily {
+            id,
+            name: name.to_string(),
+            options,
+            dir,
+            current_file_id: AtomicU64::new(current_file_id),
+            store_options: store_options.clone(),
+            caches: caches.clone(),
+            value_log,
+            mem_tables: RwLock::new(MemTables {
+                active: store_options.mem_table_kind.create(&comparator),
+                flushing: None,
+            }),
+            store_files: RwLock::new(Arc::new(store_files)),
+            compaction_lock: Mutex::new(()),
+            comparator,
+        })
+    }
+
+    #[cfg(test)]
+    pub(crate) fn current_file_id(&self) -> u64 {
+        self.current_file_id.load(Ordering::SeqCst)
+    }
+
+    /// Callers must hold the Store's writer lock, so writes land in the order they hit the WAL
+    pub(crate) fn insert(&self, key: Vec<u8>, entry: TableEntry) {
+        let active = self.mem_tables.read().unwrap().active.clone();
+        match entry {
+            // Hides the range in the mem table and everything older in one step, however many
+            // keys it covers
+            TableEntry::RangeTombstone { end } => {
+                active.delete_range(RangeTombstone { start: key, end })
+            }
+            entry => active.insert(key, entry),
+        }
+    }
+
+    fn value_for_table_entry(table_entry: &TableEntry) -> Option<Vec<u8>> {
+        match table_entry {
+            TableEntry::Tombstone => None,
+            TableEntry::Populated { expires_at, .. } if is_expired(*expires_at) => None,
+            TableEntry::Populated { value, .. } => Some(value.clone()),
+            TableEntry::InValueLog { .. } => {
+                unreachable!("Value pointers have to be read through their store file")
+            }
+            TableEntry::RangeTombstone { .. } => {
+                unreachable!("Range tombstones are never looked up by key")
+            }
+        }
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        // The mem tables have to be looked at before the store files. A flush only stops reads
+        // from checking its mem table once the new store file is in the file set, so whatever we
+        // miss in the mem tables is guaranteed to be in the store files we look at next
+        let (active, flushing) = {
+            let mem_tables = self.mem_tables.read().unwrap();
+            (mem_tables.active.clone(), mem_tables.flushing.clone())
+        };
+        for mem_table in std::iter::once(active).chain(flushing) {
+            if let Some(table_entry) = mem_table.get(key) {
+                return Ok(Self::value_for_table_entry(&table_entry));
+            }
+            if mem_table
+                .range_tombstones()
+                .covers(key, self.comparator.as_ref())
+            {
+                return Ok(None);
+            }
+        }
+
+        let store_files = self.store_files.read().unwrap().clone();
+        for store_file in store_files.iter() {
+            // Check our store files for the value. An expired key hides any older values for it,
+            // same as a tombstone would
+            match store_file.get(key)? {
+                Some(TableEntry::InValueLog { expires_at, .. }) if is_expired(expires_at) => {
+                    return Ok(None)
+                }
+                Some(TableEntry::InValueLog { pointer, .. }) => {
+                    return Ok(Some(store_file.read_value(&pointer)?))
+                }
+                Some(table_entry) => return Ok(Self::value_for_table_entry(&table_entry)),
+                None => {}
+            }
+            if store_file.range_deletes(key) {
+                return Ok(None);
+            }
+        }
+        return Ok(None);
+    }
+
+    fn allocate_file_id(&self) -> u64 {
+        self.current_file_id.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    fn install_store_file(
+        &self,
+        temp_path: &Path,
+        file_id: Option<u64>,
+        removed: &[Arc<StoreFile>],
+    ) -> io::Result<Arc<StoreFile>> {
+        let mut installed = self.install_store_files(&[(temp_path, file_id)], removed)?;
+        Ok(installed.remove(0))
+    }
+
+    /// Moves fully written store files into place, and swaps them into the file set in place of
+    /// removed in one step as far as readers are concerned. Flushed and ingested files are given
+    /// their id here, in order, so that a compaction can never end up with a newer id than data
+    /// added while it ran
+    fn install_store_files(
+        &self,
+        new_files: &[(&Path, Option<u64>)],
+        removed: &[Arc<StoreFile>],
+    ) -> io::Result<Vec<Arc<StoreFile>>> {
+        let mut store_files = self.store_files.write().unwrap();
+        let mut file_paths = Vec::with_capacity(new_files.len());
+        for (temp_path, file_id) in new_files {
+            let file_id = file_id.unwrap_or_else(|| self.allocate_file_id());
+            let file_path = Store::file_path_for_file_id(file_id, &self.dir);
+            fs::rename(temp_path, &file_path)?;
+            file_paths.push((file_id, file_path));
+        }
+        sync_dir(&self.dir)?;
+
+        let mut new_store_files: Vec<_> = store_files
+            .iter()
+            .filter(|file| !removed.iter().any(|removed| removed.id == file.id))
+            .cloned()
+            .collect();
+        let mut installed = Vec::with_capacity(file_paths.len());
+        for (file_id, file_path) in file_paths {
+            installed.push(Arc::new(StoreFile::open(
+                file_id,
+                file_path,
+                false,
+                &self.caches,
+                &self.value_log,
+                &self.comparator,
+            )?));
+        }
+        new_store_files.extend(installed.iter().cloned());
+        new_store_files.sort_by_key(|file| std::cmp::Reverse(file.id));
+        *store_files = Arc::new(new_store_files);
+        return Ok(installed);
+    }
+
+    /// Adds store files built by a `StoreFileWriter`, newest last, on top of everything already
+    /// in the column family. Callers must hold the Store's writer lock and have flushed the mem
+    /// table, so nothing in the column family is newer than the files. Every file is checked
+    /// before any is added, so a bad file leaves the column family as it was
+    pub(crate) fn ingest_store_files(&self, paths: &[PathBuf]) -> io::Result<()> {
+        for path in paths {
+            let store_file = StoreFile::open(
+                0,
+                path.clone(),
+                false,
+                &self.caches,
+                &self.value_log,
+                &self.comparator,
+            )?;
+            if !store_file.properties.value_log_segment_ids.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!(
+                        "{:?} points into a value log, so it can't be ingested on its own",
+                        path
+                    ),
+                ));
+            }
+        }
+
+        let mut temp_paths = Vec::with_capacity(paths.len());
+        for (i, path) in paths.iter().enumerate() {
+            let temp_path = self
+                .dir
+                .join(format!("{}{}", INGEST_TEMP_FILENAME_PREFIX, i));
+            // Left behind by an ingestion that didn't finish
+            if temp_path.exists() {
+                fs::remove_file(&temp_path)?;
+            }
+            link_or_copy(path, &temp_path)?;
+            temp_paths.push(temp_path);
+        }
+        let new_files: Vec<_> = temp_paths
+            .iter()
+            .map(|temp_path| (temp_path.as_path(), None))
+            .collect();
+        self.install_store_files(&new_files, &[])?;
+        Ok(())
+    }
+
+    /// Whether the active mem table has anything a flush would write out
+    pub(crate) fn has_unflushed_writes(&self) -> bool {
+        !self.mem_tables.read().unwrap().active.is_empty()
+    }
+
+    /// Assumes mem table keys are sorted!
+    /// Callers must hold the Store's writer lock, so nothing is written to the mem table we're
+    /// flushing. Returns the store file written, if the mem table had anything in it. If writing
+    /// it fails, the mem table goes back to being the active one
+    pub(crate) fn write_mem_table_to_disk(&self) -> io::Result<Option<Arc<StoreFile>>> {
+        let mem_table = {
+            let mut mem_tables = self.mem_tables.write().unwrap();
+            if mem_tables.active.is_empty() {
+                // Nothing to persist, and an empty store file would only slow down reads
+                return Ok(None);
+            }
+            let mem_table = std::mem::replace(
+                &mut mem_tables.active,
+                self.store_options.mem_table_kind.create(&self.comparator),
+            );
+            mem_tables.flushing = Some(mem_table.clone());
+            mem_table
+        };
+
+        let temp_path = self.dir.join(FLUSH_TEMP_FILENAME);
+        let store_file = self.write_store_file_from(mem_table.as_ref(), &temp_path);
+        let mut mem_tables = self.mem_tables.write().unwrap();
+        mem_tables.flushing = None;
+        match store_file {
+            Ok(store_file) => Ok(Some(store_file)),
+            Err(e) => {
+                // Nothing can have been written to the new mem table while we hold the writer
+                // lock, and the WAL still has everything in this one
+                mem_tables.active = mem_table;
+                let _ = fs::remove_file(&temp_path);
+                Err(e)
+            }
+        }
+    }
+
+    fn write_store_file_from(
+        &self,
+        mem_table: &dyn MemTable,
+        temp_path: &Path,
+    ) -> io::Result<Arc<StoreFile>> {
+        let mut writer = StoreFileWriter::create(
+            temp_path,
+            &self.store_options,
+            self.store_options.compression,
+            &self.comparator,
+        )?
+        .with_value_log(self.value_log.new_segment());
+        let mut added = Ok(());
+        mem_table.for_each(&mut |key, entry| {
+            if added.is_ok() {
+                added = writer.add(key, entry);
+            }
+        });
+        added?;
+        for tombstone in mem_table.range_tombstones().to_vec() {
+            writer.add_range_tombstone(tombstone);
+        }
+        writer.finish()?;
+        self.install_store_file(temp_path, None, &[])
+    }
+
+    /// Whether the store files in our directory are different to the ones we have loaded
+    pub(crate) fn store_files_changed(&self) -> bool {
+        let Ok(paths) = Store::get_store_files(&self.dir) else {
+            return true;
+        };
+        let mut ids_on_disk: Vec<_> = paths
+            .iter()
+            .map(|path| Store::file_id_from_path(path))
+            .collect();
+        ids_on_disk.sort_by_key(|id| std::cmp::Reverse(*id));
+        let loaded_ids: Vec<_> = self
+            .store_files
+            .read()
+            .unwrap()
+            .iter()
+            .map(|file| file.id)
+            .collect();
+        return ids_on_disk != loaded_ids;
+    }
+
+    /// The current store files, newest first. Holding on to them keeps them on disk, even if
+    /// they are compacted away in the meantime
+    pub(crate) fn store_files(&self) -> Arc<Vec<Arc<StoreFile>>> {
+        self.store_files.read().unwrap().clone()
+    }
+
+    pub(crate) fn store_file_count(&self) -> usize {
+        self.store_files.read().unwrap().len()
+    }
+
+    /// Newest first, same as the store files are read in
+    pub(crate) fn store_file_stats(&self) -> Vec<StoreFileStats> {
+        let store_files = self.store_files.read().unwrap().clone();
+        store_files.iter().map(|file| file.stats()).collect()
+    }
+
+    pub(crate) fn approximate_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        let store_files = self.store_files.read().unwrap().clone();
+        store_files
+            .iter()
+            .map(|file| file.approximate_size_of_range(start, end))
+            .sum()
+    }
+
+    /// Keys written to more than one store file are counted once per file, and tombstones are
+    /// taken to each remove one key
+    pub(crate) fn approximate_num_keys(&self) -> u64 {
+        let store_files = self.store_files.read().unwrap().clone();
+        let entry_count: u64 = store_files
+            .iter()
+            .map(|file| file.properties.entry_count)
+            .sum();
+        let tombstone_count: u64 = store_files
+            .iter()
+            .map(|file| file.properties.tombstone_count)
+            .sum();
+        entry_count.saturating_sub(tombstone_count * 2)
+    }
+
+    pub(crate) fn should_compact(&self) -> bool {
+        match self.options.compaction_policy {
+            CompactionPolicy::Manual => false,
+            CompactionPolicy::FileCount(file_count) => self.store_file_count() >= file_count,
+        }
+    }
+
+    /// Merges every current store file into one. Flushes can carry on while this runs, since the
+    /// merged file gets an id that sorts it before anything flushed in the meantime. Returns None
+    /// if there was nothing to compact. If it fails, the store files are left as they were
+    pub(crate) fn compact(&self, listeners: &EventListeners) -> io::Result<Option<CompactionInfo>> {
+        // TODO: Background thread!
+        let _compaction_guard = self.compaction_lock.lock().unwrap();
+        let started = Instant::now();
+
+        // The id is taken while no flush can be installing a file, so only files we're merging
+        // have older ids than the merged file
+        let (files_for_compaction, compaction_file_id) = {
+            let store_files = self.store_files.read().unwrap();
+            if store_files.is_empty() {
+                return Ok(None);
+            }
+            (store_files.clone(), self.allocate_file_id())
+        };
+        let input_file_ids: Vec<_> = files_for_compaction.iter().map(|file| file.id).collect();
+        let bytes_read = files_for_compaction
+            .iter()
+            .map(|file| file.size_in_bytes)
+            .sum();
+        listeners.notify(|listener| {
+            listener.on_compaction_begin(&CompactionBeginInfo {
+                column_family: self.name.clone(),
+                input_file_ids: input_file_ids.clone(),
+                input_bytes: bytes_read,
+            })
+        });
+
+        // TODO: Is there a limit to compaction for files? i.e a certain length? Ignoring for
+        // now!
+
+        let mut compacted_entries = self.merge_store_files(&files_for_compaction)?;
+
+        // Every older value for a key is being merged away too, so nothing can resurface
+        // without them
+        compacted_entries.retain(|_, entry| match entry {
+            TableEntry::Tombstone | TableEntry::RangeTombstone { .. } => false,
+            TableEntry::Populated { expires_at, .. }
+            | TableEntry::InValueLog { expires_at, .. } => !is_expired(*expires_at),
+        });
+
+        let segments: HashMap<_, _> = files_for_compaction
+            .iter()
+            .flat_map(|file| file.value_log_segments())
+            .map(|segment| (segment.id, segment.clone()))
+            .collect();
+        let segments_to_collect = self.value_log_segments_to_collect(&segments, &compacted_entries);
+
+        let compaction_temp_file_path = self.dir.join(COMPACTION_TEMP_FILENAME);
+        let mut writer = StoreFileWriter::create(
+            &compaction_temp_file_path,
+            &self.store_options,
+            self.store_options.compaction_compression_or_default(),
+            &self.comparator,
+        )?
+        .with_value_log(self.value_log.new_segment());
+        for (key, entry) in compacted_entries {
+            let entry = match entry {
+                TableEntry::InValueLog {
+                    pointer,
+                    expires_at,
+                } if segments_to_collect.contains(&pointer.segment_id) => {
+                    // Written out again, into the value log if it's still over the threshold
+                    TableEntry::Populated {
+                        value: segments[&pointer.segment_id].read(&pointer)?,
+                        expires_at,
+                    }
+                }
+                entry => entry,
+            };
+            writer.add(&key.key, &entry)?;
+        }
+        writer.finish()?;
+
+        let compacted_file = self.install_store_file(
+            &compaction_temp_file_path,
+            Some(compaction_file_id),
+            &files_for_compaction,
+        )?;
+
+        // The old files are deleted once any reads still using them are done, along with any
+        // value log segments that only they pointed into
+        for store_file in files_for_compaction.iter() {
+            store_file.mark_obsolete();
+        }
+        for segment in segments.values() {
+            if !compacted_file
+                .properties
+                .value_log_segment_ids
+                .contains(&segment.id)
+            {
+                segment.mark_obsolete();
+            }
+        }
+        Ok(Some(CompactionInfo {
+            column_family: self.name.clone(),
+            input_file_ids,
+            output_file_id: compacted_file.id,
+            bytes_read,
+            bytes_written: compacted_file.size_in_bytes,
+            duration: started.elapsed(),
+        }))
+    }
+
+    /// Every entry in the store files, newest first, with newer entries and range tombstones
+    /// applied over older ones. Sorted, since store files have to be written in key order
+    fn merge_store_files(
+        &self,
+        store_files: &[Arc<StoreFile>],
+    ) -> io::Result<BTreeMap<OrderedKey, TableEntry>> {
+        let mut entries = BTreeMap::new();
+        // Oldest first, so newer values overwrite older ones
+        for store_file in store_files.iter().rev() {
+            // Range tombstones only hide what older files wrote, so whole ranges are dropped
+            // before this file's own entries go in
+            for tombstone in &store_file.properties.range_tombstones {
+                entries.retain(|key: &OrderedKey, _| {
+                    !tombstone.covers(&key.key, self.comparator.as_ref())
+                });
+            }
+            store_file.for_each_entry(&mut |key, entry| {
+                entries.insert(OrderedKey::new(key, &self.comparator), entry);
+            })?;
+        }
+        return Ok(entries);
+    }
+
+    /// The mem tables keep changing while the store can be written to, so they are only in the
+    /// snapshot if include_mem_tables is set. Writable stores flush before taking a snapshot
+    /// instead
+    pub(crate) fn snapshot(&self, include_mem_tables: bool) -> ColumnFamilySnapshot {
+        let mem_tables = if include_mem_tables {
+            let mem_tables = self.mem_tables.read().unwrap();
+            std::iter::once(mem_tables.active.clone())
+                .chain(mem_tables.flushing.clone())
+                .collect()
+        } else {
+            Vec::new()
+        };
+        ColumnFamilySnapshot {
+            mem_tables,
+            store_files: self.store_files(),
+        }
+    }
+
+    /// Calls f with the key, value and expiry of every live key in the snapshot, in key order.
+    /// The mem tables and store files are merged as they are read, so store files are only held
+    /// in memory a block at a time
+    pub(crate) fn for_each_live_entry(
+        &self,
+        snapshot: &ColumnFamilySnapshot,
+        mut f: impl FnMut(&[u8], &[u8], u64) -> io::Result<()>,
+    ) -> io::Result<()> {
+        let comparator = self.comparator.as_ref();
+        // Newest first, so the first source at a key has its latest entry
+        let mut sources = Vec::new();
+        for mem_table in &snapshot.mem_tables {
+            // Mem tables are small enough to read in one go
+            let mut entries = Vec::new();
+            mem_table.for_each(&mut |key, entry| entries.push(Ok((key.to_vec(), entry.clone()))));
+            sources.push(MergeSource::new(
+                Box::new(entries.into_iter()),
+                mem_table.range_tombstones().to_vec(),
+            )?);
+        }
+        for store_file in snapshot.store_files.iter() {
+            sources.push(MergeSource::new(
+                Box::new(store_file.entries()),
+                store_file.properties.range_tombstones.clone(),
+            )?);
+        }
+
+        let segments: HashMap<_, _> = snapshot
+            .store_files
+            .iter()
+            .flat_map(|file| file.value_log_segments())
+            .map(|segment| (segment.id, segment.clone()))
+            .collect();
+        loop {
+            // The newest source at the smallest key any source is at
+            let mut newest: Option<usize> = None;
+            for (i, source) in sources.iter().enumerate() {
+                let Some((key, _)) = &source.head else {
+                    continue;
+                };
+                let is_smaller = newest.is_none_or(|newest| {
+                    let (newest_key, _) = sources[newest].head.as_ref().unwrap();
+                    comparator.compare(key, newest_key) == KeyOrdering::Less
+                });
+                if is_smaller {
+                    newest = Some(i);
+                }
+            }
+            let Some(newest) = newest else {
+                break;
+            };
+            let (key, entry) = sources[newest].head.take().unwrap();
+            sources[newest].advance()?;
+            // Older entries for the key are overwritten by this one
+            for source in &mut sources[newest + 1..] {
+                if source.head.as_ref().is_some_and(|(older_key, _)| {
+                    comparator.compare(older_key, &key) == KeyOrdering::Equal
+                }) {
+                    source.advance()?;
+                }
+            }
+            let range_deleted = sources[..newest].iter().any(|source| {
+                source
+                    .range_tombstones
+                    .iter()
+                    .any(|tombstone| tombstone.covers(&key, comparator))
+            });
+            if range_deleted {
+                continue;
+            }
+            match entry {
+                TableEntry::Populated { value, expires_at } if !is_expired(expires_at) => {
+                    f(&key, &value, expires_at)?
+                }
+                TableEntry::InValueLog {
+                    pointer,
+                    expires_at,
+                } if !is_expired(expires_at) => {
+                    let value = segments[&pointer.segment_id].read(&pointer)?;
+                    f(&key, &value, expires_at)?
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Segments whose values have mostly been overwritten or removed
+    fn value_log_segments_to_collect(
+        &self,
+        segments: &HashMap<u64, Arc<ValueLogSegment>>,
+        compacted_entries: &BTreeMap<OrderedKey, TableEntry>,
+    ) -> Vec<u64> {
+        let mut live_bytes: HashMap<u64, u64> = HashMap::new();
+        for entry in compacted_entries.values() {
+            if let TableEntry::InValueLog { pointer, .. } = entry {
+                *live_bytes.entry(pointer.segment_id).or_default() += pointer.size_in_segment();
+            }
+        }
+        segments
+            .values()
+            .filter(|segment| {
+                let live_bytes = live_bytes.get(&segment.id).copied().unwrap_or(0);
+                // A segment we can't get the size of is left for a later compaction
+                live_bytes > 0
+                    && segment
+                        .size_in_bytes()
+                        .is_ok_and(|size| live_bytes * VALUE_LOG_GC_DIVISOR < size)
+            })
+            .map(|segment| segment.id)
+            .collect()
+    }
+}
+
+/// One mem table or store file being merged with the others, in key order
+struct MergeSource<'a> {
+    entries: Box<dyn Iterator<Item = io::Result<(Vec<u8>, TableEntry)>> + 'a>,
+    /// The entry the source is at, None once it has run out
+    head: Option<(Vec<u8>, TableEntry)>,
+    /// Hide keys in every older source
+    range_tombstones: Vec<RangeTombstone>,
+}
+
+impl<'a> MergeSource<'a> {
+    fn new(
+        entries: Box<dyn Iterator<Item = io::Result<(Vec<u8>, TableEntry)>> + 'a>,
+        range_tombstones: Vec<RangeTombstone>,
+    ) -> io::Result<Self> {
+        let mut source = MergeSource {
+            entries,
+            head: None,
+            range_tombstones,
+        };
+        source.advance()?;
+        Ok(source)
+    }
+
+    fn advance(&mut self) -> io::Result<()> {
+        self.head = self.entries.next().transpose()?;
+        Ok(())
+    }
+}
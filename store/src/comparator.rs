@@ -0,0 +1,125 @@
+use std::{cmp::Ordering, collections::HashMap, fmt, io, sync::Arc};
+
+const BYTEWISE_COMPARATOR_NAME: &str = "bytewise";
+
+/// Decides what order a column family keeps its keys in, in mem tables and store files. Keys that
+/// compare as equal must also be equal byte for byte, since bloom filters and lookups still go by
+/// a key's bytes
+pub trait Comparator: Send + Sync {
+    /// Saved along with the column family, so it can't be opened again with a comparator that
+    /// orders keys differently to the one its store files were written with
+    fn name(&self) -> &str;
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering;
+}
+
+/// Orders keys byte by byte, so shorter keys come before any longer key they are a prefix of. The
+/// default for every column family
+pub struct BytewiseComparator;
+
+impl Comparator for BytewiseComparator {
+    fn name(&self) -> &str {
+        BYTEWISE_COMPARATOR_NAME
+    }
+
+    fn compare(&self, a: &[u8], b: &[u8]) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Stores written before comparators could be picked are all in bytewise order
+pub(crate) fn default_comparator_name() -> String {
+    BYTEWISE_COMPARATOR_NAME.to_string()
+}
+
+pub(crate) fn check_comparator_name(
+    what: &str,
+    saved_name: &str,
+    comparator: &dyn Comparator,
+) -> io::Result<()> {
+    if saved_name != comparator.name() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "{} was written with the {} comparator, but is being opened with {}",
+                what,
+                saved_name,
+                comparator.name()
+            ),
+        ));
+    }
+    Ok(())
+}
+
+/// The comparator picked for each column family, by name. Column families without one are
+/// bytewise
+#[derive(Clone, Default)]
+pub(crate) struct Comparators(HashMap<String, Arc<dyn Comparator>>);
+
+impl Comparators {
+    pub(crate) fn set(&mut self, column_family: &str, comparator: Arc<dyn Comparator>) {
+        self.0.insert(column_family.to_string(), comparator);
+    }
+
+    pub(crate) fn for_column_family(&self, column_family: &str) -> Arc<dyn Comparator> {
+        match self.0.get(column_family) {
+            Some(comparator) => comparator.clone(),
+            None => Arc::new(BytewiseComparator),
+        }
+    }
+
+    fn names(&self) -> HashMap<&str, &str> {
+        self.0
+            .iter()
+            .map(|(column_family, comparator)| (column_family.as_str(), comparator.name()))
+            .collect()
+    }
+}
+
+impl fmt::Debug for Comparators {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.names().fmt(f)
+    }
+}
+
+/// Comparators are told apart by name, same as when a store is opened
+impl PartialEq for Comparators {
+    fn eq(&self, other: &Self) -> bool {
+        self.names() == other.names()
+    }
+}
+
+/// A key that sorts by its column family's comparator, for keeping keys in a `BTreeMap`
+pub(crate) struct OrderedKey {
+    pub(crate) key: Vec<u8>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl OrderedKey {
+    pub(crate) fn new(key: Vec<u8>, comparator: &Arc<dyn Comparator>) -> Self {
+        OrderedKey {
+            key,
+            comparator: comparator.clone(),
+        }
+    }
+}
+
+impl PartialEq for OrderedKey {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for OrderedKey {}
+
+impl PartialOrd for OrderedKey {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for OrderedKey {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.comparator.compare(&self.key, &other.key)
+    }
+}
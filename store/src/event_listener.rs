@@ -0,0 +1,116 @@
+use std::{
+    io,
+    sync::{Arc, RwLock},
+    time::Duration,
+};
+
+/// Told about the work a Store does on its own, such as flushes and compactions. Every method
+/// does nothing by default, so listeners only implement the events they care about.
+///
+/// Listeners are called on whichever thread is doing the work, often while a write is waiting on
+/// it, so they should be quick and must not call back into the Store
+pub trait EventListener: Send + Sync {
+    fn on_flush_begin(&self, _info: &FlushBeginInfo) {}
+
+    fn on_flush_completed(&self, _info: &FlushInfo) {}
+
+    fn on_compaction_begin(&self, _info: &CompactionBeginInfo) {}
+
+    fn on_compaction_completed(&self, _info: &CompactionInfo) {}
+
+    /// The WAL was replaced with an empty one, since a flush persisted everything in it
+    fn on_wal_rotated(&self, _info: &WalRotationInfo) {}
+
+    /// Something failed that no caller was waiting on, so the error has nowhere else to go
+    fn on_background_error(&self, _reason: &BackgroundErrorReason, _error: &io::Error) {}
+
+    /// A write was held up flushing the mem tables, and compacting afterwards, because it took
+    /// them over the size limit. Called once the write carries on
+    fn on_write_stall(&self, _info: &WriteStallInfo) {}
+}
+
+/// Every column family's mem table is flushed together, so one flush covers them all
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushBeginInfo {
+    /// Bytes written to the mem tables of every column family since the last flush
+    pub mem_table_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushInfo {
+    /// One store file for each column family that had anything to flush
+    pub store_files: Vec<FlushedStoreFile>,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FlushedStoreFile {
+    pub column_family: String,
+    /// Only known once the file is written, since flushed files are given their id as they are
+    /// added to the column family's store files
+    pub file_id: u64,
+    pub size_in_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionBeginInfo {
+    pub column_family: String,
+    pub input_file_ids: Vec<u64>,
+    pub input_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CompactionInfo {
+    pub column_family: String,
+    pub input_file_ids: Vec<u64>,
+    /// Compaction always merges into a single store file
+    pub output_file_id: u64,
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    pub duration: Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WalRotationInfo {
+    /// Size of the WAL that was replaced
+    pub previous_size_in_bytes: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct WriteStallInfo {
+    /// Bytes in the mem tables that set off the flush
+    pub mem_table_bytes: u64,
+    /// How long the write was held up for
+    pub duration: Duration,
+}
+
+/// What the Store was doing when a background error happened
+#[derive(Clone, Debug, PartialEq)]
+pub enum BackgroundErrorReason {
+    /// A compaction set off by a column family's compaction policy. The store files it was
+    /// merging are left as they were
+    Compaction { column_family: String },
+    /// Shutting down when the Store was dropped rather than closed
+    ShutDown,
+}
+
+/// The listeners registered on a Store
+#[derive(Default)]
+pub(crate) struct EventListeners {
+    listeners: RwLock<Vec<Arc<dyn EventListener>>>,
+}
+
+impl EventListeners {
+    pub(crate) fn add(&self, listener: Arc<dyn EventListener>) {
+        self.listeners.write().unwrap().push(listener);
+    }
+
+    /// Calls f with every listener, in the order they were added
+    pub(crate) fn notify(&self, f: impl Fn(&dyn EventListener)) {
+        // Cloned so a slow listener doesn't hold up adding another
+        let listeners = self.listeners.read().unwrap().clone();
+        for listener in &listeners {
+            f(listener.as_ref());
+        }
+    }
+}
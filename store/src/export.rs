@@ -0,0 +1,207 @@
+use std::io::{self, Read, Write};
+
+/// Exports are laid out as:
+///     magic (8 bytes, "KVEXPORT")
+///     version (4 bytes)
+///     records
+///
+/// with every record laid out as:
+///     kind (1 byte)
+///     payload_size (8 bytes)
+///     payload (payload_size bytes)
+///     crc32 of the kind, payload_size and payload (4 bytes)
+///
+/// Every number is little endian. Records are one of:
+///     column family: the name, which the entries after it up to the next column family belong to
+///     entry: key_size (4 bytes), key, expires_at (8 bytes, milliseconds since the unix epoch or 0
+///         for never), then the value taking up the rest of the payload
+///     end: how many entries came before it (8 bytes). Always the last record, so a truncated
+///         export can't pass for a whole one
+const EXPORT_MAGIC: &[u8; 8] = b"KVEXPORT";
+const EXPORT_VERSION: u32 = 1;
+
+const COLUMN_FAMILY_RECORD: u8 = 1;
+const ENTRY_RECORD: u8 = 2;
+const END_RECORD: u8 = 3;
+
+/// Kind and payload size
+const RECORD_HEADER_SIZE: usize = 9;
+
+fn invalid_export(reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Invalid export: {}", reason),
+    )
+}
+
+pub(crate) enum ExportRecord {
+    ColumnFamily(String),
+    Entry {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+}
+
+pub(crate) struct ExportWriter<W: Write> {
+    writer: W,
+    entry_count: u64,
+}
+
+impl<W: Write> ExportWriter<W> {
+    pub(crate) fn new(mut writer: W) -> io::Result<Self> {
+        writer.write_all(EXPORT_MAGIC)?;
+        writer.write_all(&EXPORT_VERSION.to_le_bytes())?;
+        Ok(ExportWriter {
+            writer,
+            entry_count: 0,
+        })
+    }
+
+    fn write_record(&mut self, kind: u8, payload: &[&[u8]]) -> io::Result<()> {
+        let payload_size: usize = payload.iter().map(|part| part.len()).sum();
+        let mut header = [0; RECORD_HEADER_SIZE];
+        header[0] = kind;
+        header[1..].copy_from_slice(&(payload_size as u64).to_le_bytes());
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        self.writer.write_all(&header)?;
+        for part in payload {
+            hasher.update(part);
+            self.writer.write_all(part)?;
+        }
+        self.writer.write_all(&hasher.finalize().to_le_bytes())
+    }
+
+    pub(crate) fn column_family(&mut self, name: &str) -> io::Result<()> {
+        self.write_record(COLUMN_FAMILY_RECORD, &[name.as_bytes()])
+    }
+
+    pub(crate) fn entry(&mut self, key: &[u8], value: &[u8], expires_at: u64) -> io::Result<()> {
+        self.entry_count += 1;
+        self.write_record(
+            ENTRY_RECORD,
+            &[
+                &(key.len() as u32).to_le_bytes(),
+                key,
+                &expires_at.to_le_bytes(),
+                value,
+            ],
+        )
+    }
+
+    /// Returns how many entries were written
+    pub(crate) fn finish(mut self) -> io::Result<u64> {
+        self.write_record(END_RECORD, &[&self.entry_count.to_le_bytes()])?;
+        self.writer.flush()?;
+        Ok(self.entry_count)
+    }
+}
+
+pub(crate) struct ExportReader<R: Read> {
+    reader: R,
+    entry_count: u64,
+    finished: bool,
+}
+
+impl<R: Read> ExportReader<R> {
+    pub(crate) fn new(mut reader: R) -> io::Result<Self> {
+        let mut header = [0; 12];
+        reader
+            .read_exact(&mut header)
+            .map_err(|_| invalid_export("too short for a header"))?;
+        if &header[..8] != EXPORT_MAGIC {
+            return Err(invalid_export("not an export"));
+        }
+        let version = u32::from_le_bytes(header[8..].try_into().unwrap());
+        if version != EXPORT_VERSION {
+            return Err(invalid_export(&format!(
+                "version {} isn't supported, only version {} is",
+                version, EXPORT_VERSION
+            )));
+        }
+        Ok(ExportReader {
+            reader,
+            entry_count: 0,
+            finished: false,
+        })
+    }
+
+    /// Returns None once the end record has been read
+    pub(crate) fn next_record(&mut self) -> io::Result<Option<ExportRecord>> {
+        if self.finished {
+            return Ok(None);
+        }
+        let mut header = [0; RECORD_HEADER_SIZE];
+        self.read_exact(&mut header)?;
+        let payload_size = u64::from_le_bytes(header[1..].try_into().unwrap());
+        let mut payload = Vec::new();
+        // Read bit by bit rather than allocated up front, in case the size is garbage
+        (&mut self.reader)
+            .take(payload_size)
+            .read_to_end(&mut payload)?;
+        if payload.len() as u64 != payload_size {
+            return Err(invalid_export("truncated"));
+        }
+        let mut checksum = [0; 4];
+        self.read_exact(&mut checksum)?;
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&header);
+        hasher.update(&payload);
+        if hasher.finalize() != u32::from_le_bytes(checksum) {
+            return Err(invalid_export(&format!(
+                "checksum mismatch after {} entries",
+                self.entry_count
+            )));
+        }
+
+        match header[0] {
+            COLUMN_FAMILY_RECORD => {
+                let name = String::from_utf8(payload)
+                    .map_err(|_| invalid_export("column family name isn't UTF-8"))?;
+                Ok(Some(ExportRecord::ColumnFamily(name)))
+            }
+            ENTRY_RECORD => {
+                let key_size = payload
+                    .get(..4)
+                    .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+                    .ok_or_else(|| invalid_export("entry too short"))?;
+                if payload.len() < 4 + key_size + 8 {
+                    return Err(invalid_export("entry too short"));
+                }
+                let value = payload.split_off(4 + key_size + 8);
+                let expires_at = u64::from_le_bytes(payload[4 + key_size..].try_into().unwrap());
+                payload.truncate(4 + key_size);
+                let key = payload.split_off(4);
+                self.entry_count += 1;
+                Ok(Some(ExportRecord::Entry {
+                    key,
+                    value,
+                    expires_at,
+                }))
+            }
+            END_RECORD => {
+                let entry_count = payload
+                    .try_into()
+                    .map(u64::from_le_bytes)
+                    .map_err(|_| invalid_export("end record is the wrong size"))?;
+                if entry_count != self.entry_count {
+                    return Err(invalid_export(&format!(
+                        "expected {} entries, found {}",
+                        entry_count, self.entry_count
+                    )));
+                }
+                self.finished = true;
+                Ok(None)
+            }
+            kind => Err(invalid_export(&format!("unknown record kind {}", kind))),
+        }
+    }
+
+    fn read_exact(&mut self, buf: &mut [u8]) -> io::Result<()> {
+        self.reader.read_exact(buf).map_err(|e| match e.kind() {
+            io::ErrorKind::UnexpectedEof => invalid_export("truncated"),
+            _ => e,
+        })
+    }
+}
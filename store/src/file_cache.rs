@@ -0,0 +1,52 @@
+use std::{
+    fs::File,
+    io,
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use crate::lru::Lru;
+
+/// Open handles for store files and value log segments, so reads don't have to open a file every
+/// time they need a value or a block that isn't in the block cache. Bounded so a store with many
+/// files doesn't run out of file descriptors, with the least recently used handles closed first
+pub(crate) struct FileCache {
+    files: Mutex<Lru<u64, Arc<File>>>,
+    max_open_files: u64,
+}
+
+impl FileCache {
+    /// A max_open_files of 0 turns caching off, so every read opens its file
+    pub(crate) fn new(max_open_files: usize) -> Self {
+        FileCache {
+            files: Mutex::new(Lru::new()),
+            max_open_files: max_open_files as u64,
+        }
+    }
+
+    /// Files are keyed by the id the block cache gave them when they were opened, since store
+    /// file ids are only unique within a column family
+    pub(crate) fn get_or_open(&self, cache_id: u64, path: &Path) -> io::Result<Arc<File>> {
+        if let Some(file) = self.files.lock().unwrap().get(cache_id) {
+            return Ok(file);
+        }
+        // Opened without holding the lock, so a slow open doesn't hold up reads of other files.
+        // If two reads race to open the same file, one handle just replaces the other
+        let file = Arc::new(File::open(path)?);
+        self.files
+            .lock()
+            .unwrap()
+            .insert(cache_id, file.clone(), 1, self.max_open_files);
+        return Ok(file);
+    }
+
+    /// Closes the file's handle, once any reads still using it are done
+    pub(crate) fn evict(&self, cache_id: u64) {
+        self.files.lock().unwrap().remove(cache_id);
+    }
+
+    #[cfg(test)]
+    pub(crate) fn open_file_count(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+}
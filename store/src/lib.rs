@@ -1,10 +1,60 @@
+#![allow(clippy::needless_return)]
+
+mod backup;
+mod block_cache;
+mod bloom;
+mod column_family;
+mod comparator;
+mod event_listener;
+mod export;
+mod file_cache;
+mod lru;
+mod manifest;
+mod mem_table;
+mod options;
+mod range_tombstone;
+mod repair;
+mod skiplist;
+mod stats;
+mod store_file;
+mod upgrade;
+mod value_log;
+mod varint;
+mod wal;
+mod write_batch;
+
 use std::{
+    cmp::Ordering,
     collections::{BTreeMap, HashMap},
-    fs::{self, File},
-    io::{BufReader, BufWriter, Read, Write},
-    os::unix::prelude::FileExt,
+    fs::{self, File, TryLockError},
+    io::{self, BufWriter, Read, Write},
     path::{Path, PathBuf},
+    sync::{atomic::Ordering as AtomicOrdering, Arc, Mutex, MutexGuard, RwLock},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+pub use backup::{BackupEngine, BackupInfo};
+pub use block_cache::BlockCacheStats;
+use column_family::ColumnFamily;
+pub use column_family::{ColumnFamilyOptions, CompactionPolicy};
+use comparator::check_comparator_name;
+pub use comparator::{BytewiseComparator, Comparator};
+use event_listener::EventListeners;
+pub use event_listener::{
+    BackgroundErrorReason, CompactionBeginInfo, CompactionInfo, EventListener, FlushBeginInfo,
+    FlushInfo, FlushedStoreFile, WalRotationInfo, WriteStallInfo,
 };
+use export::{ExportReader, ExportRecord, ExportWriter};
+use manifest::{ColumnFamilyDescriptor, Manifest};
+pub use mem_table::MemTableKind;
+pub use options::{Compression, StoreOptions, SyncMode};
+pub use repair::{repair_store, verify_store, Damage, RepairReport, VerifyReport};
+use stats::Statistics;
+pub use stats::{Histogram, StoreStats};
+use store_file::{StoreFile, StoreFileCaches};
+pub use store_file::{StoreFileStats, StoreFileWriter};
+use value_log::{ValueLog, ValuePointer};
+pub use write_batch::WriteBatch;
 
 type FileOffset = usize;
 
@@ -12,50 +62,194 @@ const STORE_FILENAME_SUFFIX: &str = ".store.kv";
 
 const WRITE_AHEAD_LOG_FILENAME: &str = "write_ahead_log.txt";
 
-type StoreData = HashMap<Vec<u8>, StoreEntry>;
-type StoreIndexes = HashMap<u64, StoreData>; // file id to store data index
+/// Imports are written in batches of this many entries
+const IMPORT_BATCH_SIZE: usize = 1000;
+
+/// Held locked for as long as a Store has the directory open
+const LOCK_FILENAME: &str = "LOCK";
+
+/// How many times opening read only retries when the directory is changed under it
+const READ_ONLY_OPEN_ATTEMPTS: usize = 10;
+
+/// Store files for column families other than the default one live in a directory per column
+/// family under here
+const COLUMN_FAMILIES_DIRNAME: &str = "column_families";
+
+/// The column family used by `put`, `get` and `remove`. It always exists and can't be dropped
+pub const DEFAULT_COLUMN_FAMILY: &str = "default";
+
+/// Expiry timestamps are stored as milliseconds since the unix epoch, with 0 meaning the key never
+/// expires
+const NO_EXPIRY: u64 = 0;
 
+/// Safe to share between threads. Reads only ever take short lived locks to grab the current mem
+/// tables and store files, so they never wait on a flush or compaction. Writes are applied one
+/// at a time, in WAL order
 pub struct Store {
-    current_file_id: u64,
     dir: PathBuf,
-    pub mem_table_size_limit_in_bytes: u64,
-    // TODO: FIXME: Anything stored in the active mem table will be lost if the process crashes.
-    //      Build up a write ahead log that gets cleared every time the mem table gets flushed to
-    //      make fully durable
-    active_mem_table: BTreeMap<Vec<u8>, TableEntry>,
-    // TODO: Sparse index for keys in the store. Since the keys are
-    //     sorted, we only need to keep a subset of keys indexed. We can scan for the key in the
-    //     file if it isn't indexed already (keys are sorted, so we know at least what key the
-    //     requested key comes AFTER)
-    store_indexes: StoreIndexes,
-    bytes_written_since_last_flush: u64,
+    options: StoreOptions,
+    /// The lock is released when this is closed, which happens when the Store is closed or
+    /// dropped. Read only stores don't take the lock
+    lock_file: Option<File>,
+    column_families: RwLock<HashMap<String, Arc<ColumnFamily>>>,
+    /// Shared by the store files of every column family
+    caches: Arc<StoreFileCaches>,
+    stats: Statistics,
+    event_listeners: EventListeners,
+    /// None when the store is read only
+    writer: Option<Mutex<Writer>>,
+}
+
+/// Everything that only one writer at a time can touch
+struct Writer {
     wal_writer: BufWriter<File>,
+    /// Where the last batch appended to the WAL ends
+    wal_size_in_bytes: u64,
+    /// Set when a failed append left part of a batch in the WAL that couldn't be cut back off
+    wal_broken: bool,
+    bytes_written_since_last_flush: u64,
+    next_column_family_id: u32,
 }
 
 #[derive(Clone, Debug)]
 enum TableEntry {
     Tombstone,
-    Populated(Vec<u8>),
-}
-
-#[derive(Debug, PartialEq)]
-struct StoreEntry {
-    value_size: usize,
-    key_size: usize,
-    byte_offset: FileOffset,
-    file_id: u64,
-    // TODO: Checksum for key + value
+    Populated {
+        value: Vec<u8>,
+        expires_at: u64,
+    },
+    /// Only ever read from store files, mem tables always hold the value itself
+    InValueLog {
+        pointer: ValuePointer,
+        expires_at: u64,
+    },
+    /// Deletes every key from the entry's key up to, but not including, end. Only ever found in
+    /// write batches and the WAL, mem tables and store files keep their range tombstones apart
+    /// from their keys
+    RangeTombstone {
+        end: Vec<u8>,
+    },
 }
 
 #[derive(Debug, PartialEq)]
 struct KeyValue {
-    value_size: u32,
-    key_size: u32,
+    value_size: u64,
+    key_size: u64,
     key: Vec<u8>,
+    expires_at: u64,
     value: Vec<u8>,
 }
 
+/// The size bytes at offset, moving offset past them. None if bytes ends before they do
+fn take_bytes<'a>(bytes: &'a [u8], offset: &mut usize, size: u64) -> Option<&'a [u8]> {
+    let end = offset.checked_add(usize::try_from(size).ok()?)?;
+    let taken = bytes.get(*offset..end)?;
+    *offset = end;
+    Some(taken)
+}
+
+fn now_in_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("System clock is set before the unix epoch")
+        .as_millis() as u64
+}
+
+fn is_expired(expires_at: u64) -> bool {
+    expires_at != NO_EXPIRY && now_in_millis() >= expires_at
+}
+
+/// Makes renames and newly created files in dir survive a crash
+fn sync_dir(dir: &Path) -> io::Result<()> {
+    File::open(dir)?.sync_all()
+}
+
+/// Hard links only work within one filesystem, so anything else gets copied
+pub(crate) fn link_or_copy(from: &Path, to: &Path) -> io::Result<()> {
+    if fs::hard_link(from, to).is_ok() {
+        return Ok(());
+    }
+    fs::copy(from, to)?;
+    File::open(to)?.sync_all()
+}
+
+/// A directory from create_temp_dir_for. It is deleted when dropped, unless it was moved into place,
+/// so whatever fails part way through building it doesn't leave it behind
+struct TempDir {
+    path: PathBuf,
+    moved: bool,
+}
+
+impl TempDir {
+    fn path(&self) -> &Path {
+        &self.path
+    }
+
+    fn move_into_place(mut self, dest_dir: &Path) -> io::Result<()> {
+        sync_dir(&self.path)?;
+        fs::rename(&self.path, dest_dir)?;
+        self.moved = true;
+        match dest_dir.parent().filter(|parent| parent.is_dir()) {
+            Some(parent) => sync_dir(parent),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        if !self.moved {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+/// Directories such as checkpoints are built under a temporary name next to dest_dir, so nothing
+/// is left at dest_dir unless they are finished. Fails with `ErrorKind::AlreadyExists` if dest_dir
+/// exists
+fn create_temp_dir_for(dest_dir: &Path) -> io::Result<TempDir> {
+    if dest_dir.exists() {
+        return Err(io::Error::new(
+            io::ErrorKind::AlreadyExists,
+            format!("{:?} already exists", dest_dir),
+        ));
+    }
+    let mut temp_dir_name = dest_dir.file_name().unwrap_or_default().to_owned();
+    temp_dir_name.push(".temp");
+    let temp_dir = dest_dir.with_file_name(temp_dir_name);
+    if let Err(e) = fs::remove_dir_all(&temp_dir) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(e);
+        }
+    }
+    fs::create_dir_all(&temp_dir)?;
+    Ok(TempDir {
+        path: temp_dir,
+        moved: false,
+    })
+}
+
+fn read_only_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        "The store was opened read only",
+    )
+}
+
+fn column_family_not_found(name: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::NotFound,
+        format!("No column family named {}", name),
+    )
+}
+
 impl Store {
+    /// Opens the store in dir_path with the default options. Unless keep_existing_dir is set,
+    /// EVERYTHING already in dir_path is deleted first. Use `Store::open` to pick options, or to
+    /// make sure nothing gets deleted.
+    ///
+    /// Panics if the directory can't be cleared or the store can't be opened, e.g. because another
+    /// Store has it open. `Store::open` returns those errors instead
     pub fn new(dir_path: &Path, keep_existing_dir: bool) -> Self {
         if !keep_existing_dir {
             if let Err(e) = fs::remove_dir_all(dir_path) {
@@ -64,100 +258,635 @@ impl Store {
                 }
             }
         }
-        fs::create_dir_all(dir_path).unwrap();
+        Self::open(dir_path, &StoreOptions::default()).unwrap()
+    }
+
+    /// Opens the store in dir_path, creating it if it doesn't exist yet and the options allow it
+    pub fn open(dir_path: &Path, options: &StoreOptions) -> io::Result<Self> {
+        options.validate()?;
+        // Stores from before the manifest existed only have a WAL to go by
+        let store_exists =
+            Manifest::exists(dir_path) || dir_path.join(WRITE_AHEAD_LOG_FILENAME).exists();
+        if store_exists && options.error_if_exists {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("A store already exists in {:?}", dir_path),
+            ));
+        }
+        if !store_exists && (options.read_only || !options.create_if_missing) {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("No store in {:?}", dir_path),
+            ));
+        }
+        // Those stores are in the original format, which is only read to upgrade it
+        let upgrading = store_exists && !Manifest::exists(dir_path);
+        if upgrading && options.read_only {
+            return Err(upgrade::needs_upgrade_error(dir_path));
+        }
+        if options.read_only {
+            return Self::open_read_only(dir_path, options);
+        }
+        fs::create_dir_all(dir_path)?;
+        let lock_file = Self::lock_dir(dir_path)?;
+        if upgrading {
+            upgrade::upgrade_baseline_store(dir_path, options)?;
+        }
+
+        let mut manifest = Manifest::load(dir_path)?;
+        let default_descriptor = manifest
+            .column_families
+            .iter_mut()
+            .find(|descriptor| descriptor.name == DEFAULT_COLUMN_FAMILY)
+            .expect("The manifest is missing the default column family");
+        // Stores without a manifest get one written now
+        let mut manifest_changed = !Manifest::exists(dir_path);
+        if default_descriptor.options.compaction_policy != options.compaction_policy {
+            default_descriptor.options.compaction_policy = options.compaction_policy;
+            manifest_changed = true;
+        }
+        if !store_exists {
+            // A new store is ordered by whichever comparator it is first opened with
+            default_descriptor.comparator = options
+                .comparators
+                .for_column_family(DEFAULT_COLUMN_FAMILY)
+                .name()
+                .to_string();
+        }
+        if manifest_changed {
+            manifest.save(dir_path)?;
+        }
+        options.save(dir_path)?;
 
-        let store_info = Store::build_store_from_dir(dir_path);
+        let next_column_family_id = manifest.next_column_family_id;
+        let caches = Arc::new(StoreFileCaches::new(options));
+        let column_families = Self::open_column_families(dir_path, manifest, options, &caches)?;
 
         // If the WAL exists, that is unpersisted data from most recent writes, so data from
         // from the WAL should overwrite existing store info
         // Remember - Those writes havent been persisted yet, so there is no associated file ID
-        // TODO: Test for recovering from WAL
-        let recovered_wal_kvs =
-            Store::restore_from_wal(dir_path.join(WRITE_AHEAD_LOG_FILENAME)).unwrap();
+        let recovered_wal = wal::read_wal(dir_path.join(WRITE_AHEAD_LOG_FILENAME))?;
+        if let Some(e) = recovered_wal.corrupt_batch {
+            return Err(e);
+        }
 
         let write_ahead_log_file = fs::File::options()
-            .write(true)
+            .append(true)
             .create(true)
-            .open(dir_path.join(WRITE_AHEAD_LOG_FILENAME))
-            .unwrap();
+            .open(dir_path.join(WRITE_AHEAD_LOG_FILENAME))?;
+        let stats = Statistics::default();
+        // A torn batch from a crash is cut off, or batches appended after it would be where
+        // replaying never gets to
+        if recovered_wal.intact_size_in_bytes < recovered_wal.size_in_bytes {
+            write_ahead_log_file.set_len(recovered_wal.intact_size_in_bytes)?;
+            write_ahead_log_file.sync_all()?;
+            Statistics::add(&stats.wal_syncs, 1);
+        }
+        let wal_size_in_bytes = recovered_wal.intact_size_in_bytes;
+
+        let bytes_written_since_last_flush =
+            Self::replay_wal_entries(&column_families, recovered_wal.entries);
 
-        let mut store = Store {
-            current_file_id: store_info.1,
+        stats
+            .mem_table_bytes
+            .store(bytes_written_since_last_flush, AtomicOrdering::Relaxed);
+
+        return Ok(Store {
             dir: dir_path.to_path_buf(),
-            mem_table_size_limit_in_bytes: 1024 * 1024 * 5,
-            active_mem_table: BTreeMap::new(),
-            store_indexes: store_info.0,
-            bytes_written_since_last_flush: 0,
-            wal_writer: BufWriter::new(write_ahead_log_file),
+            options: options.clone(),
+            lock_file: Some(lock_file),
+            column_families: RwLock::new(column_families),
+            caches,
+            stats,
+            event_listeners: EventListeners::default(),
+            writer: Some(Mutex::new(Writer {
+                wal_writer: BufWriter::new(write_ahead_log_file),
+                wal_size_in_bytes,
+                wal_broken: false,
+                bytes_written_since_last_flush,
+                next_column_family_id,
+            })),
+        });
+    }
+
+    /// Nothing in the directory is changed and no lock is taken, so this is safe to do while
+    /// another Store has the directory open for writing. That Store can flush or compact while
+    /// we're reading the directory, so we keep trying until the store files are the same before
+    /// and after reading the WAL
+    fn open_read_only(dir_path: &Path, options: &StoreOptions) -> io::Result<Self> {
+        for _ in 0..READ_ONLY_OPEN_ATTEMPTS {
+            let manifest = Manifest::load(dir_path)?;
+            let caches = Arc::new(StoreFileCaches::new(options));
+            let column_families =
+                match Self::open_column_families(dir_path, manifest, options, &caches) {
+                    Ok(column_families) => column_families,
+                    // A file we listed was compacted away, or a column family dropped, before we got
+                    // to it
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+                    Err(e) => return Err(e),
+                };
+            let recovered_wal_entries =
+                wal::restore_from_wal(dir_path.join(WRITE_AHEAD_LOG_FILENAME))?;
+            if column_families
+                .values()
+                .any(|family| family.store_files_changed())
+            {
+                // Whatever flush changed the files may have truncated the WAL before we read it
+                continue;
+            }
+            Self::replay_wal_entries(&column_families, recovered_wal_entries);
+
+            return Ok(Store {
+                dir: dir_path.to_path_buf(),
+                options: options.clone(),
+                lock_file: None,
+                column_families: RwLock::new(column_families),
+                caches,
+                stats: Statistics::default(),
+                event_listeners: EventListeners::default(),
+                writer: None,
+            });
+        }
+        return Err(io::Error::other(format!(
+            "The store in {:?} kept changing while being opened read only",
+            dir_path
+        )));
+    }
+
+    fn open_column_families(
+        dir_path: &Path,
+        manifest: Manifest,
+        options: &StoreOptions,
+        caches: &Arc<StoreFileCaches>,
+    ) -> io::Result<HashMap<String, Arc<ColumnFamily>>> {
+        let mut column_families = HashMap::new();
+        for descriptor in manifest.column_families {
+            // Checked before any store file is opened, so the error names the column family
+            check_comparator_name(
+                &format!("Column family {}", descriptor.name),
+                &descriptor.comparator,
+                options
+                    .comparators
+                    .for_column_family(&descriptor.name)
+                    .as_ref(),
+            )?;
+            let family_dir = ColumnFamily::dir_for(dir_path, &descriptor.name);
+            let family = ColumnFamily::open(
+                descriptor.id,
+                &descriptor.name,
+                descriptor.options,
+                family_dir,
+                options,
+                caches,
+            )?;
+            column_families.insert(descriptor.name, Arc::new(family));
+        }
+        return Ok(column_families);
+    }
+
+    fn check_entry_sizes(&self, batch: &WriteBatch) -> io::Result<()> {
+        for batch_entry in &batch.entries {
+            let mut keys = vec![&batch_entry.key];
+            if let TableEntry::RangeTombstone { end } = &batch_entry.entry {
+                keys.push(end);
+            }
+            for key in keys {
+                if key.len() as u64 > self.options.max_key_size_in_bytes {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Key of {} bytes is over the limit of {} bytes",
+                            key.len(),
+                            self.options.max_key_size_in_bytes
+                        ),
+                    ));
+                }
+            }
+            if let TableEntry::Populated { value, .. } = &batch_entry.entry {
+                if value.len() as u64 > self.options.max_value_size_in_bytes {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        format!(
+                            "Value of {} bytes is over the limit of {} bytes",
+                            value.len(),
+                            self.options.max_value_size_in_bytes
+                        ),
+                    ));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns how many bytes the replayed entries count towards the mem table size limit
+    fn replay_wal_entries(
+        column_families: &HashMap<String, Arc<ColumnFamily>>,
+        wal_entries: Vec<wal::WalEntry>,
+    ) -> u64 {
+        let mut bytes_replayed = 0;
+        for wal_entry in wal_entries {
+            // Entries for column families that have since been dropped are skipped
+            if let Some(family) = column_families
+                .values()
+                .find(|family| family.id == wal_entry.column_family_id)
+            {
+                bytes_replayed += Self::bytes_for_entry(&wal_entry.key, &wal_entry.entry);
+                family.insert(wal_entry.key, wal_entry.entry);
+            }
+        }
+        return bytes_replayed;
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.writer.is_none()
+    }
+
+    /// Counters and timings for everything the Store has done since it was opened
+    pub fn stats(&self) -> StoreStats {
+        let store_file_counts: BTreeMap<_, _> = self
+            .column_family_list()
+            .iter()
+            .map(|family| (family.name.clone(), family.store_file_count()))
+            .collect();
+        self.stats
+            .snapshot(self.caches.blocks.stats(), store_file_counts)
+    }
+
+    /// How well the block cache is doing, for tuning `StoreOptions::block_cache_size_in_bytes`
+    pub fn block_cache_stats(&self) -> BlockCacheStats {
+        self.caches.blocks.stats()
+    }
+
+    /// Stats for each of the default column family's store files, newest first
+    pub fn store_file_stats(&self) -> Vec<StoreFileStats> {
+        self.default_family().store_file_stats()
+    }
+
+    pub fn store_file_stats_cf(&self, column_family: &str) -> io::Result<Vec<StoreFileStats>> {
+        Ok(self.column_family(column_family)?.store_file_stats())
+    }
+
+    /// Roughly how many bytes of store files hold keys from start up to, but not including, end.
+    /// Worked out from the store file indexes without reading any data, so it's only as precise
+    /// as the block size, and leaves out writes that haven't been flushed yet
+    pub fn approximate_size(&self, start: &[u8], end: &[u8]) -> u64 {
+        self.default_family().approximate_size(start, end)
+    }
+
+    pub fn approximate_size_cf(
+        &self,
+        column_family: &str,
+        start: &[u8],
+        end: &[u8],
+    ) -> io::Result<u64> {
+        Ok(self
+            .column_family(column_family)?
+            .approximate_size(start, end))
+    }
+
+    /// Roughly how many live keys are in the store files, from their entry and tombstone counts.
+    /// Keys that were overwritten or removed since the last compaction throw it off, as do writes
+    /// that haven't been flushed yet
+    pub fn approximate_num_keys(&self) -> u64 {
+        self.default_family().approximate_num_keys()
+    }
+
+    pub fn approximate_num_keys_cf(&self, column_family: &str) -> io::Result<u64> {
+        Ok(self.column_family(column_family)?.approximate_num_keys())
+    }
+
+    /// Listeners are told about flushes, compactions and anything else the Store does on its own
+    /// from now on
+    pub fn add_event_listener(&self, listener: Arc<dyn EventListener>) {
+        self.event_listeners.add(listener);
+    }
+
+    fn writer(&self) -> io::Result<MutexGuard<'_, Writer>> {
+        match &self.writer {
+            Some(writer) => Ok(writer.lock().unwrap()),
+            None => Err(read_only_error()),
+        }
+    }
+
+    /// Takes an exclusive advisory lock on the LOCK file, so no other Store (in this process or
+    /// any other) can have the directory open at the same time as us
+    pub(crate) fn lock_dir(dir_path: &Path) -> io::Result<File> {
+        let lock_file = File::options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(dir_path.join(LOCK_FILENAME))?;
+        match lock_file.try_lock() {
+            Ok(()) => Ok(lock_file),
+            Err(TryLockError::WouldBlock) => Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("The store in {:?} is already open elsewhere", dir_path),
+            )),
+            Err(TryLockError::Error(e)) => Err(e),
+        }
+    }
+
+    /// Flushes the mem tables, makes sure everything written is on disk and releases the lock on
+    /// the directory. Dropping a Store does the same, but has nowhere to report errors to
+    pub fn close(mut self) -> io::Result<()> {
+        self.shut_down()
+    }
+
+    fn shut_down(&mut self) -> io::Result<()> {
+        // Taking the writer means dropping the Store afterwards has nothing left to do
+        let Some(writer) = self.writer.take() else {
+            // Read only stores have nothing to write out
+            return Ok(());
         };
+        let mut writer = writer.into_inner().map_err(|_| {
+            io::Error::other("A write panicked, so the mem tables can't be trusted")
+        })?;
+        self.flush_with_writer(&mut writer)?;
+        writer.wal_writer.flush()?;
+        writer.wal_writer.get_ref().sync_all()?;
+        Statistics::add(&self.stats.wal_syncs, 1);
+        self.lock_file.take();
+        Ok(())
+    }
+
+    /// Drops the Store without flushing anything, leaving the WAL as it would be after a crash
+    #[cfg(test)]
+    fn simulate_crash(mut self) {
+        self.writer.take();
+    }
 
-        for kv in &recovered_wal_kvs {
-            store.put_into_memory(&kv.key, &kv.value);
+    pub fn flush_pending_writes(&self) -> io::Result<()> {
+        let mut writer = self.writer()?;
+        self.flush_with_writer(&mut writer)?;
+        drop(writer);
+        self.compact_by_policy();
+        Ok(())
+    }
+
+    /// Every column family is flushed together, since the WAL can only be truncated once none of
+    /// them need it anymore. If one fails, the WAL is left alone so nothing written is lost
+    fn flush_with_writer(&self, writer: &mut Writer) -> io::Result<()> {
+        let families = self.column_family_list();
+        let flushing = families.iter().any(|family| family.has_unflushed_writes());
+        if flushing {
+            let mem_table_bytes = writer.bytes_written_since_last_flush;
+            self.event_listeners
+                .notify(|listener| listener.on_flush_begin(&FlushBeginInfo { mem_table_bytes }));
+        }
+        let flush_started = Instant::now();
+        let mut flushed_store_files = Vec::new();
+        for family in families {
+            let started = Instant::now();
+            if let Some(store_file) = family.write_mem_table_to_disk()? {
+                self.stats.flush_micros.record_duration(started.elapsed());
+                Statistics::add(&self.stats.flush_bytes_written, store_file.size_in_bytes);
+                flushed_store_files.push(FlushedStoreFile {
+                    column_family: family.name.clone(),
+                    file_id: store_file.id,
+                    size_in_bytes: store_file.size_in_bytes,
+                });
+            }
+        }
+        if flushing {
+            let info = FlushInfo {
+                store_files: flushed_store_files,
+                duration: flush_started.elapsed(),
+            };
+            self.event_listeners
+                .notify(|listener| listener.on_flush_completed(&info));
+        }
+        writer.bytes_written_since_last_flush = 0;
+        self.stats.mem_table_bytes.store(0, AtomicOrdering::Relaxed);
+        let previous_size_in_bytes = self.truncate_wal(writer)?;
+        if previous_size_in_bytes > 0 {
+            let info = WalRotationInfo {
+                previous_size_in_bytes,
+            };
+            self.event_listeners
+                .notify(|listener| listener.on_wal_rotated(&info));
         }
+        Ok(())
+    }
 
-        return store;
+    /// Compaction failures here have no caller to go back to, so they go to the event listeners
+    fn compact_by_policy(&self) {
+        for family in self.column_family_list() {
+            if family.should_compact() {
+                if let Err(e) = self.compact_family(&family) {
+                    let reason = BackgroundErrorReason::Compaction {
+                        column_family: family.name.clone(),
+                    };
+                    self.event_listeners
+                        .notify(|listener| listener.on_background_error(&reason, &e));
+                }
+            }
+        }
     }
 
-    pub fn flush_pending_writes(&mut self) {
-        self.write_mem_table_to_disk();
-        self.truncate_wal().unwrap();
+    fn compact_family(&self, family: &ColumnFamily) -> io::Result<()> {
+        if let Some(info) = family.compact(&self.event_listeners)? {
+            self.stats.compaction_micros.record_duration(info.duration);
+            Statistics::add(&self.stats.compaction_bytes_read, info.bytes_read);
+            Statistics::add(&self.stats.compaction_bytes_written, info.bytes_written);
+            self.event_listeners
+                .notify(|listener| listener.on_compaction_completed(&info));
+        }
+        Ok(())
     }
 
-    fn truncate_wal(&mut self) -> std::io::Result<()> {
-        let temp_log_filename = self.dir.join(WRITE_AHEAD_LOG_FILENAME.to_owned() + ".temp");
-        let _log_file = fs::File::options()
+    /// Returns the size of the WAL that was replaced
+    fn truncate_wal(&self, writer: &mut Writer) -> std::io::Result<u64> {
+        let dir = &self.dir;
+        writer.wal_writer.flush()?;
+        let previous_size_in_bytes = writer.wal_writer.get_ref().metadata()?.len();
+        let temp_log_filename = dir.join(WRITE_AHEAD_LOG_FILENAME.to_owned() + ".temp");
+        let log_file = fs::File::options()
             .create(true)
-            .write(true)
-            .open(&temp_log_filename)
-            .unwrap();
+            .append(true)
+            .open(&temp_log_filename)?;
+        log_file.set_len(0)?;
+
+        std::fs::rename(temp_log_filename, dir.join(WRITE_AHEAD_LOG_FILENAME))?;
+        sync_dir(dir)?;
+        Statistics::add(&self.stats.wal_syncs, 1);
+        // The old writer points at the log we just replaced
+        writer.wal_writer = BufWriter::new(log_file);
+        writer.wal_size_in_bytes = 0;
+        writer.wal_broken = false;
+        Ok(previous_size_in_bytes)
+    }
 
-        std::fs::rename(temp_log_filename, self.dir.join(WRITE_AHEAD_LOG_FILENAME))
+    /// A failed append can leave part of a batch at the end of the WAL. Replaying stops there, so
+    /// any batch appended after it would be lost, even though its write succeeded. Cuts the WAL
+    /// back to where the last intact batch ends, or refuses every later write if even that fails
+    fn cut_off_torn_batch(&self, writer: &mut Writer) {
+        let cut_off = fs::File::options()
+            .append(true)
+            .open(self.dir.join(WRITE_AHEAD_LOG_FILENAME))
+            .and_then(|log_file| {
+                log_file.set_len(writer.wal_size_in_bytes)?;
+                log_file.sync_all()?;
+                Statistics::add(&self.stats.wal_syncs, 1);
+                Ok(log_file)
+            });
+        match cut_off {
+            Ok(log_file) => {
+                // Whatever part of the batch is still buffered must never reach the new log file
+                let torn_writer =
+                    std::mem::replace(&mut writer.wal_writer, BufWriter::new(log_file));
+                let _ = torn_writer.into_parts();
+            }
+            Err(_) => writer.wal_broken = true,
+        }
     }
 
-    /// Write a key value pair into memory, with no durability
-    fn put_into_memory(&mut self, key: &[u8], value: &[u8]) {
-        let key_len = key.len();
-        self.active_mem_table
-            .insert(key.to_vec(), TableEntry::Populated(value.to_owned()));
-        self.bytes_written_since_last_flush += (key_len + value.len()) as u64;
+    /// How much a write counts towards the mem table size limit
+    fn bytes_for_entry(key: &[u8], entry: &TableEntry) -> u64 {
+        match entry {
+            // TODO: FIXME: REFACTOR: We don't increase the mem table bytes written on removes
+            TableEntry::Tombstone => 0,
+            TableEntry::Populated { value, .. } => (key.len() + 8 + value.len()) as u64,
+            TableEntry::InValueLog { .. } => unreachable!("Mem tables never hold value pointers"),
+            TableEntry::RangeTombstone { end } => (key.len() + end.len()) as u64,
+        }
     }
 
     // Stores value with key. User is responsible for serializing/deserializing
-    pub fn put(&mut self, key: &[u8], value: &[u8]) {
-        Self::append_kv_to_file(
-            &mut self.wal_writer,
-            key.len() as u32,
-            key,
-            value.len() as u32,
-            Some(value),
+    pub fn put(&self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.put_cf(DEFAULT_COLUMN_FAMILY, key, value)
+    }
+
+    /// Stores value with key, and treats the key as absent once ttl has passed. Expired keys are
+    /// dropped from the store files on the next compaction
+    pub fn put_with_ttl(&self, key: &[u8], value: &[u8], ttl: Duration) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put_with_ttl(DEFAULT_COLUMN_FAMILY, key, value, ttl);
+        self.write(batch)
+    }
+
+    pub fn put_cf(&self, column_family: &str, key: &[u8], value: &[u8]) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.put(column_family, key, value);
+        self.write(batch)
+    }
+
+    /// Applies every write in the batch atomically. Fails without writing anything if the batch
+    /// refers to a column family that doesn't exist. A batch that fills the mem tables flushes
+    /// them, and if that fails its error is returned even though the batch is already in the WAL
+    pub fn write(&self, batch: WriteBatch) -> io::Result<()> {
+        let mut writer = self.writer()?;
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if writer.wal_broken {
+            return Err(io::Error::other(
+                "An earlier write failed partway into the WAL, so nothing more can be written \
+                 until the mem tables are flushed or the store is reopened",
+            ));
+        }
+        self.check_entry_sizes(&batch)?;
+
+        // Column families can only be created or dropped by whoever holds the writer lock, so
+        // these stay valid until we're done
+        let mut families = Vec::with_capacity(batch.len());
+        {
+            let column_families = self.column_families.read().unwrap();
+            for batch_entry in &batch.entries {
+                let family = match column_families.get(&batch_entry.column_family) {
+                    Some(family) => family,
+                    None => return Err(column_family_not_found(&batch_entry.column_family)),
+                };
+                if let TableEntry::RangeTombstone { end } = &batch_entry.entry {
+                    if family.comparator.compare(&batch_entry.key, end) == Ordering::Greater {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "The start of a range to delete can't be after its end",
+                        ));
+                    }
+                }
+                families.push(family.clone());
+            }
+        }
+
+        let appended = wal::append_batch(
+            &mut writer.wal_writer,
+            families
+                .iter()
+                .zip(&batch.entries)
+                .map(|(family, batch_entry)| {
+                    (family.id, batch_entry.key.as_slice(), &batch_entry.entry)
+                }),
+        );
+        let wal_bytes_written = match appended {
+            Ok(wal_bytes_written) => wal_bytes_written,
+            Err(e) => {
+                self.cut_off_torn_batch(&mut writer);
+                return Err(e);
+            }
+        };
+        writer.wal_size_in_bytes += wal_bytes_written;
+        Statistics::add(&self.stats.wal_bytes_written, wal_bytes_written);
+        if self.options.sync_mode == SyncMode::EveryWrite {
+            writer.wal_writer.get_ref().sync_data()?;
+            Statistics::add(&self.stats.wal_syncs, 1);
+        }
+
+        for (family, batch_entry) in families.iter().zip(batch.entries) {
+            writer.bytes_written_since_last_flush +=
+                Self::bytes_for_entry(&batch_entry.key, &batch_entry.entry);
+            let counter = match batch_entry.entry {
+                TableEntry::Tombstone => &self.stats.removes,
+                TableEntry::RangeTombstone { .. } => &self.stats.range_deletes,
+                _ => &self.stats.puts,
+            };
+            Statistics::add(counter, 1);
+            family.insert(batch_entry.key, batch_entry.entry);
+        }
+        self.stats.mem_table_bytes.store(
+            writer.bytes_written_since_last_flush,
+            AtomicOrdering::Relaxed,
         );
-        self.put_into_memory(key, value);
-        if self.bytes_written_since_last_flush > self.mem_table_size_limit_in_bytes {
+
+        if writer.bytes_written_since_last_flush > self.options.mem_table_size_limit_in_bytes {
             // TODO: Handle ongoing writes as we persist the mem table in the background
-            self.flush_pending_writes();
+            let stalled = Instant::now();
+            let mem_table_bytes = writer.bytes_written_since_last_flush;
+            self.flush_with_writer(&mut writer)?;
+            drop(writer);
+            self.compact_by_policy();
+            let info = WriteStallInfo {
+                mem_table_bytes,
+                duration: stalled.elapsed(),
+            };
+            self.event_listeners
+                .notify(|listener| listener.on_write_stall(&info));
         }
+        Ok(())
     }
 
+    /// Writes a key value record as:
+    ///     key_size (varint)
+    ///     key (key_size bytes)
+    ///     expires_at (8 bytes)
+    ///     value_size (varint)
+    ///     value (value_size bytes)
+    ///
     /// Returns how many bytes were written in total
     fn append_kv_to_file(
-        writer: &mut BufWriter<File>,
-        key_size: u32,
+        writer: &mut impl Write,
         key: &[u8],
-        value_size: u32,
+        expires_at: u64,
         value: Option<&[u8]>,
     ) -> usize {
         // TODO: Make sure this ALWAYS appends and doesn't just write wherever
-        let key_size_bytes = key_size.to_le_bytes();
-        writer.write_all(&key_size_bytes).unwrap();
-        writer.write_all(&key).unwrap();
-        let value_size_bytes = value_size.to_le_bytes();
-        writer.write_all(&value_size_bytes).unwrap();
-
-        if let Some(value) = value {
-            writer.write_all(value).unwrap();
-        }
+        let value = value.unwrap_or_default();
+        let mut bytes_written = varint::write_varint(writer, key.len() as u64).unwrap();
+        writer.write_all(key).unwrap();
+        writer.write_all(&expires_at.to_le_bytes()).unwrap();
+        bytes_written += varint::write_varint(writer, value.len() as u64).unwrap();
+        writer.write_all(value).unwrap();
         writer.flush().unwrap();
-        return key_size_bytes.len() + key.len() + value_size_bytes.len() + value_size as usize;
+        return bytes_written + key.len() + 8 + value.len();
     }
 
     fn file_path_for_file_id(file_id: u64, dir_path: &Path) -> PathBuf {
@@ -171,114 +900,171 @@ impl Store {
         return filename;
     }
 
-    fn create_store_file(file_id: u64, dir_path: &Path) -> File {
-        // TODO: Return errors
-        let file_path = Self::file_path_for_file_id(file_id, dir_path);
-
-        // Always open the file like we want to keep it. If we're asked to wipe the dir, then there
-        // should be no files here anyway
-        let file = fs::File::options()
-            .append(true)
-            .create(true)
-            .read(true)
-            .open(&file_path)
-            .unwrap();
-        return file;
-    }
-
-    fn increment_file_id(&mut self) {
-        self.current_file_id += 1;
-    }
-
-    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
-        match self.active_mem_table.get(key).cloned() {
-            Some(table_entry) => match table_entry {
-                TableEntry::Tombstone => return None,
-                TableEntry::Populated(v) => Some(v),
-            },
-            None => {
-                for file_id in (0..=self.current_file_id).rev() {
-                    // Check our store files for the value
-                    if let Some(index) = self.store_indexes.get(&file_id) {
-                        if let Some(entry) = index.get(key) {
-                            let value_offset_in_file = entry.byte_offset + 4 + entry.key_size + 4; // Skip everything until the actual value
-                            let mut buffer: Vec<u8> = vec![0; entry.value_size];
-                            self.read_from_store_file(file_id, &mut buffer, value_offset_in_file);
-                            if buffer.is_empty() {
-                                // TODO: Is there a valid use case for having an empty value for a key? Assuming it is
-                                // the tombstone for now
-                                return None;
-                            }
-                            return Some(buffer);
-                        }
-                    } else {
-                        // No index here means we've probably reached beyond our active store
-                        // files
-                        return None;
-                    }
-                }
-                return None;
-            }
+    fn column_family(&self, name: &str) -> io::Result<Arc<ColumnFamily>> {
+        match self.column_families.read().unwrap().get(name) {
+            Some(family) => Ok(family.clone()),
+            None => Err(column_family_not_found(name)),
         }
     }
 
-    fn read_from_store_file(&self, file_id: u64, buffer: &mut [u8], offset: usize) {
-        let path = Self::file_path_for_file_id(file_id, &self.dir);
-        let file = File::open(path).unwrap();
-        file.read_exact_at(buffer, offset as u64).unwrap();
+    fn column_family_list(&self) -> Vec<Arc<ColumnFamily>> {
+        self.column_families
+            .read()
+            .unwrap()
+            .values()
+            .cloned()
+            .collect()
+    }
+
+    fn default_family(&self) -> Arc<ColumnFamily> {
+        // The default column family always exists
+        self.column_family(DEFAULT_COLUMN_FAMILY).unwrap()
     }
 
-    fn create_fresh_store_file(&mut self) -> File {
-        self.increment_file_id();
-        Self::create_store_file(self.current_file_id, &self.dir)
+    /// Fails if a store file or value log segment the key's value would be in can't be read
+    pub fn get(&self, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        self.get_from(&self.default_family(), key)
     }
 
-    /// Assumes mem table keys are sorted!
-    fn write_mem_table_to_disk(&mut self) {
-        let file = self.create_fresh_store_file();
+    pub fn get_cf(&self, column_family: &str, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let family = self.column_family(column_family)?;
+        self.get_from(&family, key)
+    }
 
-        let mut writer = BufWriter::new(file);
+    fn get_from(&self, family: &ColumnFamily, key: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        let started = Instant::now();
+        let value = family.get(key);
+        Statistics::add(&self.stats.gets, 1);
+        self.stats.get_micros.record_duration(started.elapsed());
+        return value;
+    }
 
-        let mut file_offset = 0;
-        self.store_indexes
-            .insert(self.current_file_id, StoreData::new());
-        let store_index = self.store_indexes.get_mut(&self.current_file_id).unwrap();
-        for (key, value) in self.active_mem_table.iter() {
-            let (value, value_size) = match value {
-                TableEntry::Tombstone => (None, 0),
-                TableEntry::Populated(v) => (Some(v.as_slice()), v.len() as u32),
-            };
-            let key_size = key.len();
-            let bytes_written =
-                Self::append_kv_to_file(&mut writer, key_size as u32, &key, value_size, value);
-            let entry = StoreEntry {
-                value_size: value_size as usize,
-                key_size: key_size as usize,
-                byte_offset: file_offset,
-                file_id: self.current_file_id,
-            };
-            store_index.insert(key.clone(), entry);
-            file_offset += bytes_written;
-        }
-        self.active_mem_table.clear();
-        writer.flush().unwrap();
-        self.bytes_written_since_last_flush = 0;
+    pub fn remove(&self, key: &[u8]) -> io::Result<()> {
+        self.remove_cf(DEFAULT_COLUMN_FAMILY, key)
     }
 
-    pub fn remove(&mut self, key: &[u8]) {
+    /// Removes every key from start up to, but not including, end with a single range tombstone,
+    /// however many keys are in the range
+    pub fn delete_range(&self, start: &[u8], end: &[u8]) -> io::Result<()> {
+        self.delete_range_cf(DEFAULT_COLUMN_FAMILY, start, end)
+    }
+
+    pub fn delete_range_cf(&self, column_family: &str, start: &[u8], end: &[u8]) -> io::Result<()> {
+        let mut batch = WriteBatch::new();
+        batch.delete_range(column_family, start, end);
+        self.write(batch)
+    }
+
+    pub fn remove_cf(&self, column_family: &str, key: &[u8]) -> io::Result<()> {
         // No value after key is our "tombstone" for now - Not a great idea if we ever wanted to
         // checksum rows for corruption/crash recovery. No value = No bytes = Nothing to use as a
         // tombstone checksum(?)
-        // TODO: Cleanup duplication with regular "store" method"
+        let mut batch = WriteBatch::new();
+        batch.remove(column_family, key);
+        self.write(batch)
+    }
+
+    /// Creates an empty column family. Its store files are kept separate from every other column
+    /// family, and it is remembered across restarts until dropped. Its keys are ordered by the
+    /// comparator given for it with `StoreOptions::column_family_comparator`, if any
+    pub fn create_column_family(&self, name: &str, options: ColumnFamilyOptions) -> io::Result<()> {
+        let is_valid_name = !name.is_empty()
+            && name
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-');
+        if !is_valid_name {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Column family names may only contain ASCII letters, digits, '_' and '-', got {:?}",
+                    name
+                ),
+            ));
+        }
+        options.compaction_policy.validate()?;
+        let mut writer = self.writer()?;
+        if self.column_families.read().unwrap().contains_key(name) {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("Column family {} already exists", name),
+            ));
+        }
 
-        // TODO: FIXME: We'll don't persist the mem table with deletes
-        // TODO: FIXME: REFACTOR: We don't increase the mem table bytes written on removes
-        Self::append_kv_to_file(&mut self.wal_writer, key.len() as u32, key, 0, None);
-        self.active_mem_table
-            .insert(key.to_owned(), TableEntry::Tombstone);
+        let family_dir = ColumnFamily::dir_for(&self.dir, name);
+        // Leftovers from a drop that crashed before its files were removed
+        if let Err(e) = fs::remove_dir_all(&family_dir) {
+            if e.kind() != io::ErrorKind::NotFound {
+                return Err(e);
+            }
+        }
+        let family = ColumnFamily::open(
+            writer.next_column_family_id,
+            name,
+            options,
+            family_dir,
+            &self.options,
+            &self.caches,
+        )?;
+        writer.next_column_family_id += 1;
+        let mut column_families = self.column_families.write().unwrap();
+        column_families.insert(name.to_string(), Arc::new(family));
+        Self::save_manifest(&self.dir, &column_families, &writer)
+    }
+
+    /// Removes a column family along with all of its data
+    pub fn drop_column_family(&self, name: &str) -> io::Result<()> {
+        if name == DEFAULT_COLUMN_FAMILY {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "The default column family can't be dropped",
+            ));
+        }
+        let writer = self.writer()?;
+        let mut column_families = self.column_families.write().unwrap();
+        let family = match column_families.remove(name) {
+            Some(family) => family,
+            None => return Err(column_family_not_found(name)),
+        };
+        // Any of its writes still in the WAL are skipped on replay once the manifest forgets it
+        Self::save_manifest(&self.dir, &column_families, &writer)?;
+        fs::remove_dir_all(&family.dir)
+    }
+
+    pub fn column_family_names(&self) -> Vec<String> {
+        let mut names: Vec<_> = self
+            .column_families
+            .read()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect();
+        names.sort();
+        return names;
+    }
+
+    fn save_manifest(
+        dir: &Path,
+        column_families: &HashMap<String, Arc<ColumnFamily>>,
+        writer: &Writer,
+    ) -> io::Result<()> {
+        let mut column_family_descriptors: Vec<_> = column_families
+            .values()
+            .map(|family| ColumnFamilyDescriptor {
+                id: family.id,
+                name: family.name.clone(),
+                options: family.options.clone(),
+                comparator: family.comparator.name().to_string(),
+            })
+            .collect();
+        column_family_descriptors.sort_by_key(|descriptor| descriptor.id);
+        let manifest = Manifest {
+            next_column_family_id: writer.next_column_family_id,
+            column_families: column_family_descriptors,
+        };
+        manifest.save(dir)
     }
 
-    fn is_store_file(path: &PathBuf) -> bool {
+    fn is_store_file(path: &Path) -> bool {
         path.file_name()
             .unwrap()
             .to_string_lossy()
@@ -286,15 +1072,21 @@ impl Store {
     }
 
     // TODO: This name feels a bit misleading since it's just the "data" we're building up
-    fn build_store_from_dir(dir_path: &Path) -> (StoreIndexes, u64) {
-        let mut entries = Self::get_store_files(dir_path);
+    fn build_store_from_dir(
+        dir_path: &Path,
+        pin_files: bool,
+        caches: &Arc<StoreFileCaches>,
+        value_log: &ValueLog,
+        comparator: &Arc<dyn Comparator>,
+    ) -> io::Result<(Vec<Arc<StoreFile>>, u64)> {
+        let mut entries = Self::get_store_files(dir_path)?;
 
         entries.sort();
         // TODO: Assuming that the directory only holds good data, and no bad files (such as
         // malformed file names).
 
         let mut highest_file_id = 1;
-        let mut store_index = HashMap::new();
+        let mut store_files = Vec::new();
         for entry in entries {
             let filename = entry.strip_prefix(dir_path).unwrap();
             let current_file_id = Store::file_id_from_path(filename);
@@ -305,82 +1097,52 @@ impl Store {
                 highest_file_id = current_file_id;
             }
 
-            store_index.insert(current_file_id, StoreData::new());
-            let store_data = store_index.get_mut(&current_file_id).unwrap();
-
-            Self::parse_store_file_into_store_data(&dir_path, current_file_id, store_data);
+            store_files.push(Arc::new(StoreFile::open(
+                current_file_id,
+                entry,
+                pin_files,
+                caches,
+                value_log,
+                comparator,
+            )?));
         }
-        return (store_index, highest_file_id);
+        // Newest first, since that's the order reads check them in
+        store_files.sort_by_key(|file| std::cmp::Reverse(file.id));
+        return Ok((store_files, highest_file_id));
     }
 
-    /// Will increment byte_offset by:
-    ///     key_size value (4 bytes)
-    ///     key (key_size bytes)
-    ///     value_size value (4 bytes)
-    ///     value (value_size bytes)
-    fn parse_key_value_from_bytes(byte_offset: &mut FileOffset, bytes: &[u8]) -> KeyValue {
-        let key_size =
-            u32::from_le_bytes(bytes[*byte_offset..(*byte_offset + 4)].try_into().unwrap());
-        *byte_offset += 4;
-
-        let key = bytes[*byte_offset..(*byte_offset + key_size as usize)].to_vec();
-        *byte_offset += key_size as usize;
-        let value_size =
-            u32::from_le_bytes(bytes[*byte_offset..(*byte_offset + 4)].try_into().unwrap());
-        *byte_offset += 4;
-        let value = bytes[*byte_offset..(*byte_offset + value_size as usize)].to_vec();
-        *byte_offset += value_size as usize;
-
-        let kv = KeyValue {
+    /// Reads a record written by append_kv_to_file, and moves byte_offset past it. Records that
+    /// run past the end of bytes are refused with InvalidData, since they can only come from
+    /// damage the caller's checksums didn't catch
+    fn parse_key_value_from_bytes(
+        byte_offset: &mut FileOffset,
+        bytes: &[u8],
+    ) -> io::Result<KeyValue> {
+        let record_offset = *byte_offset;
+        let truncated = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the record at byte {} is truncated", record_offset),
+            )
+        };
+        let key_size = varint::read_varint(bytes, byte_offset).ok_or_else(truncated)?;
+        let key = take_bytes(bytes, byte_offset, key_size)
+            .ok_or_else(truncated)?
+            .to_vec();
+        let expires_at = take_bytes(bytes, byte_offset, 8).ok_or_else(truncated)?;
+        let expires_at = u64::from_le_bytes(expires_at.try_into().unwrap());
+        let value_size = varint::read_varint(bytes, byte_offset).ok_or_else(truncated)?;
+        let value = take_bytes(bytes, byte_offset, value_size)
+            .ok_or_else(truncated)?
+            .to_vec();
+
+        return Ok(KeyValue {
             value_size,
             key_size,
             key,
+            expires_at,
             value,
-        };
-        return kv;
-    }
-
-    fn parse_file_into_kv(
-        dir_path: &Path,
-        file_id: u64,
-        key_values: &mut HashMap<Vec<u8>, Vec<u8>>,
-    ) {
-        let path_to_open = Self::file_path_for_file_id(file_id, dir_path);
-        let mut file = File::open(path_to_open).unwrap();
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        let mut byte_offset = 0;
-        loop {
-            let kv = Store::parse_key_value_from_bytes(&mut byte_offset, &buffer);
-            key_values.insert(kv.key, kv.value);
-            if byte_offset >= buffer.len() {
-                break;
-            }
-        }
-    }
-
-    fn parse_store_file_into_store_data(dir_path: &Path, file_id: u64, store_data: &mut StoreData) {
-        let path_to_open = Self::file_path_for_file_id(file_id, dir_path);
-        let mut file = File::open(path_to_open).unwrap();
-        let mut buffer = Vec::new();
-        file.read_to_end(&mut buffer).unwrap();
-        let mut byte_offset = 0;
-
-        loop {
-            if byte_offset >= buffer.len() {
-                break;
-            }
-            let byte_offset_for_key = byte_offset;
-            let kv = Store::parse_key_value_from_bytes(&mut byte_offset, &buffer);
-            let entry = StoreEntry {
-                value_size: kv.value_size as usize,
-                key_size: kv.key_size as usize,
-                byte_offset: byte_offset_for_key,
-                file_id,
-            };
-
-            store_data.insert(kv.key, entry);
-        }
+        });
     }
 
     fn file_id_from_path(filename: &Path) -> u64 {
@@ -390,117 +1152,215 @@ impl Store {
         return file_id;
     }
 
-    fn get_store_files(dir: &Path) -> Vec<PathBuf> {
+    fn get_store_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
         // TODO: NOTE: Assuming the filepaths here are all perfect for the program for now
-        fs::read_dir(dir)
-            .unwrap()
-            .map(|res| res.map(|e| e.path()).unwrap())
-            .filter(|path| Self::is_store_file(&path))
-            .collect::<Vec<_>>()
+        let mut store_files = Vec::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if Self::is_store_file(&path) {
+                store_files.push(path);
+            }
+        }
+        return Ok(store_files);
     }
 
-    pub fn compact(&mut self) {
-        // TODO: Background thread!
-
-        let mut files_for_compaction = Self::get_store_files(&self.dir);
-
-        // TODO: Is there a limit to compaction for files? i.e a certain length? Ignoring for
-        // now!
-
-        if files_for_compaction.is_empty() {
-            return;
+    /// Compacts the store files of every column family
+    pub fn compact(&self) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(read_only_error());
         }
-
-        files_for_compaction
-            .sort_by(|a, b| Self::file_id_from_path(a).cmp(&Self::file_id_from_path(b)));
-
-        let mut compacted_kvs = HashMap::new();
-        for entry in &files_for_compaction {
-            let filename = entry.strip_prefix(&self.dir).unwrap();
-            let file_id = Self::file_id_from_path(filename);
-            Self::parse_file_into_kv(&self.dir, file_id, &mut compacted_kvs);
+        for family in self.column_family_list() {
+            self.compact_family(&family)?;
         }
+        Ok(())
+    }
 
-        let compaction_file_id = self.current_file_id;
-        let compaction_sacrifice_filename = Self::filename_for_file_id(compaction_file_id);
-        let compaction_sacrifice_file_path = self.dir.join(&compaction_sacrifice_filename);
-        let compaction_filename = "temp.".to_string() + &compaction_sacrifice_filename;
-        let compaction_file_path = self.dir.join(&compaction_filename);
-        let compaction_file = File::create(&compaction_file_path).unwrap();
-
-        let mut compaction_file = BufWriter::new(compaction_file);
-
-        let mut mapping_entries = HashMap::new();
-        let mut file_offset = 0;
-        for (k, v) in &compacted_kvs {
-            let (entry, bytes_written) =
-                Self::create_entry(&mut compaction_file, &k, v, file_offset, compaction_file_id);
-
-            file_offset += bytes_written;
-
-            mapping_entries.insert(k.to_owned(), entry);
+    pub fn compact_cf(&self, column_family: &str) -> io::Result<()> {
+        if self.is_read_only() {
+            return Err(read_only_error());
         }
+        let family = self.column_family(column_family)?;
+        self.compact_family(&family)
+    }
 
-        std::fs::rename(compaction_file_path, compaction_sacrifice_file_path).unwrap();
-        self.store_indexes
-            .insert(compaction_file_id, mapping_entries);
+    /// Adds store files built by a `StoreFileWriter` to the default column family, without going
+    /// through the WAL or mem tables. Each file is given a new id, so its keys shadow everything
+    /// written before it, and later files in paths shadow earlier ones. The files are hard linked
+    /// into the store where possible, so they must not be changed afterwards, but can be deleted.
+    ///
+    /// Readers see either none of the files or all of them. Every file is checked before any is
+    /// added, and an invalid file, or one written with a different comparator, fails with
+    /// `ErrorKind::InvalidData` or `ErrorKind::InvalidInput` without adding anything
+    pub fn ingest_files(&self, paths: &[PathBuf]) -> io::Result<()> {
+        self.ingest_files_cf(DEFAULT_COLUMN_FAMILY, paths)
+    }
 
-        // Ensure we don't delete our newly compacted file as well!
-        files_for_compaction.remove(files_for_compaction.len() - 1);
-        for entry in &files_for_compaction {
-            fs::remove_file(entry).unwrap();
+    pub fn ingest_files_cf(&self, column_family: &str, paths: &[PathBuf]) -> io::Result<()> {
+        let mut writer = self.writer()?;
+        let family = self.column_family(column_family)?;
+        // Whatever is in the mem tables was written before the files, so it has to end up in
+        // store files that the ingested ones can shadow
+        if family.has_unflushed_writes() {
+            self.flush_with_writer(&mut writer)?;
         }
+        family.ingest_store_files(paths)?;
+        drop(writer);
+        self.compact_by_policy();
+        Ok(())
     }
 
-    /// Returns the Entry created for key and value, and how many bytes were written to file
-    fn create_entry(
-        writer: &mut BufWriter<File>,
-        key: &[u8],
-        value: &[u8],
-        file_offset: FileOffset,
-        file_id: u64,
-    ) -> (StoreEntry, usize) {
-        let value_size = value.len() as u32;
-        let key_size = key.len();
-        let bytes_written =
-            Store::append_kv_to_file(writer, key_size as u32, key, value_size, Some(value));
-        let entry = StoreEntry {
-            value_size: value_size as usize,
-            key_size: key_size as usize, // TODO: Use the actual key's size once it's not just a u32
-            byte_offset: file_offset,
-            file_id,
+    /// Writes every live key in every column family to writer, along with its value and expiry,
+    /// in a format that doesn't depend on how the store keeps its files. Returns how many keys
+    /// were exported. The format is versioned and every record is checksummed, as described in the
+    /// README.
+    ///
+    /// Writable stores flush first, so the export holds exactly the writes made before it was
+    /// called while later writes carry on
+    pub fn export(&self, writer: impl Write) -> io::Result<u64> {
+        let mut families = self.column_family_list();
+        families.sort_by(|a, b| a.name.cmp(&b.name));
+        let snapshots: Vec<_> = match &self.writer {
+            Some(_) => {
+                let mut writer = self.writer()?;
+                self.flush_with_writer(&mut writer)?;
+                families
+                    .iter()
+                    .map(|family| family.snapshot(false))
+                    .collect()
+            }
+            // Nothing can change a read only store's mem tables
+            None => families
+                .iter()
+                .map(|family| family.snapshot(true))
+                .collect(),
         };
-        return (entry, bytes_written);
+
+        let mut export = ExportWriter::new(writer)?;
+        for (family, snapshot) in families.iter().zip(&snapshots) {
+            export.column_family(&family.name)?;
+            family.for_each_live_entry(snapshot, |key, value, expires_at| {
+                export.entry(key, value, expires_at)
+            })?;
+        }
+        export.finish()
     }
 
-    fn restore_from_wal(write_ahead_log_path: PathBuf) -> Result<Vec<KeyValue>, std::io::Error> {
-        let mut write_ahead_log_file =
-            match fs::File::options().read(true).open(write_ahead_log_path) {
-                Ok(file) => file,
-                Err(e) => match e.kind() {
-                    std::io::ErrorKind::NotFound => {
-                        // No WAL, nothing to restore!
-                        return Ok(vec![]);
+    /// Writes every key from an export into the store, creating any column families it doesn't
+    /// have yet. Keys keep their expiry, and ones that have already expired are skipped. Returns
+    /// how many keys were imported.
+    ///
+    /// Keys are written in batches as the export is read, so if the export turns out to be
+    /// damaged part way through, this fails with `ErrorKind::InvalidData` with the keys before
+    /// the damage already written
+    pub fn import(&self, reader: impl Read) -> io::Result<u64> {
+        let mut export = ExportReader::new(reader)?;
+        let mut column_family = None;
+        let mut batch = WriteBatch::new();
+        let mut imported = 0;
+        while let Some(record) = export.next_record()? {
+            match record {
+                ExportRecord::ColumnFamily(name) => {
+                    // The batch has to be written before any column family it refers to can be
+                    // created
+                    self.write(std::mem::take(&mut batch))?;
+                    if !self.column_family_names().contains(&name) {
+                        self.create_column_family(&name, ColumnFamilyOptions::default())?;
                     }
-                    _ => return Err(e),
-                },
-            };
-
-        let mut buffer = Vec::new();
-        write_ahead_log_file.read_to_end(&mut buffer).unwrap();
-        let mut byte_offset = 0;
-
-        let mut recovered_kvs = Vec::new();
-
-        loop {
-            if byte_offset >= buffer.len() {
-                break;
+                    column_family = Some(name);
+                }
+                ExportRecord::Entry {
+                    key,
+                    value,
+                    expires_at,
+                } => {
+                    let Some(column_family) = &column_family else {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            "Invalid export: entry before any column family",
+                        ));
+                    };
+                    if is_expired(expires_at) {
+                        continue;
+                    }
+                    batch.put_with_expiry(column_family, &key, &value, expires_at);
+                    imported += 1;
+                    if batch.len() >= IMPORT_BATCH_SIZE {
+                        self.write(std::mem::take(&mut batch))?;
+                    }
+                }
             }
-            let kv = Store::parse_key_value_from_bytes(&mut byte_offset, &buffer);
-            recovered_kvs.push(kv);
         }
+        self.write(batch)?;
+        Ok(imported)
+    }
 
-        return Ok(recovered_kvs);
+    /// Writes a copy of the store to dest_dir that opens like any other store, while this one
+    /// carries on serving reads and writes. Store files and value log segments never change once
+    /// written, so they are hard linked where possible rather than copied, and the WAL is copied
+    /// to cover whatever hasn't been flushed yet. Fails with `ErrorKind::AlreadyExists` if
+    /// dest_dir exists, and leaves nothing at dest_dir unless it succeeds
+    pub fn checkpoint(&self, dest_dir: &Path) -> io::Result<()> {
+        // Holding the writer keeps out flushes and column family changes, so the WAL we copy holds
+        // exactly the writes that aren't in the store files we take. Compactions can still swap
+        // files out, but the ones we hold on to stay on disk until they're linked
+        let mut writer = self.writer()?;
+        let unfinished_dir = create_temp_dir_for(dest_dir)?;
+        let temp_dir = unfinished_dir.path();
+        writer.wal_writer.flush()?;
+        fs::copy(
+            self.dir.join(WRITE_AHEAD_LOG_FILENAME),
+            temp_dir.join(WRITE_AHEAD_LOG_FILENAME),
+        )?;
+        File::open(temp_dir.join(WRITE_AHEAD_LOG_FILENAME))?.sync_all()?;
+        Manifest::load(&self.dir)?.save(temp_dir)?;
+        let families = self
+            .column_family_list()
+            .into_iter()
+            .map(|family| (family.name.clone(), family.store_files()))
+            .collect::<Vec<_>>();
+        drop(writer);
+        self.options.save(temp_dir)?;
+
+        for (name, store_files) in families {
+            let family_dir = ColumnFamily::dir_for(temp_dir, &name);
+            fs::create_dir_all(&family_dir)?;
+            let mut segments = HashMap::new();
+            for store_file in store_files.iter() {
+                link_or_copy(
+                    &store_file.path,
+                    &family_dir.join(Self::filename_for_file_id(store_file.id)),
+                )?;
+                for segment in store_file.value_log_segments() {
+                    segments.insert(segment.id, segment.clone());
+                }
+            }
+            for segment in segments.values() {
+                link_or_copy(
+                    &segment.path,
+                    &family_dir.join(segment.path.file_name().unwrap()),
+                )?;
+            }
+            sync_dir(&family_dir)?;
+        }
+        if temp_dir.join(COLUMN_FAMILIES_DIRNAME).exists() {
+            sync_dir(&temp_dir.join(COLUMN_FAMILIES_DIRNAME))?;
+        }
+        unfinished_dir.move_into_place(dest_dir)
+    }
+}
+
+impl Drop for Store {
+    fn drop(&mut self) {
+        if std::thread::panicking() {
+            // Flushing could panic too. Everything is in the WAL anyway
+            return;
+        }
+        if let Err(e) = self.shut_down() {
+            eprintln!("Failed to cleanly shut down store in {:?}: {}", self.dir, e);
+            self.event_listeners.notify(|listener| {
+                listener.on_background_error(&BackgroundErrorReason::ShutDown, &e)
+            });
+        }
     }
 }
 
@@ -510,49 +1370,61 @@ mod tests {
 
     const TEMP_TEST_FILE_DIR: &str = "./tmp_test_files/";
 
+    fn open_fresh(test_dir: &str, options: StoreOptions) -> Store {
+        let _ = fs::remove_dir_all(test_dir);
+        Store::open(Path::new(test_dir), &options).unwrap()
+    }
+
     #[test]
     fn it_stores_and_retreives() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "stores_and_retrieves";
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let test_key = 50_u32.to_ne_bytes();
-        assert_eq!(store.get(&test_key), None);
+        assert_eq!(store.get(&test_key).unwrap(), None);
 
-        store.put(&test_key, "100".as_bytes());
-        assert_eq!(store.get(&test_key).unwrap(), 100.to_string().as_bytes());
-        store.put(&test_key, "101".as_bytes());
-        assert_eq!(store.get(&test_key).unwrap(), 101.to_string().as_bytes());
+        store.put(&test_key, "100".as_bytes()).unwrap();
+        assert_eq!(
+            store.get(&test_key).unwrap().unwrap(),
+            100.to_string().as_bytes()
+        );
+        store.put(&test_key, "101".as_bytes()).unwrap();
+        assert_eq!(
+            store.get(&test_key).unwrap().unwrap(),
+            101.to_string().as_bytes()
+        );
     }
 
     #[test]
     fn it_deletes() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "deletes";
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let test_key = 50_u32.to_ne_bytes();
-        store.put(&test_key, "100".as_bytes());
+        store.put(&test_key, "100".as_bytes()).unwrap();
 
-        store.remove(&test_key);
-        assert_eq!(store.get(&test_key), None);
+        store.remove(&test_key).unwrap();
+        assert_eq!(store.get(&test_key).unwrap(), None);
     }
 
     #[test]
     fn it_persists() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "persists";
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let deleted_test_key = 50_u32.to_ne_bytes();
         let other_test_key = "Longer key".as_bytes();
-        store.put(&deleted_test_key, "100".as_bytes());
-        store.remove(&deleted_test_key);
+        store.put(&deleted_test_key, "100".as_bytes()).unwrap();
+        store.remove(&deleted_test_key).unwrap();
 
-        store.put(&other_test_key, "1000".as_bytes());
-        store.remove(&other_test_key);
-        store.put(&other_test_key, "2000".as_bytes());
+        store.put(other_test_key, "1000".as_bytes()).unwrap();
+        store.remove(other_test_key).unwrap();
+        store.put(other_test_key, "2000".as_bytes()).unwrap();
 
-        store.flush_pending_writes();
+        store.flush_pending_writes().unwrap();
 
+        drop(store);
         let store = Store::new(Path::new(&test_dir), true);
 
-        assert_eq!(store.get(&deleted_test_key), None);
-        let val = store.get(&other_test_key).unwrap();
+        assert_eq!(store.get(&deleted_test_key).unwrap(), None);
+        let val = store.get(other_test_key).unwrap().unwrap();
         let expected_val = 2000.to_string();
         let expected_val = expected_val.as_bytes();
         assert_eq!(val, expected_val);
@@ -561,53 +1433,57 @@ mod tests {
     #[test]
     fn it_stores_and_retrieves_using_entries() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "entries-store";
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let key = 1_u32.to_ne_bytes();
         let value = "2".as_bytes();
-        store.put(&key, value);
+        store.put(&key, value).unwrap();
 
-        let bytes = store.get(&key).unwrap();
+        let bytes = store.get(&key).unwrap().unwrap();
         assert_eq!(bytes, value);
         let key = 500_u32.to_ne_bytes();
         let value = "5000000".as_bytes();
-        store.put(&key, value);
-        let bytes = store.get(&key).unwrap();
+        store.put(&key, value).unwrap();
+        let bytes = store.get(&key).unwrap().unwrap();
         assert_eq!(bytes, value);
     }
 
     #[test]
     fn it_creates_a_new_file_after_crossing_mem_table_size_limit() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "mutliple-files";
-        let mut store = Store::new(Path::new(&test_dir), false);
-        store.mem_table_size_limit_in_bytes = 1;
-        assert_eq!(store.current_file_id, 1);
+        let store = open_fresh(
+            &test_dir,
+            StoreOptions::new().mem_table_size_limit_in_bytes(1),
+        );
+        assert_eq!(store.default_family().current_file_id(), 1);
         let key = 1_u32.to_ne_bytes();
         let value = "2".as_bytes();
-        store.put(&key, value);
+        store.put(&key, value).unwrap();
 
         let key = 500_u32.to_ne_bytes();
         let value = "5000000".as_bytes();
-        store.put(&key, value);
+        store.put(&key, value).unwrap();
 
-        assert_eq!(store.current_file_id, 3);
-        let store_files = Store::get_store_files(&PathBuf::from(&test_dir));
+        assert_eq!(store.default_family().current_file_id(), 3);
+        let store_files = Store::get_store_files(&PathBuf::from(&test_dir)).unwrap();
         assert_eq!(store_files.len(), 2);
     }
 
     #[test]
     fn it_reads_from_across_files() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "mutliple-files-reading";
-        let mut store = Store::new(Path::new(&test_dir), false);
-        store.mem_table_size_limit_in_bytes = 1;
-        assert_eq!(store.current_file_id, 1);
+        let store = open_fresh(
+            &test_dir,
+            StoreOptions::new().mem_table_size_limit_in_bytes(1),
+        );
+        assert_eq!(store.default_family().current_file_id(), 1);
 
         let key = "Longer key".as_bytes();
         let test_value = "10".as_bytes();
-        store.put(&key, test_value);
-        store.put(&2_u32.to_ne_bytes(), "20".as_bytes());
-        store.put(&3_u32.to_ne_bytes(), "30".as_bytes());
+        store.put(key, test_value).unwrap();
+        store.put(&2_u32.to_ne_bytes(), "20".as_bytes()).unwrap();
+        store.put(&3_u32.to_ne_bytes(), "30".as_bytes()).unwrap();
 
-        let result = store.get(&key).unwrap();
+        let result = store.get(key).unwrap().unwrap();
 
         assert_eq!(result, test_value);
     }
@@ -615,29 +1491,31 @@ mod tests {
     #[test]
     fn it_compacts_old_files_into_a_merged_file() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "file-compaction/untouched-active-file";
-        let mut store = Store::new(Path::new(&test_dir), false);
-        store.put(&1_u32.to_ne_bytes(), "10".as_bytes());
-        store.put(&1_u32.to_ne_bytes(), "1010".as_bytes());
-        store.put(&2_u32.to_ne_bytes(), "20".as_bytes());
-        store.put(&2_u32.to_ne_bytes(), "2020".as_bytes());
-        store.remove(&2_u32.to_ne_bytes());
-        store.flush_pending_writes();
-        store.put(&3_u32.to_ne_bytes(), "old".as_bytes());
-        store.put(&1_u32.to_ne_bytes(), "101010".as_bytes());
-        store.flush_pending_writes();
-        assert_eq!(store.current_file_id, 3);
-
-        store.put(&3_u32.to_ne_bytes(), "new".as_bytes());
-
-        store.compact();
-        let store_files = Store::get_store_files(&PathBuf::from(&test_dir));
+        let store = Store::new(Path::new(&test_dir), false);
+        store.put(&1_u32.to_ne_bytes(), "10".as_bytes()).unwrap();
+        store.put(&1_u32.to_ne_bytes(), "1010".as_bytes()).unwrap();
+        store.put(&2_u32.to_ne_bytes(), "20".as_bytes()).unwrap();
+        store.put(&2_u32.to_ne_bytes(), "2020".as_bytes()).unwrap();
+        store.remove(&2_u32.to_ne_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store.put(&3_u32.to_ne_bytes(), "old".as_bytes()).unwrap();
+        store
+            .put(&1_u32.to_ne_bytes(), "101010".as_bytes())
+            .unwrap();
+        store.flush_pending_writes().unwrap();
+        assert_eq!(store.default_family().current_file_id(), 3);
+
+        store.put(&3_u32.to_ne_bytes(), "new".as_bytes()).unwrap();
+
+        store.compact().unwrap();
+        let store_files = Store::get_store_files(&PathBuf::from(&test_dir)).unwrap();
 
         let expected_store_files = 1;
         let actual_store_files = store_files.len();
 
         assert_eq!(expected_store_files, actual_store_files);
         assert_eq!(
-            store.get(&3_u32.to_ne_bytes()),
+            store.get(&3_u32.to_ne_bytes()).unwrap(),
             Some("new".as_bytes().to_vec())
         );
     }
@@ -645,38 +1523,1728 @@ mod tests {
     #[test]
     fn compaction_will_squash_multiple_of_same_key_into_latest_value() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "file-compaction/remove-duplicates";
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let key_one = "Arbitrary string".as_bytes();
         let key_two = 2_u32.to_ne_bytes();
         let key_three = 3_u32.to_ne_bytes();
-        store.put(&key_one, "10".as_bytes());
-        store.put(&key_one, "1010".as_bytes());
-        store.put(&key_two, "20".as_bytes());
-        store.put(&key_two, "2020".as_bytes());
-        store.remove(&key_two);
-        store.put(&key_two, "202020".as_bytes());
-        store.flush_pending_writes();
-        store.put(&key_one, "101010".as_bytes());
-        store.flush_pending_writes();
-        assert_eq!(store.current_file_id, 3);
-        store.put(&key_three, "new".as_bytes());
-
-        store.compact();
+        store.put(key_one, "10".as_bytes()).unwrap();
+        store.put(key_one, "1010".as_bytes()).unwrap();
+        store.put(&key_two, "20".as_bytes()).unwrap();
+        store.put(&key_two, "2020".as_bytes()).unwrap();
+        store.remove(&key_two).unwrap();
+        store.put(&key_two, "202020".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store.put(key_one, "101010".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        assert_eq!(store.default_family().current_file_id(), 3);
+        store.put(&key_three, "new".as_bytes()).unwrap();
+
+        store.compact().unwrap();
 
-        assert_eq!(store.get(&key_one), Some("101010".as_bytes().to_vec()));
-        assert_eq!(store.get(&key_two), Some("202020".as_bytes().to_vec()));
+        assert_eq!(
+            store.get(key_one).unwrap(),
+            Some("101010".as_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get(&key_two).unwrap(),
+            Some("202020".as_bytes().to_vec())
+        );
     }
 
     #[test]
     fn mem_table_tombstones_removed_values() {
         let test_dir = TEMP_TEST_FILE_DIR.to_string() + "tombstone/mem-table-tombstone";
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let key_to_remove = 1_u32.to_ne_bytes();
-        store.put(&key_to_remove, "10".as_bytes());
-        store.flush_pending_writes();
-        assert_eq!(store.get(&key_to_remove), Some("10".as_bytes().to_vec()));
-        store.remove(&key_to_remove);
-        assert_eq!(store.get(&key_to_remove), None);
+        store.put(&key_to_remove, "10".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        assert_eq!(
+            store.get(&key_to_remove).unwrap(),
+            Some("10".as_bytes().to_vec())
+        );
+        store.remove(&key_to_remove).unwrap();
+        assert_eq!(store.get(&key_to_remove).unwrap(), None);
+    }
+
+    #[test]
+    fn huge_ttls_never_expire() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "ttl/huge-ttl";
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put_with_ttl(b"forever", b"1", Duration::MAX).unwrap();
+        assert_eq!(store.get(b"forever").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn expired_keys_are_absent() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "ttl/expired-keys";
+        let store = Store::new(Path::new(&test_dir), false);
+        let live_key = 1_u32.to_ne_bytes();
+        let expired_key = 2_u32.to_ne_bytes();
+        store.put(&expired_key, "old".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+
+        store
+            .put_with_ttl(&live_key, "10".as_bytes(), Duration::from_secs(60 * 60))
+            .unwrap();
+        store
+            .put_with_ttl(&expired_key, "20".as_bytes(), Duration::ZERO)
+            .unwrap();
+        assert_eq!(
+            store.get(&live_key).unwrap(),
+            Some("10".as_bytes().to_vec())
+        );
+        assert_eq!(store.get(&expired_key).unwrap(), None);
+
+        store.flush_pending_writes().unwrap();
+        drop(store);
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(
+            store.get(&live_key).unwrap(),
+            Some("10".as_bytes().to_vec())
+        );
+        assert_eq!(store.get(&expired_key).unwrap(), None);
+    }
+
+    #[test]
+    fn compaction_drops_expired_keys() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "ttl/compaction";
+        let store = Store::new(Path::new(&test_dir), false);
+        let expired_key = 1_u32.to_ne_bytes();
+        let live_key = 2_u32.to_ne_bytes();
+        store
+            .put_with_ttl(&expired_key, "10".as_bytes(), Duration::ZERO)
+            .unwrap();
+        store
+            .put_with_ttl(&live_key, "20".as_bytes(), Duration::from_secs(60 * 60))
+            .unwrap();
+        store.flush_pending_writes().unwrap();
+        store.put(&3_u32.to_ne_bytes(), "30".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+
+        store.compact().unwrap();
+
+        let store_files = store.default_family().store_files.read().unwrap().clone();
+        assert_eq!(store_files.len(), 1);
+        assert_eq!(store_files[0].properties.entry_count, 2);
+        assert!(store_files[0].get(&expired_key).unwrap().is_none());
+        assert!(store_files[0].get(&live_key).unwrap().is_some());
+        assert_eq!(store.get(&expired_key).unwrap(), None);
+        assert_eq!(
+            store.get(&live_key).unwrap(),
+            Some("20".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn column_families_are_separate_and_persist() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "column-families/separate";
+        let store = Store::new(Path::new(&test_dir), false);
+        let key = 1_u32.to_ne_bytes();
+        store
+            .create_column_family("sessions", ColumnFamilyOptions::default())
+            .unwrap();
+        store.put(&key, "default value".as_bytes()).unwrap();
+        store
+            .put_cf("sessions", &key, "session value".as_bytes())
+            .unwrap();
+        store.flush_pending_writes().unwrap();
+        store
+            .put_cf("sessions", &2_u32.to_ne_bytes(), "unflushed".as_bytes())
+            .unwrap();
+
+        store.simulate_crash();
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(store.column_family_names(), vec!["default", "sessions"]);
+        assert_eq!(
+            store.get(&key).unwrap(),
+            Some("default value".as_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get_cf("sessions", &key).unwrap(),
+            Some("session value".as_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get_cf("sessions", &2_u32.to_ne_bytes()).unwrap(),
+            Some("unflushed".as_bytes().to_vec())
+        );
+        assert_eq!(store.get(&2_u32.to_ne_bytes()).unwrap(), None);
+        assert!(store.get_cf("missing", &key).is_err());
+    }
+
+    #[test]
+    fn dropped_column_families_stay_dropped() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "column-families/dropped";
+        let store = Store::new(Path::new(&test_dir), false);
+        let key = 1_u32.to_ne_bytes();
+        store
+            .create_column_family("tenant", ColumnFamilyOptions::default())
+            .unwrap();
+        store.put_cf("tenant", &key, "flushed".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store
+            .put_cf("tenant", &key, "in the WAL".as_bytes())
+            .unwrap();
+
+        assert!(store.drop_column_family(DEFAULT_COLUMN_FAMILY).is_err());
+        store.drop_column_family("tenant").unwrap();
+        assert!(store.put_cf("tenant", &key, "10".as_bytes()).is_err());
+
+        store.simulate_crash();
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(store.column_family_names(), vec!["default"]);
+        store
+            .create_column_family("tenant", ColumnFamilyOptions::default())
+            .unwrap();
+        assert_eq!(store.get_cf("tenant", &key).unwrap(), None);
+    }
+
+    #[test]
+    fn write_batches_are_recovered_whole_from_the_wal() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "column-families/atomic-batches";
+        let store = Store::new(Path::new(&test_dir), false);
+        store
+            .create_column_family("accounts", ColumnFamilyOptions::default())
+            .unwrap();
+        let mut batch = WriteBatch::new();
+        batch.put(DEFAULT_COLUMN_FAMILY, "transfer".as_bytes(), "1".as_bytes());
+        batch.put("accounts", "alice".as_bytes(), "90".as_bytes());
+        store.write(batch).unwrap();
+
+        let mut batch = WriteBatch::new();
+        batch.put(DEFAULT_COLUMN_FAMILY, "transfer".as_bytes(), "2".as_bytes());
+        batch.put("accounts", "alice".as_bytes(), "80".as_bytes());
+        store.write(batch).unwrap();
+        store.simulate_crash();
+
+        // Simulate crashing part way through appending the second batch
+        let wal_path = Path::new(&test_dir).join(WRITE_AHEAD_LOG_FILENAME);
+        let wal_size = fs::metadata(&wal_path).unwrap().len();
+        let wal_file = File::options().write(true).open(&wal_path).unwrap();
+        wal_file.set_len(wal_size - 3).unwrap();
+
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(
+            store.get("transfer".as_bytes()).unwrap(),
+            Some("1".as_bytes().to_vec())
+        );
+        assert_eq!(
+            store.get_cf("accounts", "alice".as_bytes()).unwrap(),
+            Some("90".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn writes_after_recovering_from_a_torn_wal_survive_the_next_crash() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "torn-wal-recovery";
+        let options = StoreOptions::new();
+        let store = open_fresh(&test_dir, options.clone());
+        store.put(b"a", b"1").unwrap();
+        store.simulate_crash();
+
+        let wal_path = Path::new(&test_dir).join(WRITE_AHEAD_LOG_FILENAME);
+        let mut wal_file = File::options().append(true).open(&wal_path).unwrap();
+        wal_file.write_all(&[100, 0, 0, 0, 1, 2, 3]).unwrap();
+
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        store.put(b"b", b"2").unwrap();
+        store.simulate_crash();
+
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), Some(b"2".to_vec()));
+    }
+
+    #[test]
+    fn column_families_compact_by_their_own_policy() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "column-families/compaction-policy";
+        let store = Store::new(Path::new(&test_dir), false);
+        let options = ColumnFamilyOptions {
+            compaction_policy: CompactionPolicy::FileCount(2),
+        };
+        store.create_column_family("events", options).unwrap();
+        for i in 0..3_u32 {
+            store.put(&i.to_ne_bytes(), "default".as_bytes()).unwrap();
+            store
+                .put_cf("events", &i.to_ne_bytes(), "event".as_bytes())
+                .unwrap();
+            store.flush_pending_writes().unwrap();
+        }
+
+        assert_eq!(store.default_family().store_file_count(), 3);
+        assert_eq!(store.column_family("events").unwrap().store_file_count(), 1);
+        assert_eq!(
+            store.get_cf("events", &0_u32.to_ne_bytes()).unwrap(),
+            Some("event".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn reads_and_writes_from_many_threads() {
+        fn assert_send_and_sync<T: Send + Sync>() {}
+        assert_send_and_sync::<Store>();
+
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "concurrency/many-threads";
+        let store = Arc::new(open_fresh(
+            &test_dir,
+            StoreOptions::new().mem_table_size_limit_in_bytes(256),
+        ));
+        let always_there = "Always there".as_bytes();
+        store.put(always_there, "1".as_bytes()).unwrap();
+
+        let writers: Vec<_> = (0..4_u32)
+            .map(|writer| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for i in 0..200_u32 {
+                        let key = (writer * 1000 + i).to_ne_bytes();
+                        store.put(&key, &i.to_ne_bytes()).unwrap();
+                    }
+                })
+            })
+            .collect();
+        let compactor = {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for _ in 0..20 {
+                    store.compact().unwrap();
+                }
+            })
+        };
+        // Flushes and compactions swapping files around must never make a key disappear
+        for _ in 0..2000 {
+            assert_eq!(
+                store.get(always_there).unwrap(),
+                Some("1".as_bytes().to_vec())
+            );
+        }
+
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        compactor.join().unwrap();
+        for writer in 0..4_u32 {
+            for i in 0..200_u32 {
+                let key = (writer * 1000 + i).to_ne_bytes();
+                assert_eq!(store.get(&key).unwrap(), Some(i.to_ne_bytes().to_vec()));
+            }
+        }
+    }
+
+    #[test]
+    fn options_are_checked_at_open_and_persisted() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "options/open";
+        let _ = fs::remove_dir_all(&test_dir);
+        let test_dir = Path::new(&test_dir);
+
+        let no_create = StoreOptions::new().create_if_missing(false);
+        let err = Store::open(test_dir, &no_create).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        let bad_block_size = StoreOptions::new().block_size(0);
+        let err = Store::open(test_dir, &bad_block_size).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert!(!test_dir.exists());
+
+        let options = StoreOptions::new()
+            .sync_mode(SyncMode::EveryWrite)
+            .compaction_policy(CompactionPolicy::FileCount(2))
+            .error_if_exists(true);
+        let store = Store::open(test_dir, &options).unwrap();
+        store.put(&1_u32.to_ne_bytes(), "10".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store.put(&2_u32.to_ne_bytes(), "20".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        assert_eq!(store.default_family().store_file_count(), 1);
+        drop(store);
+
+        // How the store was opened isn't part of its options
+        let saved = fs::read_to_string(test_dir.join("OPTIONS")).unwrap();
+        assert!(!saved.contains("error_if_exists"));
+        assert_eq!(
+            StoreOptions::load(test_dir).unwrap(),
+            options.clone().error_if_exists(false)
+        );
+        let err = Store::open(test_dir, &options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let store = Store::open(test_dir, &no_create).unwrap();
+        assert_eq!(
+            store.get(&2_u32.to_ne_bytes()).unwrap(),
+            Some("20".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn a_store_can_only_be_opened_once_at_a_time() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "lock/open-twice";
+        let store = Store::new(Path::new(&test_dir), false);
+        store.put(&1_u32.to_ne_bytes(), "10".as_bytes()).unwrap();
+
+        let err = Store::open(Path::new(&test_dir), &StoreOptions::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        drop(store);
+        let store = Store::open(Path::new(&test_dir), &StoreOptions::new()).unwrap();
+        assert_eq!(
+            store.get(&1_u32.to_ne_bytes()).unwrap(),
+            Some("10".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn read_only_stores_read_a_live_store_without_changing_it() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "read-only/live";
+        let _ = fs::remove_dir_all(&test_dir);
+        let read_only_options = StoreOptions::new().read_only(true);
+        let err = Store::open(Path::new(&test_dir), &read_only_options)
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let store = Store::new(Path::new(&test_dir), false);
+        let flushed_key = 1_u32.to_ne_bytes();
+        let wal_key = 2_u32.to_ne_bytes();
+        store.put(&flushed_key, "old".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store.put(&flushed_key, "flushed".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store.put(&wal_key, "in the WAL".as_bytes()).unwrap();
+        let wal_path = Path::new(&test_dir).join(WRITE_AHEAD_LOG_FILENAME);
+        let wal_size = fs::metadata(&wal_path).unwrap().len();
+
+        let read_only = Store::open(Path::new(&test_dir), &read_only_options).unwrap();
+        assert!(read_only.is_read_only());
+        assert_eq!(
+            read_only.get(&flushed_key).unwrap(),
+            Some("flushed".as_bytes().to_vec())
+        );
+        assert_eq!(
+            read_only.get(&wal_key).unwrap(),
+            Some("in the WAL".as_bytes().to_vec())
+        );
+        let err = read_only.put(&wal_key, "10".as_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(read_only.remove(&wal_key).is_err());
+        assert!(read_only.compact().is_err());
+        assert!(read_only.flush_pending_writes().is_err());
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), wal_size);
+
+        // Compaction deleting the files the read only store has loaded doesn't break its reads
+        store.compact().unwrap();
+        assert_eq!(
+            read_only.get(&flushed_key).unwrap(),
+            Some("flushed".as_bytes().to_vec())
+        );
+        store.put(&wal_key, "newer".as_bytes()).unwrap();
+        assert_eq!(
+            read_only.get(&wal_key).unwrap(),
+            Some("in the WAL".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn closing_flushes_and_releases_the_lock() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "close/flushes";
+        let store = Store::new(Path::new(&test_dir), false);
+        store.put(&1_u32.to_ne_bytes(), "10".as_bytes()).unwrap();
+        store.close().unwrap();
+
+        let wal_path = Path::new(&test_dir).join(WRITE_AHEAD_LOG_FILENAME);
+        assert_eq!(fs::metadata(wal_path).unwrap().len(), 0);
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(store.default_family().store_file_count(), 1);
+        assert_eq!(
+            store.get(&1_u32.to_ne_bytes()).unwrap(),
+            Some("10".as_bytes().to_vec())
+        );
+        store.put(&2_u32.to_ne_bytes(), "20".as_bytes()).unwrap();
+        drop(store);
+
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(store.default_family().store_file_count(), 2);
+        assert_eq!(
+            store.get(&2_u32.to_ne_bytes()).unwrap(),
+            Some("20".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn compaction_recompresses_into_the_current_codec() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "compression/recompress";
+        let store = open_fresh(&test_dir, StoreOptions::new().compression(Compression::Lz4));
+        let value = "Compresses well ".repeat(100);
+        for i in 0..2_u32 {
+            store.put(&i.to_ne_bytes(), value.as_bytes()).unwrap();
+            store.flush_pending_writes().unwrap();
+        }
+        let store_files = store.default_family().store_files.read().unwrap().clone();
+        assert!(store_files
+            .iter()
+            .all(|file| file.properties.compression == Compression::Lz4));
+        drop(store_files);
+        drop(store);
+
+        let options = StoreOptions::new()
+            .compression(Compression::Lz4)
+            .compaction_compression(Compression::Zstd);
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        store.compact().unwrap();
+        let store_files = store.default_family().store_files.read().unwrap().clone();
+        assert_eq!(store_files.len(), 1);
+        assert_eq!(store_files[0].properties.compression, Compression::Zstd);
+        for i in 0..2_u32 {
+            assert_eq!(
+                store.get(&i.to_ne_bytes()).unwrap(),
+                Some(value.as_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn repeated_reads_are_served_from_the_block_cache() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "block-cache/repeated-reads";
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"hot", b"value").unwrap();
+        store.flush_pending_writes().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(store.get(b"hot").unwrap(), Some(b"value".to_vec()));
+        }
+        let stats = store.block_cache_stats();
+        assert_eq!(stats.misses, 1);
+        assert_eq!(stats.hits, 2);
+        assert!(stats.usage_in_bytes > 0);
+        drop(store);
+
+        let options = StoreOptions::new().block_cache_size_in_bytes(0);
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        for _ in 0..3 {
+            assert_eq!(store.get(b"hot").unwrap(), Some(b"value".to_vec()));
+        }
+        let stats = store.block_cache_stats();
+        assert_eq!(stats.misses, 3);
+        assert_eq!(stats.usage_in_bytes, 0);
+    }
+
+    #[test]
+    fn open_store_files_are_bounded_and_closed_once_compacted_away() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "file-cache/bounded";
+        let options = StoreOptions::new()
+            .max_open_files(2)
+            .block_cache_size_in_bytes(0);
+        let store = open_fresh(&test_dir, options);
+        for i in 0..3_u32 {
+            store.put(&i.to_ne_bytes(), b"value").unwrap();
+            store.flush_pending_writes().unwrap();
+        }
+
+        for i in 0..3_u32 {
+            assert_eq!(
+                store.get(&i.to_ne_bytes()).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        assert_eq!(store.caches.files.open_file_count(), 2);
+
+        store.compact().unwrap();
+        assert_eq!(store.caches.files.open_file_count(), 0);
+        for i in 0..3_u32 {
+            assert_eq!(
+                store.get(&i.to_ne_bytes()).unwrap(),
+                Some(b"value".to_vec())
+            );
+        }
+        assert_eq!(store.caches.files.open_file_count(), 1);
+    }
+
+    #[test]
+    fn value_log_reads_keep_their_segments_open() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "file-cache/value-log";
+        let options = StoreOptions::new()
+            .value_log_threshold_in_bytes(8)
+            .block_cache_size_in_bytes(0);
+        let store = open_fresh(&test_dir, options);
+        store.put(b"big", &[1; 100]).unwrap();
+        store.flush_pending_writes().unwrap();
+
+        for _ in 0..3 {
+            assert_eq!(store.get(b"big").unwrap(), Some(vec![1; 100]));
+        }
+        // The store file and its value log segment
+        assert_eq!(store.caches.files.open_file_count(), 2);
+    }
+
+    #[test]
+    fn mmap_reads_survive_their_files_being_compacted_away() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "mmap/compacted-away";
+        // Big values compress, so both compressed and uncompressed blocks get read
+        let big_value = "Compresses well ".repeat(100).into_bytes();
+        let options = StoreOptions::new()
+            .mmap_reads(true)
+            .compression(Compression::Lz4);
+        let store = open_fresh(&test_dir, options.clone());
+        for i in 0..2_u32 {
+            store.put(&i.to_ne_bytes(), b"small").unwrap();
+            store.put(&(i + 100).to_ne_bytes(), &big_value).unwrap();
+            store.flush_pending_writes().unwrap();
+        }
+
+        let read_only = Store::open(Path::new(&test_dir), &options.read_only(true)).unwrap();
+        store.compact().unwrap();
+        assert_eq!(
+            Store::get_store_files(Path::new(&test_dir)).unwrap().len(),
+            1
+        );
+        for reader in [&store, &read_only] {
+            for i in 0..2_u32 {
+                assert_eq!(
+                    reader.get(&i.to_ne_bytes()).unwrap(),
+                    Some(b"small".to_vec())
+                );
+                assert_eq!(
+                    reader.get(&(i + 100).to_ne_bytes()).unwrap(),
+                    Some(big_value.clone())
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn large_values_live_in_the_value_log_until_compaction_collects_them() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "value-log/gc";
+        let options = StoreOptions::new().value_log_threshold_in_bytes(1000);
+        let store = open_fresh(&test_dir, options.clone());
+        let big_value = |i: u32, version: u8| vec![version + i as u8; 2000];
+        for i in 0..5_u32 {
+            store.put(&i.to_ne_bytes(), &big_value(i, 0)).unwrap();
+        }
+        store.put(b"small", b"inline").unwrap();
+        store.flush_pending_writes().unwrap();
+        // Leaves only key 4 live in the first segment
+        for i in 0..4_u32 {
+            store.put(&i.to_ne_bytes(), &big_value(i, 100)).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+
+        let segment_ids = || {
+            let mut ids = ValueLog::segment_ids_in_dir(Path::new(&test_dir)).unwrap();
+            ids.sort();
+            ids
+        };
+        assert_eq!(segment_ids(), vec![1, 2]);
+        let store_file_size = |store: &Store| {
+            let store_files = store.default_family().store_files.read().unwrap().clone();
+            store_files
+                .iter()
+                .map(|file| fs::metadata(&file.path).unwrap().len())
+                .sum::<u64>()
+        };
+        assert!(store_file_size(&store) < 2000);
+
+        store.compact().unwrap();
+        // The live value in the first segment was moved to a new one
+        assert_eq!(segment_ids(), vec![2, 3]);
+        assert!(store_file_size(&store) < 2000);
+        for i in 0..4_u32 {
+            assert_eq!(
+                store.get(&i.to_ne_bytes()).unwrap(),
+                Some(big_value(i, 100))
+            );
+        }
+        assert_eq!(
+            store.get(&4_u32.to_ne_bytes()).unwrap(),
+            Some(big_value(4, 0))
+        );
+        assert_eq!(store.get(b"small").unwrap(), Some(b"inline".to_vec()));
+        drop(store);
+
+        // Left behind by a flush that crashed before its store file was in place
+        fs::write(Path::new(&test_dir).join("10.vlog"), b"unused").unwrap();
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        assert_eq!(segment_ids(), vec![2, 3]);
+        assert_eq!(
+            store.get(&4_u32.to_ne_bytes()).unwrap(),
+            Some(big_value(4, 0))
+        );
+    }
+
+    #[test]
+    fn oversized_keys_and_values_are_refused() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "sizes/limits";
+        let options = StoreOptions::new()
+            .max_key_size_in_bytes(8)
+            .max_value_size_in_bytes(16);
+        let store = open_fresh(&test_dir, options);
+        let err = store.put(&[1; 9], b"value").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = store.put(b"key", &[1; 17]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        // Nothing in a batch is written if any of it is too big
+        let mut batch = WriteBatch::new();
+        batch.put(DEFAULT_COLUMN_FAMILY, b"fits", b"value");
+        batch.put(DEFAULT_COLUMN_FAMILY, b"too big", &[1; 17]);
+        assert!(store.write(batch).is_err());
+
+        store.put(&[1; 8], &[2; 16]).unwrap();
+        store.simulate_crash();
+        let store = Store::new(Path::new(&test_dir), true);
+        assert_eq!(store.get(&[1; 8]).unwrap(), Some(vec![2; 16]));
+        assert_eq!(store.get(b"fits").unwrap(), None);
+        assert_eq!(store.get(b"key").unwrap(), None);
+    }
+
+    struct ReverseComparator;
+
+    impl Comparator for ReverseComparator {
+        fn name(&self) -> &str {
+            "reverse"
+        }
+
+        fn compare(&self, a: &[u8], b: &[u8]) -> std::cmp::Ordering {
+            b.cmp(a)
+        }
     }
+
+    #[test]
+    fn column_families_keep_keys_in_their_comparators_order() {
+        for (i, mem_table_kind) in [MemTableKind::SkipList, MemTableKind::BTreeMap]
+            .into_iter()
+            .enumerate()
+        {
+            let test_dir = format!("{}comparators/reverse-{}", TEMP_TEST_FILE_DIR, i);
+            let options = StoreOptions::new()
+                .mem_table_kind(mem_table_kind)
+                .block_size(64)
+                .column_family_comparator("reversed", Arc::new(ReverseComparator));
+            let store = open_fresh(&test_dir, options.clone());
+            store
+                .create_column_family("reversed", ColumnFamilyOptions::default())
+                .unwrap();
+            for i in 0..200_u32 {
+                store
+                    .put_cf("reversed", &i.to_be_bytes(), &i.to_le_bytes())
+                    .unwrap();
+                if i % 50 == 49 {
+                    store.flush_pending_writes().unwrap();
+                }
+            }
+            store.compact_cf("reversed").unwrap();
+
+            let family = store.column_family("reversed").unwrap();
+            let store_files = family.store_files.read().unwrap().clone();
+            assert_eq!(store_files.len(), 1);
+            assert_eq!(
+                store_files[0].properties.smallest_key,
+                199_u32.to_be_bytes()
+            );
+            assert_eq!(store_files[0].properties.largest_key, 0_u32.to_be_bytes());
+            for i in 0..200_u32 {
+                assert_eq!(
+                    store.get_cf("reversed", &i.to_be_bytes()).unwrap(),
+                    Some(i.to_le_bytes().to_vec())
+                );
+            }
+            assert_eq!(
+                store.get_cf("reversed", &200_u32.to_be_bytes()).unwrap(),
+                None
+            );
+            drop(store_files);
+            drop(family);
+            drop(store);
+
+            // The reversed column family's files can't be read in bytewise order
+            let err = Store::open(Path::new(&test_dir), &StoreOptions::new())
+                .err()
+                .unwrap();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            let store = Store::open(Path::new(&test_dir), &options).unwrap();
+            assert_eq!(
+                store.get_cf("reversed", &7_u32.to_be_bytes()).unwrap(),
+                Some(7_u32.to_le_bytes().to_vec())
+            );
+        }
+    }
+
+    #[test]
+    fn stores_remember_the_default_column_familys_comparator() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "comparators/default";
+        let options = StoreOptions::new().comparator(Arc::new(ReverseComparator));
+        let store = open_fresh(&test_dir, options.clone());
+        store.put("a".as_bytes(), "1".as_bytes()).unwrap();
+        store.put("b".as_bytes(), "2".as_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        assert_eq!(
+            store.default_family().store_files.read().unwrap()[0]
+                .properties
+                .smallest_key,
+            "b".as_bytes()
+        );
+        drop(store);
+
+        let err = Store::open(Path::new(&test_dir), &StoreOptions::new())
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = Store::open(Path::new(&test_dir), &StoreOptions::new().read_only(true))
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        assert_eq!(
+            store.get("a".as_bytes()).unwrap(),
+            Some("1".as_bytes().to_vec())
+        );
+    }
+
+    #[test]
+    fn delete_range_hides_older_keys_until_compaction_drops_them() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "delete-range";
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        let value = |i: u32| i.to_string().into_bytes();
+        // 0..50 in a store file, 50..100 in the mem table
+        for i in 0..100_u32 {
+            store.put(&i.to_be_bytes(), &value(i)).unwrap();
+            if i == 49 {
+                store.flush_pending_writes().unwrap();
+            }
+        }
+        store
+            .delete_range(&20_u32.to_be_bytes(), &80_u32.to_be_bytes())
+            .unwrap();
+        // Written after the range was deleted, so it stays
+        store.put(&30_u32.to_be_bytes(), &value(30)).unwrap();
+        assert!(store
+            .delete_range(&5_u32.to_be_bytes(), &4_u32.to_be_bytes())
+            .is_err());
+
+        let check = |store: &Store| {
+            for i in 0..100_u32 {
+                let expected = if (20..80).contains(&i) && i != 30 {
+                    None
+                } else {
+                    Some(value(i))
+                };
+                assert_eq!(store.get(&i.to_be_bytes()).unwrap(), expected, "key {}", i);
+            }
+        };
+        check(&store);
+
+        // Replayed from the WAL
+        store.simulate_crash();
+        let store = Store::open(Path::new(&test_dir), &StoreOptions::new()).unwrap();
+        check(&store);
+
+        // A mem table holding only a range tombstone still gets flushed
+        store.flush_pending_writes().unwrap();
+        store
+            .delete_range(&0_u32.to_be_bytes(), &10_u32.to_be_bytes())
+            .unwrap();
+        store.flush_pending_writes().unwrap();
+        let store_files = store.default_family().store_files.read().unwrap().clone();
+        assert_eq!(store_files.len(), 3);
+        assert_eq!(store_files[0].properties.entry_count, 0);
+        assert_eq!(store_files[0].properties.range_tombstones.len(), 1);
+        drop(store_files);
+        assert_eq!(store.get(&5_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(store.get(&10_u32.to_be_bytes()).unwrap(), Some(value(10)));
+
+        store.compact().unwrap();
+        let store_files = store.default_family().store_files.read().unwrap().clone();
+        assert_eq!(store_files.len(), 1);
+        assert!(store_files[0].properties.range_tombstones.is_empty());
+        // 10..20, 30 and 80..100
+        assert_eq!(store_files[0].properties.entry_count, 31);
+        assert_eq!(store.get(&30_u32.to_be_bytes()).unwrap(), Some(value(30)));
+        assert_eq!(store.get(&50_u32.to_be_bytes()).unwrap(), None);
+    }
+
+    #[test]
+    fn store_file_stats_and_estimates_come_from_the_indexes() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "estimates";
+        let store = open_fresh(&test_dir, StoreOptions::new().block_size(256));
+        assert_eq!(store.approximate_num_keys(), 0);
+        assert_eq!(
+            store.approximate_size(&0_u32.to_be_bytes(), &u32::MAX.to_be_bytes()),
+            0
+        );
+
+        for i in 0..1000_u32 {
+            store.put(&i.to_be_bytes(), &[7; 100]).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        for i in 0..100_u32 {
+            store.remove(&i.to_be_bytes()).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+
+        let stats = store.store_file_stats();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].entry_count, 100);
+        assert_eq!(stats[0].tombstone_count, 100);
+        assert_eq!(stats[1].entry_count, 1000);
+        assert_eq!(stats[1].smallest_key, 0_u32.to_be_bytes());
+        assert_eq!(stats[1].largest_key, 999_u32.to_be_bytes());
+        let store_files = Store::get_store_files(Path::new(&test_dir)).unwrap();
+        let total_file_size: u64 = store_files
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+        assert_eq!(
+            stats.iter().map(|stats| stats.size_in_bytes).sum::<u64>(),
+            total_file_size
+        );
+        assert_eq!(store.approximate_num_keys(), 900);
+
+        let everything = store.approximate_size(&0_u32.to_be_bytes(), &1000_u32.to_be_bytes());
+        assert!(everything > 100 * 1000 && everything < total_file_size);
+        let second_half = store.approximate_size(&500_u32.to_be_bytes(), &1000_u32.to_be_bytes());
+        assert!(second_half > everything / 3 && second_half < everything * 2 / 3);
+        assert_eq!(
+            store.approximate_size(&2000_u32.to_be_bytes(), &3000_u32.to_be_bytes()),
+            0
+        );
+
+        store.compact().unwrap();
+        assert_eq!(store.approximate_num_keys(), 900);
+        assert!(store.approximate_num_keys_cf("missing").is_err());
+    }
+
+    #[test]
+    fn stats_count_what_the_store_has_done() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "stats";
+        let store = open_fresh(
+            &test_dir,
+            StoreOptions::new().sync_mode(SyncMode::EveryWrite),
+        );
+        assert_eq!(store.stats().puts, 0);
+
+        for i in 0..10_u32 {
+            store.put(&i.to_be_bytes(), &[1; 10]).unwrap();
+        }
+        store.remove(&0_u32.to_be_bytes()).unwrap();
+        store
+            .delete_range(&1_u32.to_be_bytes(), &3_u32.to_be_bytes())
+            .unwrap();
+        assert_eq!(store.get(&5_u32.to_be_bytes()).unwrap(), Some(vec![1; 10]));
+        assert_eq!(store.get(&1_u32.to_be_bytes()).unwrap(), None);
+
+        let stats = store.stats();
+        assert_eq!(stats.puts, 10);
+        assert_eq!(stats.removes, 1);
+        assert_eq!(stats.range_deletes, 1);
+        assert_eq!(stats.gets, 2);
+        assert_eq!(stats.get_micros.count, 2);
+        assert_eq!(stats.wal_syncs, 12);
+        assert!(stats.wal_bytes_written > 10 * 14);
+        assert!(stats.mem_table_bytes > 0);
+        assert_eq!(stats.flush_micros.count, 0);
+
+        store.flush_pending_writes().unwrap();
+        store.put(&20_u32.to_be_bytes(), &[1; 10]).unwrap();
+        store.flush_pending_writes().unwrap();
+        let stats = store.stats();
+        // Every write, and the WAL being replaced after each flush
+        assert_eq!(stats.wal_syncs, 15);
+        assert_eq!(stats.flush_micros.count, 2);
+        assert_eq!(stats.store_file_counts[DEFAULT_COLUMN_FAMILY], 2);
+        assert_eq!(stats.mem_table_bytes, 0);
+        let store_files = Store::get_store_files(Path::new(&test_dir)).unwrap();
+        let total_file_size: u64 = store_files
+            .iter()
+            .map(|path| fs::metadata(path).unwrap().len())
+            .sum();
+        assert_eq!(stats.flush_bytes_written, total_file_size);
+
+        store.compact().unwrap();
+        let stats = store.stats();
+        assert_eq!(stats.compaction_micros.count, 1);
+        assert_eq!(stats.compaction_bytes_read, total_file_size);
+        assert!(stats.compaction_bytes_written > 0);
+        assert_eq!(stats.store_file_counts[DEFAULT_COLUMN_FAMILY], 1);
+        let map = stats.to_map();
+        assert_eq!(map["puts"], 11);
+        assert_eq!(map["compaction_micros.count"], 1);
+        assert_eq!(map["store_files.default"], 1);
+    }
+
+    #[derive(Default)]
+    struct RecordingListener {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl RecordingListener {
+        fn record(&self, event: String) {
+            self.events.lock().unwrap().push(event);
+        }
+
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut self.events.lock().unwrap())
+        }
+    }
+
+    impl EventListener for RecordingListener {
+        fn on_flush_begin(&self, info: &FlushBeginInfo) {
+            self.record(format!("flush begin {}", info.mem_table_bytes));
+        }
+
+        fn on_flush_completed(&self, info: &FlushInfo) {
+            for file in &info.store_files {
+                self.record(format!("flushed {} {}", file.column_family, file.file_id));
+            }
+        }
+
+        fn on_compaction_begin(&self, info: &CompactionBeginInfo) {
+            self.record(format!(
+                "compaction begin {} {:?}",
+                info.column_family, info.input_file_ids
+            ));
+        }
+
+        fn on_compaction_completed(&self, info: &CompactionInfo) {
+            assert!(info.bytes_read > 0 && info.bytes_written > 0);
+            self.record(format!(
+                "compacted {} {:?} into {}",
+                info.column_family, info.input_file_ids, info.output_file_id
+            ));
+        }
+
+        fn on_wal_rotated(&self, info: &WalRotationInfo) {
+            assert!(info.previous_size_in_bytes > 0);
+            self.record("wal rotated".to_string());
+        }
+
+        fn on_background_error(&self, reason: &BackgroundErrorReason, _error: &io::Error) {
+            self.record(format!("background error {:?}", reason));
+        }
+
+        fn on_write_stall(&self, info: &WriteStallInfo) {
+            self.record(format!("write stall {}", info.mem_table_bytes));
+        }
+    }
+
+    #[test]
+    fn event_listeners_hear_about_flushes_compactions_and_failures() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "event_listeners";
+        // Every put crosses the mem table limit, and every second flush compacts
+        let store = open_fresh(
+            &test_dir,
+            StoreOptions::new()
+                .mem_table_size_limit_in_bytes(100)
+                .compaction_policy(CompactionPolicy::FileCount(2)),
+        );
+        let listener = Arc::new(RecordingListener::default());
+        store.add_event_listener(listener.clone());
+
+        store.put(&0_u32.to_be_bytes(), &[1; 100]).unwrap();
+        assert_eq!(
+            listener.take(),
+            [
+                "flush begin 112",
+                "flushed default 2",
+                "wal rotated",
+                "write stall 112"
+            ]
+        );
+
+        store.put(&1_u32.to_be_bytes(), &[1; 100]).unwrap();
+        assert_eq!(
+            listener.take(),
+            [
+                "flush begin 112",
+                "flushed default 3",
+                "wal rotated",
+                "compaction begin default [3, 2]",
+                "compacted default [3, 2] into 4",
+                "write stall 112"
+            ]
+        );
+
+        // Nothing to flush, so nothing to report
+        store.flush_pending_writes().unwrap();
+        assert!(listener.take().is_empty());
+
+        // The write still succeeds when the compaction it sets off fails
+        fs::create_dir(Path::new(&test_dir).join("compaction.temp")).unwrap();
+        store.put(&2_u32.to_be_bytes(), &[1; 100]).unwrap();
+        assert_eq!(
+            listener.take(),
+            [
+                "flush begin 112",
+                "flushed default 5",
+                "wal rotated",
+                "compaction begin default [5, 4]",
+                "background error Compaction { column_family: \"default\" }",
+                "write stall 112"
+            ]
+        );
+        assert_eq!(store.store_file_stats().len(), 2);
+        for i in 0..3_u32 {
+            assert_eq!(store.get(&i.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+        }
+
+        // Asking for a compaction gets the error back instead
+        assert!(store.compact().is_err());
+        fs::remove_dir(Path::new(&test_dir).join("compaction.temp")).unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.store_file_stats().len(), 1);
+        for i in 0..3_u32 {
+            assert_eq!(store.get(&i.to_be_bytes()).unwrap(), Some(vec![1; 100]));
+        }
+    }
+
+    #[test]
+    fn checkpoints_are_openable_copies_taken_while_writes_carry_on() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "checkpoint_source";
+        let checkpoint_dir = PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "checkpoint");
+        let _ = fs::remove_dir_all(&checkpoint_dir);
+        let options = StoreOptions::new().value_log_threshold_in_bytes(64);
+        let store = Arc::new(open_fresh(&test_dir, options.clone()));
+        store
+            .create_column_family("other", ColumnFamilyOptions::default())
+            .unwrap();
+        for i in 0..100_u32 {
+            store.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+            store.put_cf("other", &i.to_be_bytes(), &[2; 10]).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        // Only in the WAL
+        store.put(b"unflushed", b"value").unwrap();
+
+        let writer = {
+            let store = store.clone();
+            std::thread::spawn(move || {
+                for i in 1000..3000_u32 {
+                    store.put(&i.to_be_bytes(), &[3; 10]).unwrap();
+                }
+            })
+        };
+        store.checkpoint(&checkpoint_dir).unwrap();
+        writer.join().unwrap();
+        let err = store.checkpoint(&checkpoint_dir).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+
+        // Changing the original afterwards, and compacting away the files the checkpoint linked,
+        // leaves the checkpoint as it was
+        store.remove(&0_u32.to_be_bytes()).unwrap();
+        store.flush_pending_writes().unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get(&0_u32.to_be_bytes()).unwrap(), None);
+        drop(store);
+
+        let checkpoint = Store::open(&checkpoint_dir, &options).unwrap();
+        assert_eq!(checkpoint.column_family_names(), ["default", "other"]);
+        for i in 0..100_u32 {
+            assert_eq!(
+                checkpoint.get(&i.to_be_bytes()).unwrap(),
+                Some(vec![1; 100])
+            );
+            assert_eq!(
+                checkpoint.get_cf("other", &i.to_be_bytes()).unwrap(),
+                Some(vec![2; 10])
+            );
+        }
+        assert_eq!(
+            checkpoint.get(b"unflushed").unwrap(),
+            Some(b"value".to_vec())
+        );
+        // Writes made during the checkpoint are either all there up to some point, or not at all
+        let taken = (1000..3000_u32)
+            .take_while(|i| checkpoint.get(&i.to_be_bytes()).unwrap().is_some())
+            .count() as u32;
+        assert!((1000 + taken..3000).all(|i| checkpoint.get(&i.to_be_bytes()).unwrap().is_none()));
+    }
+
+    #[test]
+    fn failed_checkpoints_leave_nothing_behind() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "failed_checkpoint_source";
+        let checkpoint_dir = PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "failed_checkpoint");
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+        store.close().unwrap();
+
+        let store =
+            Store::open(Path::new(&test_dir), &StoreOptions::new().read_only(true)).unwrap();
+        let err = store.checkpoint(&checkpoint_dir).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(!checkpoint_dir.exists());
+        assert!(!checkpoint_dir
+            .with_file_name("failed_checkpoint.temp")
+            .exists());
+    }
+
+    #[test]
+    fn backups_share_files_and_restore_into_openable_stores() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "backup_source";
+        let backup_dir = PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "backups");
+        let restore_dir = PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "restored");
+        for dir in [&backup_dir, &restore_dir] {
+            let _ = fs::remove_dir_all(dir);
+        }
+        let shared_files = || -> std::collections::HashSet<_> {
+            fs::read_dir(backup_dir.join("shared"))
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect()
+        };
+
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        let backups = BackupEngine::open(&backup_dir).unwrap();
+        assert_eq!(
+            BackupEngine::open(&backup_dir).err().unwrap().kind(),
+            io::ErrorKind::WouldBlock
+        );
+        for i in 0..100_u32 {
+            store.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        store.put(b"unflushed", b"value").unwrap();
+        let first = backups.create_new_backup(&store).unwrap();
+        let first_shared_files = shared_files();
+
+        for i in 100..200_u32 {
+            store.put(&i.to_be_bytes(), &[2; 100]).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        let second = backups.create_new_backup(&store).unwrap();
+        assert_eq!(backups.backups().unwrap(), [first.clone(), second.clone()]);
+        // Only the new store file and the changed WAL are added
+        let second_only: Vec<_> = shared_files()
+            .difference(&first_shared_files)
+            .cloned()
+            .collect();
+        assert_eq!(second_only.len(), 2);
+        assert!(second.file_count > 2);
+        store.remove(&0_u32.to_be_bytes()).unwrap();
+        drop(store);
+
+        backups.verify_backup(first.id).unwrap();
+        backups.verify_backup(second.id).unwrap();
+        backups.restore(first.id, &restore_dir).unwrap();
+        assert_eq!(
+            backups
+                .restore(first.id, &restore_dir)
+                .err()
+                .unwrap()
+                .kind(),
+            io::ErrorKind::AlreadyExists
+        );
+        let restored = Store::new(&restore_dir, true);
+        assert_eq!(
+            restored.get(&0_u32.to_be_bytes()).unwrap(),
+            Some(vec![1; 100])
+        );
+        assert_eq!(restored.get(b"unflushed").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(restored.get(&150_u32.to_be_bytes()).unwrap(), None);
+        drop(restored);
+        fs::remove_dir_all(&restore_dir).unwrap();
+
+        // Purging only deletes the files the second backup doesn't use
+        backups.purge_old_backups(1).unwrap();
+        assert_eq!(backups.backups().unwrap(), std::slice::from_ref(&second));
+        assert_eq!(
+            backups.verify_backup(first.id).err().unwrap().kind(),
+            io::ErrorKind::NotFound
+        );
+        backups.verify_backup(second.id).unwrap();
+        assert_eq!(shared_files().len(), second.file_count);
+        backups.restore(second.id, &restore_dir).unwrap();
+        let restored = Store::new(&restore_dir, true);
+        assert_eq!(
+            restored.get(&0_u32.to_be_bytes()).unwrap(),
+            Some(vec![1; 100])
+        );
+        assert_eq!(
+            restored.get(&150_u32.to_be_bytes()).unwrap(),
+            Some(vec![2; 100])
+        );
+        drop(restored);
+        fs::remove_dir_all(&restore_dir).unwrap();
+
+        // The source store is gone, so a file still linked to it can be damaged safely. The new
+        // WAL is empty, so it's the new store file that gets damaged
+        let store_file = second_only
+            .iter()
+            .max_by_key(|path| fs::metadata(path).unwrap().len())
+            .unwrap();
+        let mut damaged = fs::read(store_file).unwrap();
+        damaged[0] ^= 1;
+        fs::write(store_file, damaged).unwrap();
+        let err = backups.verify_backup(second.id).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = backups.restore(second.id, &restore_dir).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!restore_dir.exists());
+        assert!(!restore_dir.with_file_name("restored.temp").exists());
+    }
+
+    #[test]
+    fn backups_only_share_files_with_the_same_contents() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "backup_collision_source";
+        let backup_dir = PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "backup_collisions");
+        let restore_dir =
+            PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "backup_collision_restored");
+        for dir in [&backup_dir, &restore_dir] {
+            let _ = fs::remove_dir_all(dir);
+        }
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+        store.flush_pending_writes().unwrap();
+
+        // A different file already in the backup under the name the store file would get
+        let store_file = fs::read(Path::new(&test_dir).join("2.store.kv")).unwrap();
+        let shared_name = format!(
+            "2.store.kv_{:08x}_{}",
+            crc32fast::hash(&store_file),
+            store_file.len()
+        );
+        let backups = BackupEngine::open(&backup_dir).unwrap();
+        let impostor = backup_dir.join("shared").join(&shared_name);
+        fs::write(&impostor, vec![0; store_file.len()]).unwrap();
+
+        let backup = backups.create_new_backup(&store).unwrap();
+        drop(store);
+        assert!(backup_dir.join("shared").join(shared_name + "_1").exists());
+        assert_eq!(fs::read(&impostor).unwrap(), vec![0; store_file.len()]);
+        backups.restore(backup.id, &restore_dir).unwrap();
+        let restored = Store::open(&restore_dir, &StoreOptions::new()).unwrap();
+        assert_eq!(restored.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn exports_import_into_a_store_with_a_different_layout() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "export_source";
+        let import_dir = TEMP_TEST_FILE_DIR.to_string() + "export_destination";
+        let store = open_fresh(
+            &test_dir,
+            StoreOptions::new().value_log_threshold_in_bytes(64),
+        );
+        store
+            .create_column_family("other", ColumnFamilyOptions::default())
+            .unwrap();
+        for i in 0..1500_u32 {
+            store.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        for i in 0..10_u32 {
+            store.put(&i.to_be_bytes(), &[2; 10]).unwrap();
+            store.put_cf("other", &i.to_be_bytes(), &[3; 10]).unwrap();
+        }
+        store.remove(&10_u32.to_be_bytes()).unwrap();
+        store
+            .delete_range(&20_u32.to_be_bytes(), &30_u32.to_be_bytes())
+            .unwrap();
+        store
+            .put_with_ttl(b"expiring", b"value", Duration::from_secs(60 * 60))
+            .unwrap();
+        store
+            .put_with_ttl(b"expired", b"value", Duration::ZERO)
+            .unwrap();
+
+        let mut export = Vec::new();
+        // Tombstones and expired keys are left out: one removed key and ten deleted by range from
+        // the default column family, plus the expiring key and the other column family's keys
+        assert_eq!(store.export(&mut export).unwrap(), 1500 - 1 - 10 + 1 + 10);
+
+        let imported = open_fresh(
+            &import_dir,
+            StoreOptions::new().mem_table_kind(MemTableKind::BTreeMap),
+        );
+        assert_eq!(imported.import(export.as_slice()).unwrap(), 1500);
+        assert_eq!(imported.column_family_names(), ["default", "other"]);
+        assert_eq!(
+            imported.get(&0_u32.to_be_bytes()).unwrap(),
+            Some(vec![2; 10])
+        );
+        assert_eq!(imported.get(&10_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(imported.get(&25_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(
+            imported.get(&1499_u32.to_be_bytes()).unwrap(),
+            Some(vec![1; 100])
+        );
+        assert_eq!(
+            imported.get_cf("other", &9_u32.to_be_bytes()).unwrap(),
+            Some(vec![3; 10])
+        );
+        assert_eq!(imported.get(b"expiring").unwrap(), Some(b"value".to_vec()));
+        assert_eq!(imported.get(b"expired").unwrap(), None);
+
+        // An export of the imported store is the same, byte for byte
+        let mut reexport = Vec::new();
+        imported.export(&mut reexport).unwrap();
+        assert_eq!(reexport, export);
+
+        let mut damaged = export.clone();
+        let middle = damaged.len() / 2;
+        damaged[middle] ^= 1;
+        let err = imported.import(damaged.as_slice()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let truncated = &export[..export.len() - 1];
+        let err = imported.import(truncated).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let err = imported.import(&b"not an export"[..]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn exports_merge_unflushed_writes_over_store_files() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "export_mem_tables";
+        let dir = Path::new(&test_dir);
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        for key in [b"a", b"b", b"c", b"d", b"e"] {
+            store.put(key, b"1").unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        store.delete_range(b"b", b"d").unwrap();
+        store.put(b"c", b"2").unwrap();
+        store.simulate_crash();
+
+        // Read only stores export their mem tables as they are
+        let read_only = Store::open(dir, &StoreOptions::new().read_only(true)).unwrap();
+        let mut export = Vec::new();
+        assert_eq!(read_only.export(&mut export).unwrap(), 4);
+        drop(read_only);
+
+        let store = Store::open(dir, &StoreOptions::new()).unwrap();
+        let mut flushed_export = Vec::new();
+        store.export(&mut flushed_export).unwrap();
+        assert_eq!(flushed_export, export);
+    }
+
+    #[test]
+    fn ingested_files_shadow_everything_written_before_them() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "ingest";
+        let files_dir = PathBuf::from(TEMP_TEST_FILE_DIR.to_string() + "ingest_files");
+        let _ = fs::remove_dir_all(&files_dir);
+        fs::create_dir_all(&files_dir).unwrap();
+        let options = StoreOptions::new().block_size(256);
+        let store = open_fresh(&test_dir, options.clone());
+        for i in 0..100_u32 {
+            store.put(&i.to_be_bytes(), &[1; 10]).unwrap();
+        }
+        store.flush_pending_writes().unwrap();
+        store.put(&5_u32.to_be_bytes(), &[9; 10]).unwrap();
+
+        let first = files_dir.join("first");
+        let mut writer = StoreFileWriter::new(&first, &options).unwrap();
+        for i in 0..50_u32 {
+            writer.put(&i.to_be_bytes(), &[2; 10]).unwrap();
+        }
+        writer.remove(&60_u32.to_be_bytes()).unwrap();
+        let err = writer.put(&0_u32.to_be_bytes(), &[2; 10]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        writer.finish().unwrap();
+        let second = files_dir.join("second");
+        let mut writer = StoreFileWriter::new(&second, &options).unwrap();
+        writer.put(&0_u32.to_be_bytes(), &[3; 10]).unwrap();
+        writer.finish().unwrap();
+
+        // Neither a file ordered by another comparator nor one that isn't a store file at all
+        // gets anything added
+        let reversed = files_dir.join("reversed");
+        let reverse_options = options
+            .clone()
+            .comparator(Arc::new(ReverseComparator) as Arc<dyn Comparator>);
+        let mut writer = StoreFileWriter::new(&reversed, &reverse_options).unwrap();
+        writer.put(b"b", b"value").unwrap();
+        writer.put(b"a", b"value").unwrap();
+        writer.finish().unwrap();
+        let garbage = files_dir.join("garbage");
+        fs::write(&garbage, [7; 100]).unwrap();
+        let err = store
+            .ingest_files(&[first.clone(), reversed])
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        let err = store.ingest_files(&[first.clone(), garbage]).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(store.get(&5_u32.to_be_bytes()).unwrap(), Some(vec![9; 10]));
+
+        store
+            .ingest_files(&[first.clone(), second.clone()])
+            .unwrap();
+        // The mem table was flushed first, so even the unflushed write is shadowed
+        assert_eq!(store.get(&5_u32.to_be_bytes()).unwrap(), Some(vec![2; 10]));
+        assert_eq!(store.get(&0_u32.to_be_bytes()).unwrap(), Some(vec![3; 10]));
+        assert_eq!(store.get(&60_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(store.get(&70_u32.to_be_bytes()).unwrap(), Some(vec![1; 10]));
+        store.put(&0_u32.to_be_bytes(), &[4; 10]).unwrap();
+        assert_eq!(store.get(&0_u32.to_be_bytes()).unwrap(), Some(vec![4; 10]));
+
+        // The ingested files are the store's own now
+        fs::remove_dir_all(&files_dir).unwrap();
+        store.close().unwrap();
+        let store = Store::open(Path::new(&test_dir), &options).unwrap();
+        store.compact().unwrap();
+        assert_eq!(store.get(&0_u32.to_be_bytes()).unwrap(), Some(vec![4; 10]));
+        assert_eq!(store.get(&5_u32.to_be_bytes()).unwrap(), Some(vec![2; 10]));
+        assert_eq!(store.get(&60_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(store.get(&70_u32.to_be_bytes()).unwrap(), Some(vec![1; 10]));
+    }
+
+    #[test]
+    fn repairs_salvage_what_verifying_finds_intact() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "repair";
+        let dir = Path::new(&test_dir);
+        let options = StoreOptions::new().block_size(256);
+        let store = open_fresh(&test_dir, options.clone());
+        store
+            .create_column_family("users", ColumnFamilyOptions::default())
+            .unwrap();
+        for i in 0..100_u32 {
+            store.put(&i.to_be_bytes(), &[1; 10]).unwrap();
+        }
+        store.put_cf("users", b"alice", b"admin").unwrap();
+        let err = verify_store(dir, &options).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        store.close().unwrap();
+        let report = verify_store(dir, &options).unwrap();
+        assert!(report.is_intact());
+        assert_eq!(report.store_files_checked, 2);
+        assert_eq!(report.entries_checked, 101);
+
+        // Damages the first block of the default column family's store file, tears the WAL and
+        // mangles the manifest
+        let store_file_path = Store::get_store_files(dir).unwrap().pop().unwrap();
+        let mut bytes = fs::read(&store_file_path).unwrap();
+        bytes[5] ^= 0xff;
+        fs::write(&store_file_path, bytes).unwrap();
+        let wal_path = dir.join(WRITE_AHEAD_LOG_FILENAME);
+        let mut wal_file = File::options().append(true).open(&wal_path).unwrap();
+        wal_file.write_all(&[100, 0, 0, 0, 1, 2, 3, 4, 5]).unwrap();
+        let manifest_path = dir.join(manifest::MANIFEST_FILENAME);
+        fs::write(&manifest_path, b"{").unwrap();
+
+        let report = verify_store(dir, &options).unwrap();
+        assert!(!report.is_intact());
+        assert!(report.entries_checked > 1 && report.entries_checked < 101);
+        let damaged_paths: Vec<_> = report
+            .damage
+            .iter()
+            .map(|damage| damage.path.clone())
+            .collect();
+        assert!(damaged_paths.contains(&store_file_path));
+        assert!(damaged_paths.contains(&wal_path));
+        assert!(damaged_paths.contains(&manifest_path));
+        // Verifying doesn't change anything
+        assert_eq!(verify_store(dir, &options).unwrap(), report);
+
+        let repair_report = repair_store(dir, &options).unwrap();
+        assert_eq!(repair_report.damage, report.damage);
+        // Everything but the users column family's entry came from the damaged file
+        assert_eq!(repair_report.entries_salvaged, report.entries_checked - 1);
+        assert_eq!(repair_report.quarantined.len(), 3);
+        assert!(repair_report
+            .quarantined
+            .iter()
+            .all(|path| path.starts_with(dir.join("quarantine")) && path.exists()));
+        assert!(verify_store(dir, &options).unwrap().is_intact());
+
+        let store = Store::open(dir, &options).unwrap();
+        assert_eq!(store.get(&0_u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(store.get(&99_u32.to_be_bytes()).unwrap(), Some(vec![1; 10]));
+        let readable = (0..100_u32)
+            .filter(|i| store.get(&i.to_be_bytes()).unwrap().is_some())
+            .count();
+        assert_eq!(readable as u64, repair_report.entries_salvaged);
+        // The rebuilt manifest picked the column family back up from its directory
+        assert_eq!(
+            store.get_cf("users", b"alice").unwrap(),
+            Some(b"admin".to_vec())
+        );
+        store.close().unwrap();
+        assert!(repair_store(dir, &options).unwrap().damage.is_empty());
+    }
+
+    #[test]
+    fn stores_in_the_original_format_are_upgraded_on_open() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "upgrades_original_format";
+        let dir = Path::new(&test_dir);
+        let _ = fs::remove_dir_all(dir);
+        fs::create_dir_all(dir).unwrap();
+        let record = |key: &[u8], value: &[u8]| {
+            let mut bytes = Vec::new();
+            bytes.extend_from_slice(&(key.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(key);
+            bytes.extend_from_slice(&(value.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(value);
+            bytes
+        };
+        // A flush that removed "b", then a newer file written out of order
+        fs::write(
+            dir.join("2.store.kv"),
+            [record(b"a", b"1"), record(b"b", b"")].concat(),
+        )
+        .unwrap();
+        fs::write(
+            dir.join("3.store.kv"),
+            [record(b"c", b"3"), record(b"a", b"2")].concat(),
+        )
+        .unwrap();
+        fs::write(dir.join("4.store.kv"), &record(b"d", b"4")[..7]).unwrap();
+        // Writes since the last flush, the last of them torn by a crash
+        fs::write(
+            dir.join(WRITE_AHEAD_LOG_FILENAME),
+            [
+                record(b"c", b""),
+                record(b"e", b"5"),
+                record(b"f", b"6")[..7].to_vec(),
+            ]
+            .concat(),
+        )
+        .unwrap();
+
+        let read_only = StoreOptions::new().read_only(true);
+        let err = Store::open(dir, &read_only).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = verify_store(dir, &StoreOptions::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::Unsupported);
+        let err = Store::open(dir, &StoreOptions::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!dir.join(manifest::MANIFEST_FILENAME).exists());
+
+        // The files upgraded before the damaged one was found aren't upgraded twice
+        fs::remove_file(dir.join("4.store.kv")).unwrap();
+        let store = Store::open(dir, &StoreOptions::new()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), None);
+        assert_eq!(store.get(b"e").unwrap(), Some(b"5".to_vec()));
+        assert_eq!(store.get(b"f").unwrap(), None);
+        store.put(b"d", b"4").unwrap();
+        store.close().unwrap();
+
+        let store = Store::open(dir, &read_only).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(store.get(b"d").unwrap(), Some(b"4".to_vec()));
+        assert_eq!(store.get(b"e").unwrap(), Some(b"5".to_vec()));
+    }
+
+    #[test]
+    fn wal_entries_of_unknown_kinds_are_refused() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "unknown_wal_entry_kind";
+        let dir = Path::new(&test_dir);
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+        drop(store);
+
+        // A batch that checks out, holding an entry of a kind we don't know
+        let mut payload = 0_u32.to_le_bytes().to_vec();
+        payload.push(9);
+        Store::append_kv_to_file(&mut payload, b"b", NO_EXPIRY, Some(b"2"));
+        let mut batch = (payload.len() as u64).to_le_bytes().to_vec();
+        batch.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        batch.extend_from_slice(&payload);
+        let mut wal = fs::File::options()
+            .append(true)
+            .open(dir.join(WRITE_AHEAD_LOG_FILENAME))
+            .unwrap();
+        wal.write_all(&batch).unwrap();
+        drop(wal);
+
+        let err = Store::open(dir, &StoreOptions::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn malformed_wal_batches_are_reported_and_cut_off_by_repair() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "malformed_wal_batch";
+        let dir = Path::new(&test_dir);
+        let wal_path = dir.join(WRITE_AHEAD_LOG_FILENAME);
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+        store.simulate_crash();
+        let intact_size = fs::metadata(&wal_path).unwrap().len();
+
+        // A range tombstone whose key claims to be 100 bytes long, behind a matching checksum
+        let payload = [0, 0, 0, 0, 2, 100, b'k'];
+        let mut batch = (payload.len() as u64).to_le_bytes().to_vec();
+        batch.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        batch.extend_from_slice(&payload);
+        let mut wal = fs::File::options().append(true).open(&wal_path).unwrap();
+        wal.write_all(&batch).unwrap();
+        drop(wal);
+
+        let err = Store::open(dir, &StoreOptions::new()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let read_only = StoreOptions::new().read_only(true);
+        let err = Store::open(dir, &read_only).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let report = verify_store(dir, &StoreOptions::new()).unwrap();
+        assert_eq!(report.wal_entries_checked, 1);
+        assert_eq!(report.damage.len(), 1);
+        assert_eq!(report.damage[0].path, wal_path);
+        repair_store(dir, &StoreOptions::new()).unwrap();
+        assert_eq!(fs::metadata(&wal_path).unwrap().len(), intact_size);
+
+        let store = Store::open(dir, &StoreOptions::new()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+    }
+
+    #[test]
+    fn failed_flushes_return_errors_and_keep_the_writes() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "failed_flush";
+        let dir = Path::new(&test_dir);
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+
+        // Nothing can be written where the flush puts its store file before moving it into place
+        fs::create_dir(dir.join("flush.temp")).unwrap();
+        assert!(store.flush_pending_writes().is_err());
+        assert!(store.export(Vec::new()).is_err());
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert!(
+            fs::metadata(dir.join(WRITE_AHEAD_LOG_FILENAME))
+                .unwrap()
+                .len()
+                > 0
+        );
+        assert!(store.close().is_err());
+
+        fs::remove_dir(dir.join("flush.temp")).unwrap();
+        let store = Store::open(dir, &StoreOptions::new()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        store.close().unwrap();
+    }
+
+    #[test]
+    fn failed_wal_appends_are_cut_off() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "failed_wal_append";
+        let dir = Path::new(&test_dir);
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+
+        // Part of the next batch makes it into the WAL before the append fails
+        let wal_path = dir.join(WRITE_AHEAD_LOG_FILENAME);
+        let mut log_file = File::options().append(true).open(&wal_path).unwrap();
+        log_file.write_all(&[7; 5]).unwrap();
+        store.writer().unwrap().wal_writer = BufWriter::new(File::open(&wal_path).unwrap());
+        assert!(store.put(b"b", b"2").is_err());
+
+        store.put(b"c", b"3").unwrap();
+        store.simulate_crash();
+        let store = Store::open(dir, &StoreOptions::new()).unwrap();
+        assert_eq!(store.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(store.get(b"b").unwrap(), None);
+        assert_eq!(store.get(b"c").unwrap(), Some(b"3".to_vec()));
+    }
+
+    #[test]
+    fn gets_return_errors_from_damaged_store_files() {
+        let test_dir = TEMP_TEST_FILE_DIR.to_string() + "get_from_damaged_store_file";
+        let dir = Path::new(&test_dir);
+        let store = open_fresh(&test_dir, StoreOptions::new());
+        store.put(b"a", b"1").unwrap();
+        store.close().unwrap();
+
+        let path = dir.join("2.store.kv");
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[5] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        let store = Store::open(dir, &StoreOptions::new()).unwrap();
+        let err = store.get(b"a").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
     // TODO: Some tombstone tests
 }
@@ -0,0 +1,114 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash::Hash,
+};
+
+struct LruEntry<V> {
+    value: V,
+    charge: u64,
+    last_used: u64,
+}
+
+/// A least recently used list, kept as a map from when each entry was last used to its key.
+/// Not thread safe by itself, the caches using it put it behind a lock
+pub(crate) struct Lru<K, V> {
+    entries: HashMap<K, LruEntry<V>>,
+    by_last_used: BTreeMap<u64, K>,
+    next_use: u64,
+    /// The sum of every entry's charge
+    pub(crate) usage: u64,
+}
+
+impl<K: Copy + Eq + Hash, V: Clone> Lru<K, V> {
+    pub(crate) fn new() -> Self {
+        Lru {
+            entries: HashMap::new(),
+            by_last_used: BTreeMap::new(),
+            next_use: 0,
+            usage: 0,
+        }
+    }
+
+    /// Marks the entry as the most recently used
+    pub(crate) fn get(&mut self, key: K) -> Option<V> {
+        let use_count = self.next_use;
+        let entry = self.entries.get_mut(&key)?;
+        self.next_use += 1;
+        self.by_last_used.remove(&entry.last_used);
+        self.by_last_used.insert(use_count, key);
+        entry.last_used = use_count;
+        return Some(entry.value.clone());
+    }
+
+    /// Evicts the least recently used entries until the new one fits. Values charged more than
+    /// the whole capacity are never added
+    pub(crate) fn insert(&mut self, key: K, value: V, charge: u64, capacity: u64) {
+        if charge > capacity {
+            return;
+        }
+        self.remove(key);
+        while self.usage + charge > capacity {
+            let (_, evicted_key) = self
+                .by_last_used
+                .pop_first()
+                .expect("The cache is over capacity with nothing in it");
+            let evicted = self.entries.remove(&evicted_key).unwrap();
+            self.usage -= evicted.charge;
+        }
+        let use_count = self.next_use;
+        self.next_use += 1;
+        self.by_last_used.insert(use_count, key);
+        self.entries.insert(
+            key,
+            LruEntry {
+                value,
+                charge,
+                last_used: use_count,
+            },
+        );
+        self.usage += charge;
+    }
+
+    pub(crate) fn remove(&mut self, key: K) {
+        if let Some(removed) = self.entries.remove(&key) {
+            self.by_last_used.remove(&removed.last_used);
+            self.usage -= removed.charge;
+        }
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn least_recently_used_entries_are_evicted_first() {
+        let mut lru = Lru::new();
+        lru.insert(1, 'a', 10, 30);
+        lru.insert(2, 'b', 10, 30);
+        lru.insert(3, 'c', 10, 30);
+        // Makes 2 the least recently used
+        assert_eq!(lru.get(1), Some('a'));
+
+        lru.insert(4, 'd', 10, 30);
+        assert_eq!(lru.get(2), None);
+        assert_eq!(lru.get(1), Some('a'));
+        assert_eq!(lru.get(3), Some('c'));
+        assert_eq!(lru.get(4), Some('d'));
+        assert_eq!(lru.usage, 30);
+
+        // Too big to ever fit, so nothing is evicted for it
+        lru.insert(5, 'e', 31, 30);
+        assert_eq!(lru.get(5), None);
+        assert_eq!(lru.len(), 3);
+
+        lru.remove(3);
+        assert_eq!(lru.get(3), None);
+        assert_eq!(lru.usage, 20);
+    }
+}
@@ -1,21 +1,80 @@
-use std::path::Path;
+#![allow(clippy::needless_return)]
 
-use store::Store;
+use std::{env, path::Path, process::ExitCode};
 
-fn main() {
-    let mut store = Store::new(Path::new("stuff"), false);
-    store.mem_table_size_limit_in_bytes = 1024 * 1024 * 1024;
+use store::{repair_store, verify_store, StoreOptions};
 
-    store.put(&50000000_u32.to_ne_bytes(), "hellowaoaodashdasd".as_bytes());
-    for i in 0..=10000000_u32 {
-        let key = i.to_ne_bytes();
-        store.put(&key, &key);
+const USAGE: &str = "Usage: store verify <dir>
+       store repair <dir>
+
+Checks, or repairs, a store that nothing has open. Exits with 0 when no damage was found, 1 when
+some was and 2 when the store couldn't be checked at all";
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let (command, dir) = match args.as_slice() {
+        [command, dir] => (command.as_str(), Path::new(dir)),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    // Stores using another comparator need verify_store or repair_store called with it
+    let options = StoreOptions::new();
+    let result = match command {
+        "verify" => verify(dir, &options),
+        "repair" => repair(dir, &options),
+        _ => {
+            eprintln!("{}", USAGE);
+            return ExitCode::from(2);
+        }
+    };
+    match result {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("Failed to {} {:?}: {}", command, dir, e);
+            ExitCode::from(2)
+        }
     }
+}
 
-    store.flush_pending_writes();
-    // store.compact();
-    let returned = String::from_utf8(store.get(&50000000_u32.to_ne_bytes()).unwrap()).unwrap();
-    dbg!(returned);
+/// Returns whether the store is intact
+fn verify(dir: &Path, options: &StoreOptions) -> std::io::Result<bool> {
+    let report = verify_store(dir, options)?;
+    println!(
+        "Checked {} store files holding {} entries, and {} WAL entries",
+        report.store_files_checked, report.entries_checked, report.wal_entries_checked
+    );
+    for damage in &report.damage {
+        println!("{}", damage);
+    }
+    if report.is_intact() {
+        println!("No damage found");
+    } else {
+        println!(
+            "Found {} problems, run `store repair` to fix them",
+            report.damage.len()
+        );
+    }
+    return Ok(report.is_intact());
+}
+
+/// Returns whether the store was intact before repairing
+fn repair(dir: &Path, options: &StoreOptions) -> std::io::Result<bool> {
+    let report = repair_store(dir, options)?;
+    if report.damage.is_empty() {
+        println!("No damage found, nothing to repair");
+        return Ok(true);
+    }
+    for damage in &report.damage {
+        println!("{}", damage);
+    }
+    println!("Salvaged {} entries", report.entries_salvaged);
+    for path in &report.quarantined {
+        println!("Quarantined {}", path.display());
+    }
+    return Ok(false);
 }
 
 #[cfg(test)]
@@ -46,20 +105,22 @@ mod tests {
 
         let json = serde_json::to_string(&thing).unwrap();
 
-        let mut store = Store::new(Path::new(&test_dir), false);
+        let store = Store::new(Path::new(&test_dir), false);
         let key = 1_u32.to_ne_bytes();
-        store.put(&key, &json.as_bytes());
+        store.put(&key, json.as_bytes()).unwrap();
 
         for i in 5..10_u32 {
             let i = i.to_ne_bytes();
-            store.put(&i, "Some stuff here and that".as_bytes());
+            store
+                .put(&i, "Some stuff here and that".as_bytes())
+                .unwrap();
         }
 
-        store.put(&key, &json.as_bytes());
+        store.put(&key, json.as_bytes()).unwrap();
 
-        let bytes = store.get(&key).unwrap();
+        let bytes = store.get(&key).unwrap().unwrap();
         let stored_json = std::str::from_utf8(&bytes).unwrap();
-        let parsed_thing: Thing = serde_json::from_str(&stored_json).unwrap();
+        let parsed_thing: Thing = serde_json::from_str(stored_json).unwrap();
         assert_eq!(stored_json, json);
         assert_eq!(thing, parsed_thing);
     }
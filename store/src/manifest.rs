@@ -0,0 +1,65 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comparator::default_comparator_name, sync_dir, ColumnFamilyOptions, DEFAULT_COLUMN_FAMILY,
+};
+
+pub(crate) const MANIFEST_FILENAME: &str = "MANIFEST";
+
+/// Persisted description of the column families in a store directory
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct Manifest {
+    /// Ids are never reused, so WAL entries for a dropped column family can't be replayed into a
+    /// newer column family with the same name
+    pub(crate) next_column_family_id: u32,
+    pub(crate) column_families: Vec<ColumnFamilyDescriptor>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct ColumnFamilyDescriptor {
+    pub(crate) id: u32,
+    pub(crate) name: String,
+    pub(crate) options: ColumnFamilyOptions,
+    /// Name of the comparator the column family's keys are ordered by
+    #[serde(default = "default_comparator_name")]
+    pub(crate) comparator: String,
+}
+
+impl Manifest {
+    pub(crate) fn exists(dir_path: &Path) -> bool {
+        dir_path.join(MANIFEST_FILENAME).exists()
+    }
+
+    /// Directories without a manifest only have the default column family
+    pub(crate) fn load(dir_path: &Path) -> io::Result<Self> {
+        match fs::read(dir_path.join(MANIFEST_FILENAME)) {
+            Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest {
+                next_column_family_id: 1,
+                column_families: vec![ColumnFamilyDescriptor {
+                    id: 0,
+                    name: DEFAULT_COLUMN_FAMILY.to_string(),
+                    options: ColumnFamilyOptions::default(),
+                    comparator: default_comparator_name(),
+                }],
+            }),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Replaces the manifest in one go, so a crash never leaves a half written manifest behind, and
+    /// syncs it so the old one can't come back either
+    pub(crate) fn save(&self, dir_path: &Path) -> io::Result<()> {
+        let temp_path = dir_path.join(MANIFEST_FILENAME.to_owned() + ".temp");
+        fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        File::open(&temp_path)?.sync_all()?;
+        fs::rename(temp_path, dir_path.join(MANIFEST_FILENAME))?;
+        sync_dir(dir_path)
+    }
+}
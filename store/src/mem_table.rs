@@ -0,0 +1,123 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, RwLock,
+    },
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comparator::{Comparator, OrderedKey},
+    range_tombstone::{RangeTombstone, RangeTombstones},
+    skiplist::SkipListMemTable,
+    TableEntry,
+};
+
+/// Which mem table implementation a Store keeps unflushed writes in
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum MemTableKind {
+    /// A `BTreeMap` behind a `RwLock`. Reads wait while a write is being applied
+    BTreeMap,
+    /// A concurrent skiplist allocated from an arena. Reads never take a lock
+    #[default]
+    SkipList,
+}
+
+impl MemTableKind {
+    pub(crate) fn create(&self, comparator: &Arc<dyn Comparator>) -> Arc<dyn MemTable> {
+        match self {
+            MemTableKind::BTreeMap => Arc::new(BTreeMemTable::new(comparator)),
+            MemTableKind::SkipList => Arc::new(SkipListMemTable::new(comparator)),
+        }
+    }
+}
+
+/// Keys written to a mem table before one of its range tombstones read back as tombstones, so
+/// deleting a range takes effect all at once without touching the keys it covers
+pub(crate) trait MemTable: Send + Sync {
+    fn insert(&self, key: Vec<u8>, entry: TableEntry);
+
+    fn delete_range(&self, tombstone: RangeTombstone);
+
+    fn get(&self, key: &[u8]) -> Option<TableEntry>;
+
+    /// Whether the mem table has neither keys nor range tombstones
+    fn is_empty(&self) -> bool;
+
+    /// Calls f with the latest entry for every key, in key order
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &TableEntry));
+
+    /// Range tombstones hiding keys in older mem tables and store files, as well as the keys
+    /// written to this one before them
+    fn range_tombstones(&self) -> &RangeTombstones;
+}
+
+pub(crate) struct BTreeMemTable {
+    /// Every write gets a higher sequence number, so range tombstones can tell which keys were
+    /// written before them
+    entries: RwLock<BTreeMap<OrderedKey, (TableEntry, u64)>>,
+    next_sequence: AtomicU64,
+    range_tombstones: RangeTombstones,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl BTreeMemTable {
+    pub(crate) fn new(comparator: &Arc<dyn Comparator>) -> Self {
+        BTreeMemTable {
+            entries: RwLock::new(BTreeMap::new()),
+            next_sequence: AtomicU64::new(1),
+            range_tombstones: RangeTombstones::default(),
+            comparator: comparator.clone(),
+        }
+    }
+}
+
+impl MemTable for BTreeMemTable {
+    fn insert(&self, key: Vec<u8>, entry: TableEntry) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        self.entries
+            .write()
+            .unwrap()
+            .insert(OrderedKey::new(key, &self.comparator), (entry, sequence));
+    }
+
+    fn delete_range(&self, tombstone: RangeTombstone) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        self.range_tombstones.add(tombstone, sequence);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<TableEntry> {
+        let ordered_key = OrderedKey::new(key.to_vec(), &self.comparator);
+        let (entry, sequence) = self.entries.read().unwrap().get(&ordered_key).cloned()?;
+        if self
+            .range_tombstones
+            .covers_since(key, sequence, self.comparator.as_ref())
+        {
+            return Some(TableEntry::Tombstone);
+        }
+        Some(entry)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.entries.read().unwrap().is_empty() && self.range_tombstones.is_empty()
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &TableEntry)) {
+        for (key, (entry, sequence)) in self.entries.read().unwrap().iter() {
+            if self
+                .range_tombstones
+                .covers_since(&key.key, *sequence, self.comparator.as_ref())
+            {
+                f(&key.key, &TableEntry::Tombstone);
+            } else {
+                f(&key.key, entry);
+            }
+        }
+    }
+
+    fn range_tombstones(&self) -> &RangeTombstones {
+        &self.range_tombstones
+    }
+}
@@ -0,0 +1,310 @@
+use std::{
+    fs::{self, File},
+    io,
+    path::Path,
+    sync::Arc,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    comparator::{Comparator, Comparators},
+    sync_dir, CompactionPolicy, MemTableKind, DEFAULT_COLUMN_FAMILY,
+};
+
+const OPTIONS_FILENAME: &str = "OPTIONS";
+
+const MAX_BLOCK_SIZE: usize = 64 * 1024 * 1024;
+
+const MAX_BLOOM_BITS_PER_KEY: u32 = 64;
+
+/// Store file indexes hold keys with a 4 byte size
+const MAX_KEY_SIZE: u64 = u32::MAX as u64;
+
+/// When writes to the WAL are fsynced
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum SyncMode {
+    /// Every write is handed to the OS before returning, but may be lost if the machine (rather
+    /// than the process) goes down before the OS writes it out
+    #[default]
+    Buffered,
+    /// Every write is fsynced before returning
+    EveryWrite,
+}
+
+/// How data blocks in store files are compressed. Blocks that barely compress are stored
+/// uncompressed whatever this is set to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub enum Compression {
+    #[default]
+    None,
+    /// Fast to compress and decompress
+    Lz4,
+    /// Slower than Lz4, but compresses better
+    Zstd,
+}
+
+/// Settings for opening a Store, built up from the defaults:
+///
+/// ```
+/// use store::{StoreOptions, SyncMode};
+///
+/// let options = StoreOptions::new()
+///     .mem_table_size_limit_in_bytes(64 * 1024 * 1024)
+///     .sync_mode(SyncMode::EveryWrite);
+/// ```
+///
+/// Options are checked when the Store is opened, and written to an OPTIONS file in the store
+/// directory so it's always possible to tell what a store was last opened with
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+// OPTIONS files written before an option existed load with its default
+#[serde(default)]
+pub struct StoreOptions {
+    pub(crate) mem_table_size_limit_in_bytes: u64,
+    pub(crate) mem_table_kind: MemTableKind,
+    pub(crate) sync_mode: SyncMode,
+    pub(crate) compaction_policy: CompactionPolicy,
+    pub(crate) block_size: usize,
+    pub(crate) compression: Compression,
+    pub(crate) compaction_compression: Option<Compression>,
+    pub(crate) bloom_bits_per_key: u32,
+    pub(crate) block_cache_size_in_bytes: u64,
+    pub(crate) max_open_files: usize,
+    pub(crate) mmap_reads: bool,
+    pub(crate) value_log_threshold_in_bytes: Option<u64>,
+    pub(crate) max_key_size_in_bytes: u64,
+    pub(crate) max_value_size_in_bytes: u64,
+    /// Comparators can't be written out, only their names, which the manifest already has
+    #[serde(skip)]
+    pub(crate) comparators: Comparators,
+    /// How the store is being opened this time, rather than how it's configured, so these load
+    /// with their defaults
+    #[serde(skip)]
+    pub(crate) create_if_missing: bool,
+    #[serde(skip)]
+    pub(crate) error_if_exists: bool,
+    #[serde(skip)]
+    pub(crate) read_only: bool,
+}
+
+impl Default for StoreOptions {
+    fn default() -> Self {
+        Self {
+            mem_table_size_limit_in_bytes: 1024 * 1024 * 5,
+            mem_table_kind: MemTableKind::default(),
+            sync_mode: SyncMode::default(),
+            compaction_policy: CompactionPolicy::default(),
+            block_size: 4 * 1024,
+            compression: Compression::default(),
+            compaction_compression: None,
+            bloom_bits_per_key: 10,
+            block_cache_size_in_bytes: 8 * 1024 * 1024,
+            max_open_files: 1000,
+            mmap_reads: false,
+            value_log_threshold_in_bytes: None,
+            max_key_size_in_bytes: 64 * 1024,
+            max_value_size_in_bytes: 1024 * 1024 * 1024,
+            comparators: Comparators::default(),
+            create_if_missing: true,
+            error_if_exists: false,
+            read_only: false,
+        }
+    }
+}
+
+impl StoreOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads the options a store was last opened with
+    pub fn load(dir_path: &Path) -> io::Result<Self> {
+        let bytes = fs::read(dir_path.join(OPTIONS_FILENAME))?;
+        Ok(serde_json::from_slice(&bytes)?)
+    }
+
+    /// Flushes the mem tables once writes to them add up to this many bytes. Applies to the mem
+    /// tables of every column family combined, since they all share one WAL
+    pub fn mem_table_size_limit_in_bytes(mut self, mem_table_size_limit_in_bytes: u64) -> Self {
+        self.mem_table_size_limit_in_bytes = mem_table_size_limit_in_bytes;
+        self
+    }
+
+    pub fn mem_table_kind(mut self, mem_table_kind: MemTableKind) -> Self {
+        self.mem_table_kind = mem_table_kind;
+        self
+    }
+
+    pub fn sync_mode(mut self, sync_mode: SyncMode) -> Self {
+        self.sync_mode = sync_mode;
+        self
+    }
+
+    /// Compaction policy for the default column family. Other column families get theirs from
+    /// the ColumnFamilyOptions they were created with
+    pub fn compaction_policy(mut self, compaction_policy: CompactionPolicy) -> Self {
+        self.compaction_policy = compaction_policy;
+        self
+    }
+
+    /// Size in bytes that store file blocks are filled up to
+    pub fn block_size(mut self, block_size: usize) -> Self {
+        self.block_size = block_size;
+        self
+    }
+
+    /// Compression for store files written by flushing a mem table
+    pub fn compression(mut self, compression: Compression) -> Self {
+        self.compression = compression;
+        self
+    }
+
+    /// Compression for store files written by compaction. Defaults to whatever `compression` is.
+    /// Since compaction rewrites every store file, compacting is also how existing store files
+    /// get moved over to a new codec
+    pub fn compaction_compression(mut self, compaction_compression: Compression) -> Self {
+        self.compaction_compression = Some(compaction_compression);
+        self
+    }
+
+    pub(crate) fn compaction_compression_or_default(&self) -> Compression {
+        self.compaction_compression.unwrap_or(self.compression)
+    }
+
+    /// Bits per key for store file bloom filters, or 0 for no bloom filters
+    pub fn bloom_bits_per_key(mut self, bloom_bits_per_key: u32) -> Self {
+        self.bloom_bits_per_key = bloom_bits_per_key;
+        self
+    }
+
+    /// How many bytes of decompressed data blocks to keep in memory, shared between every column
+    /// family. 0 turns the cache off
+    pub fn block_cache_size_in_bytes(mut self, block_cache_size_in_bytes: u64) -> Self {
+        self.block_cache_size_in_bytes = block_cache_size_in_bytes;
+        self
+    }
+
+    /// How many store files and value log segments to keep open between reads, shared between
+    /// every column family. Once
+    /// more files than this are in use, the least recently read ones are closed and have to be
+    /// opened again on their next read. 0 opens the file on every read
+    pub fn max_open_files(mut self, max_open_files: usize) -> Self {
+        self.max_open_files = max_open_files;
+        self
+    }
+
+    /// Read store files through a memory mapping of each file rather than with a read call per
+    /// block. Uncompressed blocks are then used straight from the mapping without being copied
+    /// or cached, leaving their caching to the OS. Defaults to false
+    pub fn mmap_reads(mut self, mmap_reads: bool) -> Self {
+        self.mmap_reads = mmap_reads;
+        self
+    }
+
+    /// Values at least this big are kept in a separate value log, with store files only holding a
+    /// pointer to them. Compactions then only have to move the pointer, rather than rewriting
+    /// the whole value every time. Off by default
+    pub fn value_log_threshold_in_bytes(mut self, value_log_threshold_in_bytes: u64) -> Self {
+        self.value_log_threshold_in_bytes = Some(value_log_threshold_in_bytes);
+        self
+    }
+
+    /// Writes with a bigger key fail with `ErrorKind::InvalidInput`. Defaults to 64KiB
+    pub fn max_key_size_in_bytes(mut self, max_key_size_in_bytes: u64) -> Self {
+        self.max_key_size_in_bytes = max_key_size_in_bytes;
+        self
+    }
+
+    /// Writes with a bigger value fail with `ErrorKind::InvalidInput`. Defaults to 1GiB
+    pub fn max_value_size_in_bytes(mut self, max_value_size_in_bytes: u64) -> Self {
+        self.max_value_size_in_bytes = max_value_size_in_bytes;
+        self
+    }
+
+    /// Key order for the default column family. Defaults to `BytewiseComparator`. The store
+    /// remembers the comparator's name, and fails to open with `ErrorKind::InvalidInput` if it's
+    /// given a comparator with a different name later
+    pub fn comparator(self, comparator: Arc<dyn Comparator>) -> Self {
+        self.column_family_comparator(DEFAULT_COLUMN_FAMILY, comparator)
+    }
+
+    /// Key order for the named column family, used when it's created and checked against on
+    /// every open after that, same as the default column family's comparator. Has to be given
+    /// every time the store is opened, since only the comparator's name is saved
+    pub fn column_family_comparator(
+        mut self,
+        column_family: &str,
+        comparator: Arc<dyn Comparator>,
+    ) -> Self {
+        self.comparators.set(column_family, comparator);
+        self
+    }
+
+    /// Create the store if the directory doesn't hold one yet. Defaults to true
+    pub fn create_if_missing(mut self, create_if_missing: bool) -> Self {
+        self.create_if_missing = create_if_missing;
+        self
+    }
+
+    /// Fail to open if the directory already holds a store. Defaults to false
+    pub fn error_if_exists(mut self, error_if_exists: bool) -> Self {
+        self.error_if_exists = error_if_exists;
+        self
+    }
+
+    /// Open without changing anything in the directory. Reads see the store as it was when it was
+    /// opened, and writes, flushes and compactions fail. Safe to use on a directory that another
+    /// Store has open for writing. Defaults to false
+    pub fn read_only(mut self, read_only: bool) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub(crate) fn validate(&self) -> io::Result<()> {
+        if self.mem_table_size_limit_in_bytes == 0 {
+            return Err(invalid_option(
+                "mem_table_size_limit_in_bytes must be above 0",
+            ));
+        }
+        if self.block_size == 0 || self.block_size > MAX_BLOCK_SIZE {
+            return Err(invalid_option(&format!(
+                "block_size must be between 1 and {} bytes, got {}",
+                MAX_BLOCK_SIZE, self.block_size
+            )));
+        }
+        if self.bloom_bits_per_key > MAX_BLOOM_BITS_PER_KEY {
+            return Err(invalid_option(&format!(
+                "bloom_bits_per_key can be at most {}, got {}",
+                MAX_BLOOM_BITS_PER_KEY, self.bloom_bits_per_key
+            )));
+        }
+        if self.max_key_size_in_bytes == 0 || self.max_key_size_in_bytes > MAX_KEY_SIZE {
+            return Err(invalid_option(&format!(
+                "max_key_size_in_bytes must be between 1 and {} bytes, got {}",
+                MAX_KEY_SIZE, self.max_key_size_in_bytes
+            )));
+        }
+        if self.max_value_size_in_bytes == 0 {
+            return Err(invalid_option("max_value_size_in_bytes must be above 0"));
+        }
+        if self.value_log_threshold_in_bytes == Some(0) {
+            return Err(invalid_option(
+                "value_log_threshold_in_bytes must be above 0",
+            ));
+        }
+        self.compaction_policy.validate()
+    }
+
+    /// Replaces the OPTIONS file in one go, same as the manifest
+    pub(crate) fn save(&self, dir_path: &Path) -> io::Result<()> {
+        let temp_path = dir_path.join(OPTIONS_FILENAME.to_owned() + ".temp");
+        fs::write(&temp_path, serde_json::to_vec_pretty(self)?)?;
+        File::open(&temp_path)?.sync_all()?;
+        fs::rename(temp_path, dir_path.join(OPTIONS_FILENAME))?;
+        sync_dir(dir_path)
+    }
+}
+
+pub(crate) fn invalid_option(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, message.to_string())
+}
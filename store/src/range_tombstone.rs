@@ -0,0 +1,67 @@
+use std::{cmp::Ordering, sync::RwLock};
+
+use serde::{Deserialize, Serialize};
+
+use crate::comparator::Comparator;
+
+/// Removes every key from start up to, but not including, end. Only hides keys written before it:
+/// keys in older mem tables and store files than the one holding the tombstone
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct RangeTombstone {
+    pub(crate) start: Vec<u8>,
+    pub(crate) end: Vec<u8>,
+}
+
+impl RangeTombstone {
+    pub(crate) fn covers(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        comparator.compare(&self.start, key) != Ordering::Greater
+            && comparator.compare(key, &self.end) == Ordering::Less
+    }
+}
+
+/// The range tombstones written to a mem table, each with the mem table's sequence number for
+/// when it was written. Kept to one side of its keys, since they cover keys that aren't in the
+/// mem table at all
+#[derive(Default)]
+pub(crate) struct RangeTombstones {
+    tombstones: RwLock<Vec<(RangeTombstone, u64)>>,
+}
+
+impl RangeTombstones {
+    pub(crate) fn add(&self, tombstone: RangeTombstone, sequence: u64) {
+        self.tombstones.write().unwrap().push((tombstone, sequence));
+    }
+
+    pub(crate) fn covers(&self, key: &[u8], comparator: &dyn Comparator) -> bool {
+        self.covers_since(key, 0, comparator)
+    }
+
+    /// Whether a range tombstone written after sequence covers the key
+    pub(crate) fn covers_since(
+        &self,
+        key: &[u8],
+        sequence: u64,
+        comparator: &dyn Comparator,
+    ) -> bool {
+        self.tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .any(|(tombstone, tombstone_sequence)| {
+                *tombstone_sequence > sequence && tombstone.covers(key, comparator)
+            })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.tombstones.read().unwrap().is_empty()
+    }
+
+    pub(crate) fn to_vec(&self) -> Vec<RangeTombstone> {
+        self.tombstones
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(tombstone, _)| tombstone.clone())
+            .collect()
+    }
+}
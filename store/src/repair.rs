@@ -0,0 +1,525 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    column_family::ColumnFamily,
+    comparator::check_comparator_name,
+    manifest::{ColumnFamilyDescriptor, Manifest, MANIFEST_FILENAME},
+    now_in_millis,
+    store_file::{StoreFile, StoreFileCaches, StoreFileWriter},
+    sync_dir, upgrade,
+    value_log::ValueLog,
+    wal, ColumnFamilyOptions, Comparator, Store, StoreOptions, COLUMN_FAMILIES_DIRNAME,
+    DEFAULT_COLUMN_FAMILY, STORE_FILENAME_SUFFIX, WRITE_AHEAD_LOG_FILENAME,
+};
+
+/// Everything a repair takes out of the store is moved under here, in a directory named after
+/// when the repair ran
+const QUARANTINE_DIRNAME: &str = "quarantine";
+
+/// Where the intact entries of a damaged store file are written before replacing it
+const SALVAGE_TEMP_FILENAME: &str = "salvage.temp";
+
+/// Something wrong with a file in a store directory
+#[derive(Clone, Debug, PartialEq)]
+pub struct Damage {
+    pub path: PathBuf,
+    pub description: String,
+}
+
+impl fmt::Display for Damage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.path.display(), self.description)
+    }
+}
+
+/// What `verify_store` checked, and what it found wrong
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct VerifyReport {
+    pub store_files_checked: usize,
+    /// Entries read out of store files, leaving out any that are damaged
+    pub entries_checked: u64,
+    pub wal_entries_checked: u64,
+    pub damage: Vec<Damage>,
+}
+
+impl VerifyReport {
+    pub fn is_intact(&self) -> bool {
+        self.damage.is_empty()
+    }
+}
+
+/// What `repair_store` found wrong, and what it did about it
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RepairReport {
+    /// Everything found wrong before repairing, as `verify_store` would report it
+    pub damage: Vec<Damage>,
+    /// Entries copied out of damaged store files into the files that replaced them
+    pub entries_salvaged: u64,
+    /// Where everything taken out of the store was moved to
+    pub quarantined: Vec<PathBuf>,
+}
+
+struct DamagedStoreFile {
+    path: PathBuf,
+    /// None when the file isn't named after a file id, so it can't be salvaged as one
+    id: Option<u64>,
+    comparator: Arc<dyn Comparator>,
+}
+
+/// Everything verifying found, along with what repairing needs to fix it
+struct Inspection {
+    report: VerifyReport,
+    damaged_store_files: Vec<DamagedStoreFile>,
+    /// Column family directories the manifest doesn't list, left behind by a drop that crashed
+    orphaned_dirs: Vec<PathBuf>,
+    /// Where the WAL stops being intact, when that's before its end
+    wal_intact_size_in_bytes: Option<u64>,
+    /// What to replace the manifest with, when it's damaged
+    repaired_manifest: Option<Manifest>,
+}
+
+impl Inspection {
+    fn damage(&mut self, path: &Path, description: impl Into<String>) {
+        self.report.damage.push(Damage {
+            path: path.to_path_buf(),
+            description: description.into(),
+        });
+    }
+}
+
+/// Where one repair moves what it takes out of the store, laid out like the store directory
+struct Quarantine {
+    store_dir: PathBuf,
+    dir: PathBuf,
+    paths: Vec<PathBuf>,
+}
+
+impl Quarantine {
+    fn new(store_dir: &Path) -> Self {
+        Quarantine {
+            store_dir: store_dir.to_path_buf(),
+            dir: store_dir
+                .join(QUARANTINE_DIRNAME)
+                .join(now_in_millis().to_string()),
+            paths: Vec::new(),
+        }
+    }
+
+    fn path_for(&mut self, path: &Path) -> io::Result<PathBuf> {
+        let quarantined = self.dir.join(path.strip_prefix(&self.store_dir).unwrap());
+        fs::create_dir_all(quarantined.parent().unwrap())?;
+        self.paths.push(quarantined.clone());
+        return Ok(quarantined);
+    }
+
+    fn move_in(&mut self, path: &Path) -> io::Result<()> {
+        fs::rename(path, self.path_for(path)?)
+    }
+
+    fn copy_in(&mut self, path: &Path) -> io::Result<()> {
+        fs::copy(path, self.path_for(path)?)?;
+        Ok(())
+    }
+
+    fn write(&mut self, path: &Path, contents: &[u8]) -> io::Result<()> {
+        fs::write(self.path_for(path)?, contents)
+    }
+}
+
+/// Checks every file in a store directory without changing any of them:
+/// - the manifest is readable, consistent with itself and the WAL, and has a directory for every
+///   column family
+/// - every store file opens, and every block in it passes its checksum with its keys in order
+/// - every value a store file points to in the value log passes its checksum
+/// - every batch in the WAL passes its checksum
+///
+/// Keys are checked against the comparators in options, which must be the ones the manifest names,
+/// just as for `Store::open`. Takes the directory's lock, so fails with `ErrorKind::WouldBlock`
+/// while a Store has it open
+pub fn verify_store(dir: &Path, options: &StoreOptions) -> io::Result<VerifyReport> {
+    let _lock_file = lock_store_dir(dir)?;
+    Ok(inspect(dir, options)?.report)
+}
+
+/// Verifies the store directory as `verify_store` does, then fixes what it found so the store opens
+/// with everything that could be saved:
+/// - a damaged store file is replaced by one with the same id holding only its intact entries, or
+///   taken out altogether if its footer, index or properties can't be read
+/// - the WAL is cut off after its last intact batch
+/// - an unreadable manifest is rebuilt from the column family directories. Their ids are lost
+///   along with it, so any of their writes still in the WAL are too. A manifest that contradicts
+///   itself is corrected
+/// - column family directories the manifest doesn't list are taken out
+///
+/// Nothing is deleted. Whatever is taken out or replaced, and the damaged end of the WAL, is moved
+/// under `quarantine` in the store directory
+pub fn repair_store(dir: &Path, options: &StoreOptions) -> io::Result<RepairReport> {
+    let _lock_file = lock_store_dir(dir)?;
+    let inspection = inspect(dir, options)?;
+    let mut quarantine = Quarantine::new(dir);
+    let caches = Arc::new(StoreFileCaches::new(options));
+    let mut entries_salvaged = 0;
+    for damaged in &inspection.damaged_store_files {
+        entries_salvaged += salvage_store_file(damaged, options, &caches, &mut quarantine)?;
+    }
+
+    if let Some(intact_size_in_bytes) = inspection.wal_intact_size_in_bytes {
+        let wal_path = dir.join(WRITE_AHEAD_LOG_FILENAME);
+        let damaged_end = fs::read(&wal_path)?.split_off(intact_size_in_bytes as usize);
+        quarantine.write(&wal_path, &damaged_end)?;
+        let wal_file = File::options().write(true).open(&wal_path)?;
+        wal_file.set_len(intact_size_in_bytes)?;
+        wal_file.sync_all()?;
+    }
+
+    if let Some(manifest) = &inspection.repaired_manifest {
+        let manifest_path = dir.join(MANIFEST_FILENAME);
+        // Copied rather than moved, since saving replaces the manifest in one go
+        if manifest_path.exists() {
+            quarantine.copy_in(&manifest_path)?;
+        }
+        manifest.save(dir)?;
+    }
+
+    for orphaned_dir in &inspection.orphaned_dirs {
+        quarantine.move_in(orphaned_dir)?;
+    }
+    sync_dir(dir)?;
+
+    Ok(RepairReport {
+        damage: inspection.report.damage,
+        entries_salvaged,
+        quarantined: quarantine.paths,
+    })
+}
+
+/// Refuses directories Store::open wouldn't find a store in, rather than reporting them as damaged,
+/// and stores in the original format, whose files would all look damaged
+fn lock_store_dir(dir: &Path) -> io::Result<File> {
+    if !Manifest::exists(dir) {
+        if dir.join(WRITE_AHEAD_LOG_FILENAME).exists() {
+            return Err(upgrade::needs_upgrade_error(dir));
+        }
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("No store in {:?}", dir),
+        ));
+    }
+    Store::lock_dir(dir)
+}
+
+fn inspect(dir: &Path, options: &StoreOptions) -> io::Result<Inspection> {
+    let mut inspection = Inspection {
+        report: VerifyReport::default(),
+        damaged_store_files: Vec::new(),
+        orphaned_dirs: Vec::new(),
+        wal_intact_size_in_bytes: None,
+        repaired_manifest: None,
+    };
+
+    let wal_path = dir.join(WRITE_AHEAD_LOG_FILENAME);
+    let wal = wal::read_wal(wal_path.clone())?;
+    inspection.report.wal_entries_checked = wal.entries.len() as u64;
+    if let Some(e) = &wal.corrupt_batch {
+        inspection.damage(&wal_path, e.to_string());
+        inspection.wal_intact_size_in_bytes = Some(wal.intact_size_in_bytes);
+    } else if wal.intact_size_in_bytes < wal.size_in_bytes {
+        inspection.damage(
+            &wal_path,
+            format!(
+                "the {} bytes from offset {} aren't an intact batch, so they won't be replayed",
+                wal.size_in_bytes - wal.intact_size_in_bytes,
+                wal.intact_size_in_bytes
+            ),
+        );
+        inspection.wal_intact_size_in_bytes = Some(wal.intact_size_in_bytes);
+    }
+    let wal_column_family_ids: HashSet<_> = wal
+        .entries
+        .iter()
+        .map(|entry| entry.column_family_id)
+        .collect();
+
+    let column_families = inspect_manifest(dir, options, &wal_column_family_ids, &mut inspection)?;
+    let caches = Arc::new(StoreFileCaches::new(options));
+    for name in column_families {
+        let family_dir = ColumnFamily::dir_for(dir, &name);
+        if family_dir.is_dir() {
+            let comparator = options.comparators.for_column_family(&name);
+            inspect_store_files(&family_dir, &comparator, &caches, &mut inspection)?;
+        }
+    }
+    return Ok(inspection);
+}
+
+/// Names of the directories under column_families
+fn column_family_dir_names(dir: &Path) -> io::Result<Vec<String>> {
+    let mut names = Vec::new();
+    let entries = match fs::read_dir(dir.join(COLUMN_FAMILIES_DIRNAME)) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(names),
+        Err(e) => return Err(e),
+    };
+    for entry in entries {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().to_string());
+        }
+    }
+    names.sort();
+    return Ok(names);
+}
+
+/// Returns the column families whose store files should be checked
+fn inspect_manifest(
+    dir: &Path,
+    options: &StoreOptions,
+    wal_column_family_ids: &HashSet<u32>,
+    inspection: &mut Inspection,
+) -> io::Result<Vec<String>> {
+    let manifest_path = dir.join(MANIFEST_FILENAME);
+    let dir_names = column_family_dir_names(dir)?;
+    let descriptor_for = |id, name: &str| ColumnFamilyDescriptor {
+        id,
+        name: name.to_string(),
+        options: ColumnFamilyOptions::default(),
+        comparator: options
+            .comparators
+            .for_column_family(name)
+            .name()
+            .to_string(),
+    };
+
+    let manifest = match Manifest::load(dir) {
+        Ok(manifest) => manifest,
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof
+            ) =>
+        {
+            inspection.damage(&manifest_path, format!("unreadable: {}", e));
+            // Every column family directory is taken to be a column family, with an id nothing in
+            // the WAL uses, so none of another column family's writes get replayed into it
+            let mut next_column_family_id = wal_column_family_ids
+                .iter()
+                .max()
+                .map_or(1, |id| id + 1)
+                .max(1);
+            let mut column_families = vec![descriptor_for(0, DEFAULT_COLUMN_FAMILY)];
+            for name in &dir_names {
+                column_families.push(descriptor_for(next_column_family_id, name));
+                next_column_family_id += 1;
+            }
+            let names = column_families
+                .iter()
+                .map(|descriptor| descriptor.name.clone())
+                .collect();
+            inspection.repaired_manifest = Some(Manifest {
+                next_column_family_id,
+                column_families,
+            });
+            return Ok(names);
+        }
+        Err(e) => return Err(e),
+    };
+
+    // Nothing can be judged about keys ordered by a comparator we don't have
+    for descriptor in &manifest.column_families {
+        check_comparator_name(
+            &format!("Column family {}", descriptor.name),
+            &descriptor.comparator,
+            options
+                .comparators
+                .for_column_family(&descriptor.name)
+                .as_ref(),
+        )?;
+    }
+
+    let mut manifest_damaged = false;
+    let mut names = HashSet::new();
+    let mut ids = HashSet::new();
+    let mut column_families = Vec::new();
+    for descriptor in manifest.column_families {
+        if names.contains(&descriptor.name) || ids.contains(&descriptor.id) {
+            inspection.damage(
+                &manifest_path,
+                format!(
+                    "lists column family {} with id {} after another column family with the same name or id",
+                    descriptor.name, descriptor.id
+                ),
+            );
+            manifest_damaged = true;
+            continue;
+        }
+        let family_dir = ColumnFamily::dir_for(dir, &descriptor.name);
+        if !family_dir.is_dir() {
+            inspection.damage(
+                &family_dir,
+                format!(
+                    "column family {} is in the MANIFEST, but its directory is missing",
+                    descriptor.name
+                ),
+            );
+        }
+        names.insert(descriptor.name.clone());
+        ids.insert(descriptor.id);
+        column_families.push(descriptor);
+    }
+    if !names.contains(DEFAULT_COLUMN_FAMILY) {
+        inspection.damage(&manifest_path, "the default column family is missing");
+        manifest_damaged = true;
+        names.insert(DEFAULT_COLUMN_FAMILY.to_string());
+        column_families.insert(0, descriptor_for(0, DEFAULT_COLUMN_FAMILY));
+    }
+    let highest_id_in_use = column_families
+        .iter()
+        .map(|descriptor| descriptor.id)
+        .chain(wal_column_family_ids.iter().copied())
+        .max()
+        .unwrap_or(0);
+    let mut next_column_family_id = manifest.next_column_family_id;
+    if next_column_family_id <= highest_id_in_use {
+        inspection.damage(
+            &manifest_path,
+            format!(
+                "column family id {} is handed out next, but ids up to {} are already in use",
+                next_column_family_id, highest_id_in_use
+            ),
+        );
+        manifest_damaged = true;
+        next_column_family_id = highest_id_in_use + 1;
+    }
+
+    for name in dir_names {
+        if !names.contains(&name) {
+            let orphaned_dir = dir.join(COLUMN_FAMILIES_DIRNAME).join(&name);
+            inspection.damage(&orphaned_dir, "isn't a column family in the MANIFEST");
+            inspection.orphaned_dirs.push(orphaned_dir);
+        }
+    }
+
+    let names = column_families
+        .iter()
+        .map(|descriptor| descriptor.name.clone())
+        .collect();
+    if manifest_damaged {
+        inspection.repaired_manifest = Some(Manifest {
+            next_column_family_id,
+            column_families,
+        });
+    }
+    return Ok(names);
+}
+
+fn store_file_id(path: &Path) -> Option<u64> {
+    path.file_name()?
+        .to_string_lossy()
+        .strip_suffix(STORE_FILENAME_SUFFIX)?
+        .parse()
+        .ok()
+}
+
+fn inspect_store_files(
+    family_dir: &Path,
+    comparator: &Arc<dyn Comparator>,
+    caches: &Arc<StoreFileCaches>,
+    inspection: &mut Inspection,
+) -> io::Result<()> {
+    let value_log = ValueLog::open(family_dir, false, caches)?;
+    let mut paths = Store::get_store_files(family_dir)?;
+    paths.sort();
+    for path in paths {
+        inspection.report.store_files_checked += 1;
+        let id = store_file_id(&path);
+        let problems = match id {
+            None => vec!["the name isn't a file id".to_string()],
+            Some(id) => {
+                match StoreFile::open(id, path.clone(), false, caches, &value_log, comparator) {
+                    Ok(store_file) => {
+                        let mut entry_count = 0;
+                        let problems = store_file.verify_entries(&mut |_, _| entry_count += 1);
+                        inspection.report.entries_checked += entry_count;
+                        problems
+                    }
+                    Err(e) => vec![e.to_string()],
+                }
+            }
+        };
+        if !problems.is_empty() {
+            for problem in problems {
+                inspection.damage(&path, problem);
+            }
+            inspection.damaged_store_files.push(DamagedStoreFile {
+                path,
+                id,
+                comparator: comparator.clone(),
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Replaces a damaged store file with one holding its intact entries and range tombstones, under
+/// the same id so it shadows the same files it did before. Returns how many entries were salvaged
+fn salvage_store_file(
+    damaged: &DamagedStoreFile,
+    options: &StoreOptions,
+    caches: &Arc<StoreFileCaches>,
+    quarantine: &mut Quarantine,
+) -> io::Result<u64> {
+    let family_dir = damaged.path.parent().unwrap();
+    let value_log = ValueLog::open(family_dir, false, caches)?;
+    let store_file = match damaged.id.map(|id| {
+        StoreFile::open(
+            id,
+            damaged.path.clone(),
+            false,
+            caches,
+            &value_log,
+            &damaged.comparator,
+        )
+    }) {
+        Some(Ok(store_file)) => store_file,
+        // Without its index there's no telling where its blocks are
+        _ => {
+            quarantine.move_in(&damaged.path)?;
+            return Ok(0);
+        }
+    };
+
+    let temp_path = family_dir.join(SALVAGE_TEMP_FILENAME);
+    let mut writer = StoreFileWriter::create(
+        &temp_path,
+        options,
+        store_file.properties.compression,
+        &damaged.comparator,
+    )?;
+    for tombstone in &store_file.properties.range_tombstones {
+        writer.add_range_tombstone(tombstone.clone());
+    }
+    let mut entries_salvaged = 0;
+    let mut result = Ok(());
+    // Entries pointing into the value log keep doing so, since verifying read their values
+    store_file.verify_entries(&mut |key, entry| {
+        if result.is_ok() {
+            result = writer.add(&key, &entry);
+            entries_salvaged += 1;
+        }
+    });
+    result?;
+    writer.finish()?;
+    drop(store_file);
+
+    quarantine.move_in(&damaged.path)?;
+    fs::rename(&temp_path, &damaged.path)?;
+    sync_dir(family_dir)?;
+    return Ok(entries_salvaged);
+}
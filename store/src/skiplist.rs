@@ -0,0 +1,484 @@
+use std::{
+    alloc::{self, Layout},
+    cmp::Ordering as KeyOrdering,
+    ptr, slice,
+    sync::{
+        atomic::{AtomicPtr, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use crate::{
+    comparator::Comparator,
+    mem_table::MemTable,
+    range_tombstone::{RangeTombstone, RangeTombstones},
+    TableEntry,
+};
+
+const MAX_HEIGHT: usize = 12;
+
+/// Each node has a 1 in BRANCHING chance of also being linked on the level above
+const BRANCHING: u64 = 4;
+
+const ARENA_BLOCK_SIZE: usize = 64 * 1024;
+
+const ARENA_ALIGNMENT: usize = 8;
+
+/// Hands out memory that lives until the arena is dropped, so a mem table doesn't need an
+/// allocation per key. Allocating takes a short lock, but nothing that reads from the memory does
+struct Arena {
+    blocks: Mutex<ArenaBlocks>,
+}
+
+struct ArenaBlocks {
+    blocks: Vec<(*mut u8, Layout)>,
+    current_block: *mut u8,
+    current_block_used: usize,
+}
+
+// The raw pointers are only ever handed out, never read through, by the arena itself
+unsafe impl Send for ArenaBlocks {}
+
+impl Arena {
+    fn new() -> Self {
+        Self {
+            blocks: Mutex::new(ArenaBlocks {
+                blocks: Vec::new(),
+                current_block: ptr::null_mut(),
+                // Forces a block to be allocated on first use
+                current_block_used: ARENA_BLOCK_SIZE,
+            }),
+        }
+    }
+
+    fn allocate_block(blocks: &mut ArenaBlocks, size: usize) -> *mut u8 {
+        let layout = Layout::from_size_align(size, ARENA_ALIGNMENT).unwrap();
+        let block = unsafe { alloc::alloc_zeroed(layout) };
+        if block.is_null() {
+            alloc::handle_alloc_error(layout);
+        }
+        blocks.blocks.push((block, layout));
+        return block;
+    }
+
+    /// The returned memory is zeroed and aligned to ARENA_ALIGNMENT
+    fn allocate(&self, size: usize) -> *mut u8 {
+        let size = size.max(1).next_multiple_of(ARENA_ALIGNMENT);
+        let mut blocks = self.blocks.lock().unwrap();
+        if size > ARENA_BLOCK_SIZE / 4 {
+            // Big allocations get a block to themselves, rather than wasting what is left of the
+            // current one
+            return Self::allocate_block(&mut blocks, size);
+        }
+        if blocks.current_block_used + size > ARENA_BLOCK_SIZE {
+            blocks.current_block = Self::allocate_block(&mut blocks, ARENA_BLOCK_SIZE);
+            blocks.current_block_used = 0;
+        }
+        let allocation = unsafe { blocks.current_block.add(blocks.current_block_used) };
+        blocks.current_block_used += size;
+        return allocation;
+    }
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for (block, layout) in self.blocks.get_mut().unwrap().blocks.drain(..) {
+            unsafe { alloc::dealloc(block, layout) };
+        }
+    }
+}
+
+/// Nodes and everything they point to live in the arena. Nodes are never changed once they are
+/// linked in, apart from their next pointers
+struct Node {
+    key: *const u8,
+    key_len: usize,
+    value: *const u8,
+    value_len: usize,
+    tombstone: bool,
+    expires_at: u64,
+    /// Every insert gets a higher sequence number, so newer writes to a key sort before older ones
+    sequence: u64,
+    /// Points at `height` next pointers, one per level the node is linked on
+    next: *const AtomicPtr<Node>,
+}
+
+impl Node {
+    fn key(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.key, self.key_len) }
+    }
+
+    /// The node's entry, unless a range tombstone written after it covers its key
+    fn live_entry(
+        &self,
+        range_tombstones: &RangeTombstones,
+        comparator: &dyn Comparator,
+    ) -> TableEntry {
+        if range_tombstones.covers_since(self.key(), self.sequence, comparator) {
+            return TableEntry::Tombstone;
+        }
+        self.entry()
+    }
+
+    fn next(&self, level: usize) -> &AtomicPtr<Node> {
+        unsafe { &*self.next.add(level) }
+    }
+
+    fn entry(&self) -> TableEntry {
+        if self.tombstone {
+            return TableEntry::Tombstone;
+        }
+        let value = unsafe { slice::from_raw_parts(self.value, self.value_len) };
+        TableEntry::Populated {
+            value: value.to_vec(),
+            expires_at: self.expires_at,
+        }
+    }
+
+    /// Nodes are sorted by key, then newest first
+    fn is_before(&self, key: &[u8], sequence: u64, comparator: &dyn Comparator) -> bool {
+        match comparator.compare(self.key(), key) {
+            KeyOrdering::Less => true,
+            KeyOrdering::Equal => self.sequence > sequence,
+            KeyOrdering::Greater => false,
+        }
+    }
+}
+
+/// A skiplist that can be read without locks while inserts happen concurrently. Keys are never
+/// removed or overwritten, a newer write to a key is inserted in front of the older ones instead
+pub(crate) struct SkipListMemTable {
+    arena: Arena,
+    head: *const Node,
+    next_sequence: AtomicU64,
+    random_state: AtomicU64,
+    range_tombstones: RangeTombstones,
+    comparator: Arc<dyn Comparator>,
+}
+
+// Everything the raw pointers point at is owned by the arena, and only changes through atomics
+unsafe impl Send for SkipListMemTable {}
+unsafe impl Sync for SkipListMemTable {}
+
+impl SkipListMemTable {
+    pub(crate) fn new(comparator: &Arc<dyn Comparator>) -> Self {
+        let arena = Arena::new();
+        let head = Self::allocate_node(&arena, &[], &TableEntry::Tombstone, 0, MAX_HEIGHT);
+        Self {
+            arena,
+            head,
+            next_sequence: AtomicU64::new(1),
+            random_state: AtomicU64::new(0x853c49e6748fea9b),
+            range_tombstones: RangeTombstones::default(),
+            comparator: comparator.clone(),
+        }
+    }
+
+    fn allocate_node(
+        arena: &Arena,
+        key: &[u8],
+        entry: &TableEntry,
+        sequence: u64,
+        height: usize,
+    ) -> *const Node {
+        let key_copy = arena.allocate(key.len());
+        unsafe { ptr::copy_nonoverlapping(key.as_ptr(), key_copy, key.len()) };
+        let (value, value_len, tombstone, expires_at) = match entry {
+            TableEntry::Tombstone => (ptr::null(), 0, true, 0),
+            TableEntry::Populated { value, expires_at } => {
+                let value_copy = arena.allocate(value.len());
+                unsafe { ptr::copy_nonoverlapping(value.as_ptr(), value_copy, value.len()) };
+                (value_copy as *const u8, value.len(), false, *expires_at)
+            }
+            TableEntry::InValueLog { .. } => {
+                unreachable!("Value pointers are never written to a mem table")
+            }
+            TableEntry::RangeTombstone { .. } => {
+                unreachable!("Range tombstones are kept apart from a mem table's keys")
+            }
+        };
+        // Zeroed memory is a valid null AtomicPtr
+        let next = arena.allocate(height * std::mem::size_of::<AtomicPtr<Node>>());
+        let node = arena.allocate(std::mem::size_of::<Node>()) as *mut Node;
+        unsafe {
+            node.write(Node {
+                key: key_copy,
+                key_len: key.len(),
+                value,
+                value_len,
+                tombstone,
+                expires_at,
+                sequence,
+                next: next as *const AtomicPtr<Node>,
+            })
+        };
+        return node;
+    }
+
+    fn node(&self, node: *const Node) -> &Node {
+        unsafe { &*node }
+    }
+
+    fn random_height(&self) -> usize {
+        // splitmix64, so concurrent inserts don't need a lock to get a random number
+        let mut random = self
+            .random_state
+            .fetch_add(0x9e3779b97f4a7c15, Ordering::Relaxed);
+        random = (random ^ (random >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        random = (random ^ (random >> 27)).wrapping_mul(0x94d049bb133111eb);
+        random ^= random >> 31;
+
+        let mut height = 1;
+        while height < MAX_HEIGHT && random.is_multiple_of(BRANCHING) {
+            height += 1;
+            random /= BRANCHING;
+        }
+        return height;
+    }
+
+    /// Walks along level from node, returning the last node before (key, sequence) and the node
+    /// after it
+    fn find_on_level(
+        &self,
+        mut node: *const Node,
+        level: usize,
+        key: &[u8],
+        sequence: u64,
+    ) -> (*const Node, *const Node) {
+        loop {
+            let next = self.node(node).next(level).load(Ordering::Acquire);
+            if !next.is_null()
+                && self
+                    .node(next)
+                    .is_before(key, sequence, self.comparator.as_ref())
+            {
+                node = next;
+            } else {
+                return (node, next);
+            }
+        }
+    }
+
+    /// Returns the first node that isn't before (key, sequence), or null
+    fn find_greater_or_equal(&self, key: &[u8], sequence: u64) -> *const Node {
+        let mut node = self.head;
+        let mut next = ptr::null();
+        for level in (0..MAX_HEIGHT).rev() {
+            (node, next) = self.find_on_level(node, level, key, sequence);
+        }
+        return next;
+    }
+}
+
+impl MemTable for SkipListMemTable {
+    fn insert(&self, key: Vec<u8>, entry: TableEntry) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        let height = self.random_height();
+        let node = Self::allocate_node(&self.arena, &key, &entry, sequence, height);
+
+        let mut before = [ptr::null(); MAX_HEIGHT];
+        let mut after = [ptr::null(); MAX_HEIGHT];
+        let mut current = self.head;
+        for level in (0..MAX_HEIGHT).rev() {
+            (current, after[level]) = self.find_on_level(current, level, &key, sequence);
+            before[level] = current;
+        }
+
+        // Linking from the bottom up means a node is always reachable on level 0 before any other
+        // level, so a reader can never skip past it
+        for level in 0..height {
+            loop {
+                self.node(node)
+                    .next(level)
+                    .store(after[level] as *mut Node, Ordering::Relaxed);
+                if self
+                    .node(before[level])
+                    .next(level)
+                    .compare_exchange(
+                        after[level] as *mut Node,
+                        node as *mut Node,
+                        Ordering::Release,
+                        Ordering::Relaxed,
+                    )
+                    .is_ok()
+                {
+                    break;
+                }
+                // Another insert got in between the two nodes first, so look again from where we
+                // were. Nodes are never removed, so that node is still before ours
+                (before[level], after[level]) =
+                    self.find_on_level(before[level], level, &key, sequence);
+            }
+        }
+    }
+
+    fn delete_range(&self, tombstone: RangeTombstone) {
+        let sequence = self.next_sequence.fetch_add(1, Ordering::Relaxed);
+        self.range_tombstones.add(tombstone, sequence);
+    }
+
+    fn get(&self, key: &[u8]) -> Option<TableEntry> {
+        // The highest sequence number sorts first, so this finds the newest write for the key
+        let node = self.find_greater_or_equal(key, u64::MAX);
+        if node.is_null() || self.node(node).key() != key {
+            return None;
+        }
+        return Some(
+            self.node(node)
+                .live_entry(&self.range_tombstones, self.comparator.as_ref()),
+        );
+    }
+
+    fn is_empty(&self) -> bool {
+        self.node(self.head)
+            .next(0)
+            .load(Ordering::Acquire)
+            .is_null()
+            && self.range_tombstones.is_empty()
+    }
+
+    fn for_each(&self, f: &mut dyn FnMut(&[u8], &TableEntry)) {
+        let mut previous_key: Option<&[u8]> = None;
+        let mut node = self.node(self.head).next(0).load(Ordering::Acquire) as *const Node;
+        while !node.is_null() {
+            let current = self.node(node);
+            // Only the first node for a key is the latest write, the rest are older versions
+            if previous_key != Some(current.key()) {
+                f(
+                    current.key(),
+                    &current.live_entry(&self.range_tombstones, self.comparator.as_ref()),
+                );
+                previous_key = Some(current.key());
+            }
+            node = current.next(0).load(Ordering::Acquire);
+        }
+    }
+
+    fn range_tombstones(&self) -> &RangeTombstones {
+        &self.range_tombstones
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    fn bytewise() -> Arc<dyn Comparator> {
+        Arc::new(BytewiseComparator)
+    }
+
+    fn value_of(entry: Option<TableEntry>) -> Option<Vec<u8>> {
+        match entry {
+            Some(TableEntry::Populated { value, .. }) => Some(value),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn it_keeps_keys_sorted_and_returns_the_latest_write() {
+        let mem_table = SkipListMemTable::new(&bytewise());
+        let populated = |value: &str| TableEntry::Populated {
+            value: value.as_bytes().to_vec(),
+            expires_at: 0,
+        };
+        mem_table.insert("b".as_bytes().to_vec(), populated("1"));
+        mem_table.insert("a".as_bytes().to_vec(), populated("2"));
+        mem_table.insert("b".as_bytes().to_vec(), populated("3"));
+        mem_table.insert("c".as_bytes().to_vec(), TableEntry::Tombstone);
+
+        assert_eq!(value_of(mem_table.get("b".as_bytes())), Some("3".into()));
+        assert!(matches!(
+            mem_table.get("c".as_bytes()),
+            Some(TableEntry::Tombstone)
+        ));
+        assert!(mem_table.get("d".as_bytes()).is_none());
+
+        let mut entries = Vec::new();
+        mem_table.for_each(&mut |key, entry| {
+            entries.push((key.to_vec(), value_of(Some(entry.clone()))))
+        });
+        assert_eq!(
+            entries,
+            vec![
+                ("a".into(), Some("2".into())),
+                ("b".into(), Some("3".into())),
+                ("c".into(), None)
+            ]
+        );
+    }
+
+    #[test]
+    fn range_tombstones_only_hide_keys_written_before_them() {
+        let mem_tables: [Arc<dyn MemTable>; 2] = [
+            Arc::new(SkipListMemTable::new(&bytewise())),
+            Arc::new(crate::mem_table::BTreeMemTable::new(&bytewise())),
+        ];
+        for mem_table in mem_tables {
+            let populated = |value: &str| TableEntry::Populated {
+                value: value.as_bytes().to_vec(),
+                expires_at: 0,
+            };
+            mem_table.insert("a".as_bytes().to_vec(), populated("1"));
+            mem_table.insert("b".as_bytes().to_vec(), populated("2"));
+            mem_table.insert("d".as_bytes().to_vec(), populated("3"));
+            mem_table.delete_range(RangeTombstone {
+                start: "a".as_bytes().to_vec(),
+                end: "c".as_bytes().to_vec(),
+            });
+            mem_table.insert("b".as_bytes().to_vec(), populated("4"));
+
+            assert!(matches!(
+                mem_table.get("a".as_bytes()),
+                Some(TableEntry::Tombstone)
+            ));
+            assert_eq!(value_of(mem_table.get("b".as_bytes())), Some("4".into()));
+            assert_eq!(value_of(mem_table.get("d".as_bytes())), Some("3".into()));
+            let mut entries = Vec::new();
+            mem_table.for_each(&mut |key, entry| {
+                entries.push((key.to_vec(), value_of(Some(entry.clone()))))
+            });
+            assert_eq!(
+                entries,
+                vec![
+                    ("a".into(), None),
+                    ("b".into(), Some("4".into())),
+                    ("d".into(), Some("3".into()))
+                ]
+            );
+        }
+    }
+
+    #[test]
+    fn it_handles_concurrent_inserts_and_reads() {
+        let mem_table = Arc::new(SkipListMemTable::new(&bytewise()));
+        let threads: Vec<_> = (0..4_u32)
+            .map(|thread_id| {
+                let mem_table = mem_table.clone();
+                thread::spawn(move || {
+                    for i in 0..1000_u32 {
+                        let key = (i * 4 + thread_id).to_be_bytes().to_vec();
+                        mem_table.insert(
+                            key.clone(),
+                            TableEntry::Populated {
+                                value: key.clone(),
+                                expires_at: 0,
+                            },
+                        );
+                        assert_eq!(value_of(mem_table.get(&key)), Some(key));
+                    }
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        let mut keys = Vec::new();
+        mem_table.for_each(&mut |key, _| keys.push(key.to_vec()));
+        let expected: Vec<_> = (0..4000_u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        assert_eq!(keys, expected);
+    }
+}
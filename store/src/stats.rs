@@ -0,0 +1,268 @@
+use std::{
+    collections::BTreeMap,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+use crate::BlockCacheStats;
+
+/// Values are counted in power of two buckets. Bucket 0 counts zeroes, and bucket i counts values
+/// from 2^(i-1) up to 2^i - 1
+const HISTOGRAM_BUCKET_COUNT: usize = 65;
+
+fn bucket_for(value: u64) -> usize {
+    (u64::BITS - value.leading_zeros()) as usize
+}
+
+fn bucket_upper_bound(bucket: usize) -> u64 {
+    match bucket {
+        0 => 0,
+        64.. => u64::MAX,
+        _ => (1 << bucket) - 1,
+    }
+}
+
+/// How a set of values was spread out, e.g. how many microseconds every get took
+#[derive(Clone, Debug, PartialEq)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum: u64,
+    pub max: u64,
+    buckets: Vec<u64>,
+}
+
+impl Default for Histogram {
+    fn default() -> Self {
+        Histogram {
+            count: 0,
+            sum: 0,
+            max: 0,
+            buckets: vec![0; HISTOGRAM_BUCKET_COUNT],
+        }
+    }
+}
+
+impl Histogram {
+    pub fn mean(&self) -> f64 {
+        if self.count == 0 {
+            return 0.0;
+        }
+        self.sum as f64 / self.count as f64
+    }
+
+    /// The value that percentile percent of values are at or below, e.g. 99.0 for the p99. Only
+    /// as precise as the power of two bucket it falls in, rounded up
+    pub fn percentile(&self, percentile: f64) -> u64 {
+        if self.count == 0 {
+            return 0;
+        }
+        let rank = ((percentile / 100.0 * self.count as f64).ceil() as u64).clamp(1, self.count);
+        let mut counted = 0;
+        for (bucket, count) in self.buckets.iter().enumerate() {
+            counted += count;
+            if counted >= rank {
+                return bucket_upper_bound(bucket).min(self.max);
+            }
+        }
+        return self.max;
+    }
+}
+
+/// Records values into a histogram without taking a lock
+pub(crate) struct HistogramRecorder {
+    count: AtomicU64,
+    sum: AtomicU64,
+    max: AtomicU64,
+    buckets: Vec<AtomicU64>,
+}
+
+impl Default for HistogramRecorder {
+    fn default() -> Self {
+        HistogramRecorder {
+            count: AtomicU64::new(0),
+            sum: AtomicU64::new(0),
+            max: AtomicU64::new(0),
+            buckets: (0..HISTOGRAM_BUCKET_COUNT)
+                .map(|_| AtomicU64::new(0))
+                .collect(),
+        }
+    }
+}
+
+impl HistogramRecorder {
+    pub(crate) fn record(&self, value: u64) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum.fetch_add(value, Ordering::Relaxed);
+        self.max.fetch_max(value, Ordering::Relaxed);
+        self.buckets[bucket_for(value)].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn record_duration(&self, duration: Duration) {
+        self.record(duration.as_micros() as u64);
+    }
+
+    /// Values recorded while this runs may be only partly counted
+    fn snapshot(&self) -> Histogram {
+        Histogram {
+            count: self.count.load(Ordering::Relaxed),
+            sum: self.sum.load(Ordering::Relaxed),
+            max: self.max.load(Ordering::Relaxed),
+            buckets: self
+                .buckets
+                .iter()
+                .map(|bucket| bucket.load(Ordering::Relaxed))
+                .collect(),
+        }
+    }
+}
+
+/// Counters for everything a Store does, kept as atomics so counting never waits on anything
+#[derive(Default)]
+pub(crate) struct Statistics {
+    pub(crate) puts: AtomicU64,
+    pub(crate) removes: AtomicU64,
+    pub(crate) range_deletes: AtomicU64,
+    pub(crate) gets: AtomicU64,
+    pub(crate) mem_table_bytes: AtomicU64,
+    pub(crate) flush_bytes_written: AtomicU64,
+    pub(crate) compaction_bytes_read: AtomicU64,
+    pub(crate) compaction_bytes_written: AtomicU64,
+    pub(crate) wal_bytes_written: AtomicU64,
+    pub(crate) wal_syncs: AtomicU64,
+    pub(crate) flush_micros: HistogramRecorder,
+    pub(crate) compaction_micros: HistogramRecorder,
+    pub(crate) get_micros: HistogramRecorder,
+}
+
+impl Statistics {
+    pub(crate) fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub(crate) fn snapshot(
+        &self,
+        block_cache: BlockCacheStats,
+        store_file_counts: BTreeMap<String, usize>,
+    ) -> StoreStats {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StoreStats {
+            puts: load(&self.puts),
+            removes: load(&self.removes),
+            range_deletes: load(&self.range_deletes),
+            gets: load(&self.gets),
+            mem_table_bytes: load(&self.mem_table_bytes),
+            flush_bytes_written: load(&self.flush_bytes_written),
+            compaction_bytes_read: load(&self.compaction_bytes_read),
+            compaction_bytes_written: load(&self.compaction_bytes_written),
+            wal_bytes_written: load(&self.wal_bytes_written),
+            wal_syncs: load(&self.wal_syncs),
+            flush_micros: self.flush_micros.snapshot(),
+            compaction_micros: self.compaction_micros.snapshot(),
+            get_micros: self.get_micros.snapshot(),
+            block_cache,
+            store_file_counts,
+        }
+    }
+}
+
+/// What a Store has done since it was opened
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreStats {
+    pub puts: u64,
+    pub removes: u64,
+    pub range_deletes: u64,
+    pub gets: u64,
+    /// Bytes written to the mem tables since they were last flushed
+    pub mem_table_bytes: u64,
+    /// Bytes of store files written by flushes
+    pub flush_bytes_written: u64,
+    /// Bytes of store files merged by compactions
+    pub compaction_bytes_read: u64,
+    /// Bytes of store files written by compactions
+    pub compaction_bytes_written: u64,
+    pub wal_bytes_written: u64,
+    /// How many times the WAL was fsynced: after every write with `SyncMode::EveryWrite`, when it
+    /// is replaced after a flush and when the store is closed
+    pub wal_syncs: u64,
+    /// How long each flush of every column family's mem table took, so its count is the number of
+    /// flushes that wrote anything
+    pub flush_micros: Histogram,
+    /// How long each compaction of a column family took
+    pub compaction_micros: Histogram,
+    pub get_micros: Histogram,
+    pub block_cache: BlockCacheStats,
+    /// How many store files each column family has. Every store file in a column family is on the
+    /// same level, since compaction always merges all of them
+    pub store_file_counts: BTreeMap<String, usize>,
+}
+
+impl StoreStats {
+    /// Every number as a flat list of names, e.g. `get_micros.p99` or `store_files.default`, for
+    /// passing on to whatever collects metrics
+    pub fn to_map(&self) -> BTreeMap<String, u64> {
+        let mut map = BTreeMap::new();
+        for (name, value) in [
+            ("puts", self.puts),
+            ("removes", self.removes),
+            ("range_deletes", self.range_deletes),
+            ("gets", self.gets),
+            ("mem_table_bytes", self.mem_table_bytes),
+            ("flush_bytes_written", self.flush_bytes_written),
+            ("compaction_bytes_read", self.compaction_bytes_read),
+            ("compaction_bytes_written", self.compaction_bytes_written),
+            ("wal_bytes_written", self.wal_bytes_written),
+            ("wal_syncs", self.wal_syncs),
+            ("block_cache.hits", self.block_cache.hits),
+            ("block_cache.misses", self.block_cache.misses),
+            (
+                "block_cache.usage_in_bytes",
+                self.block_cache.usage_in_bytes,
+            ),
+            (
+                "block_cache.capacity_in_bytes",
+                self.block_cache.capacity_in_bytes,
+            ),
+        ] {
+            map.insert(name.to_string(), value);
+        }
+        for (name, histogram) in [
+            ("flush_micros", &self.flush_micros),
+            ("compaction_micros", &self.compaction_micros),
+            ("get_micros", &self.get_micros),
+        ] {
+            map.insert(format!("{}.count", name), histogram.count);
+            map.insert(format!("{}.sum", name), histogram.sum);
+            map.insert(format!("{}.max", name), histogram.max);
+            map.insert(format!("{}.p50", name), histogram.percentile(50.0));
+            map.insert(format!("{}.p99", name), histogram.percentile(99.0));
+        }
+        for (column_family, count) in &self.store_file_counts {
+            map.insert(format!("store_files.{}", column_family), *count as u64);
+        }
+        return map;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histograms_bucket_by_powers_of_two() {
+        let recorder = HistogramRecorder::default();
+        for value in 1..=100 {
+            recorder.record(value);
+        }
+        recorder.record(0);
+        let histogram = recorder.snapshot();
+        assert_eq!(histogram.count, 101);
+        assert_eq!(histogram.sum, 5050);
+        assert_eq!(histogram.max, 100);
+        assert_eq!(histogram.percentile(0.0), 0);
+        // The 51st value is 50, in the bucket of 32 to 63
+        assert_eq!(histogram.percentile(50.0), 63);
+        assert_eq!(histogram.percentile(100.0), 100);
+        assert_eq!(Histogram::default().percentile(99.0), 0);
+        assert_eq!(bucket_upper_bound(bucket_for(u64::MAX)), u64::MAX);
+    }
+}
@@ -0,0 +1,1155 @@
+use std::{
+    cmp::Ordering as KeyOrdering,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    ops::Deref,
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+use memmap2::Mmap;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    block_cache::BlockCache,
+    bloom::BloomFilter,
+    comparator::{check_comparator_name, default_comparator_name, Comparator},
+    file_cache::FileCache,
+    range_tombstone::RangeTombstone,
+    value_log::{ValueLog, ValueLogSegment, ValueLogWriter, ValuePointer},
+    Compression, Store, StoreOptions, TableEntry, DEFAULT_COLUMN_FAMILY, NO_EXPIRY,
+};
+
+/// The last 8 bytes of every store file
+const STORE_FILE_MAGIC: u64 = 0x6b76_7374_6f72_6531;
+
+/// Files with any other version are refused rather than misread. Store files from before this
+/// format, with no footer at all, are upgraded when their store is opened
+const STORE_FILE_FORMAT_VERSION: u32 = 1;
+
+/// Size of the codec tag and crc32 after every block
+const BLOCK_TRAILER_SIZE: usize = 5;
+
+/// Codec tags, saying how a block's contents were compressed
+const NO_COMPRESSION_TAG: u8 = 0;
+const LZ4_TAG: u8 = 1;
+const ZSTD_TAG: u8 = 2;
+
+/// Record kinds, saying what a record's value holds
+const VALUE_RECORD: u8 = 0;
+const TOMBSTONE_RECORD: u8 = 1;
+const VALUE_POINTER_RECORD: u8 = 2;
+
+/// Blocks are only kept compressed if it saves at least an eighth of their size
+const MIN_COMPRESSION_SAVING_DIVISOR: usize = 8;
+
+const BLOCK_HANDLE_SIZE: usize = 16;
+
+/// Filter, index and properties block handles, then the format version and magic number
+const FOOTER_SIZE: usize = BLOCK_HANDLE_SIZE * 3 + 4 + 8;
+
+/// Where a block is in its file. The size doesn't include the block's trailer
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct BlockHandle {
+    offset: u64,
+    size: u64,
+}
+
+impl BlockHandle {
+    fn encode_into(&self, bytes: &mut Vec<u8>) {
+        bytes.extend_from_slice(&self.offset.to_le_bytes());
+        bytes.extend_from_slice(&self.size.to_le_bytes());
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        BlockHandle {
+            offset: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            size: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+        }
+    }
+}
+
+/// The data block that holds every key up to and including last_key, that the previous data
+/// block doesn't
+#[derive(Debug, PartialEq)]
+struct IndexEntry {
+    last_key: Vec<u8>,
+    handle: BlockHandle,
+}
+
+/// Stats about a store file, worked out while it was written
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct StoreFileProperties {
+    pub(crate) entry_count: u64,
+    pub(crate) tombstone_count: u64,
+    pub(crate) data_block_count: u64,
+    pub(crate) raw_key_size: u64,
+    pub(crate) raw_value_size: u64,
+    pub(crate) smallest_key: Vec<u8>,
+    pub(crate) largest_key: Vec<u8>,
+    /// What the data blocks were compressed with, when they compressed well enough to be worth it
+    #[serde(default)]
+    pub(crate) compression: Compression,
+    /// Every value log segment this file points into
+    #[serde(default)]
+    pub(crate) value_log_segment_ids: Vec<u64>,
+    /// Name of the comparator the keys are ordered by
+    #[serde(default = "default_comparator_name")]
+    pub(crate) comparator: String,
+    /// Ranges deleted from older store files. There are few enough of them to keep in memory
+    /// along with the rest of the properties
+    #[serde(default)]
+    pub(crate) range_tombstones: Vec<RangeTombstone>,
+}
+
+/// What's in one store file, worked out from its properties without reading any data blocks
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct StoreFileStats {
+    pub file_id: u64,
+    pub size_in_bytes: u64,
+    /// Every entry in the file, tombstones included
+    pub entry_count: u64,
+    pub tombstone_count: u64,
+    pub range_tombstone_count: u64,
+    pub smallest_key: Vec<u8>,
+    pub largest_key: Vec<u8>,
+}
+
+fn corrupt_store_file(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Store file {:?} is corrupt: {}", path, reason),
+    )
+}
+
+/// Writes a store file from entries added in the comparator's key order. The file is laid out as:
+///     data blocks, each made of a record kind followed by a key value record as written to the
+///         WAL
+///     filter block, a bloom filter over every key
+///     index block, the last key and handle of every data block
+///     properties block, StoreFileProperties as JSON
+///     footer, the handles of the three blocks above, the format version and a magic number
+///
+/// Every block is followed by a codec tag, saying how the block was compressed, and a crc32 of the
+/// (possibly compressed) contents and the tag. Only data blocks are ever compressed. Tombstones are
+/// written with an empty value, and values kept in the value log with an encoded ValuePointer.
+///
+/// Store files can also be built outside of a Store, from keys that are already sorted, and then
+/// added to a Store with `Store::ingest_files`. That skips the WAL and mem tables altogether:
+///
+/// ```
+/// # let dir = std::env::temp_dir().join("store_file_writer_doc");
+/// # let _ = std::fs::remove_dir_all(&dir);
+/// # std::fs::create_dir_all(&dir).unwrap();
+/// use store::{Store, StoreFileWriter, StoreOptions};
+///
+/// let options = StoreOptions::new();
+/// let path = dir.join("nightly.sst");
+/// let mut writer = StoreFileWriter::new(&path, &options).unwrap();
+/// writer.put(b"apple", b"1").unwrap();
+/// writer.put(b"banana", b"2").unwrap();
+/// writer.finish().unwrap();
+///
+/// let store = Store::open(&dir.join("store"), &options).unwrap();
+/// store.ingest_files(&[path]).unwrap();
+/// assert_eq!(store.get(b"banana").unwrap(), Some(b"2".to_vec()));
+/// ```
+pub struct StoreFileWriter {
+    path: PathBuf,
+    writer: BufWriter<File>,
+    block_size: usize,
+    compression: Compression,
+    bloom_bits_per_key: u32,
+    offset: u64,
+    current_block: Vec<u8>,
+    last_key: Vec<u8>,
+    index: Vec<IndexEntry>,
+    keys: Vec<Vec<u8>>,
+    properties: StoreFileProperties,
+    value_log: Option<ValueLogWriter>,
+    value_log_threshold_in_bytes: Option<u64>,
+    comparator: Arc<dyn Comparator>,
+}
+
+impl StoreFileWriter {
+    /// Writes a store file for the default column family, laid out and compressed according to
+    /// options
+    pub fn new(path: &Path, options: &StoreOptions) -> io::Result<Self> {
+        Self::new_cf(path, options, DEFAULT_COLUMN_FAMILY)
+    }
+
+    /// Writes a store file for the named column family, with keys in the order of the comparator
+    /// options has for it
+    pub fn new_cf(path: &Path, options: &StoreOptions, column_family: &str) -> io::Result<Self> {
+        Self::create(
+            path,
+            options,
+            options.compression,
+            &options.comparators.for_column_family(column_family),
+        )
+    }
+
+    /// Keys must be added in ascending order, and only once each. Fails with
+    /// `ErrorKind::InvalidInput` otherwise
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> io::Result<()> {
+        self.add(
+            key,
+            &TableEntry::Populated {
+                value: value.to_vec(),
+                expires_at: NO_EXPIRY,
+            },
+        )
+    }
+
+    /// Hides the key in whatever the Store held before the file was ingested
+    pub fn remove(&mut self, key: &[u8]) -> io::Result<()> {
+        self.add(key, &TableEntry::Tombstone)
+    }
+
+    pub(crate) fn create(
+        path: &Path,
+        options: &StoreOptions,
+        compression: Compression,
+        comparator: &Arc<dyn Comparator>,
+    ) -> io::Result<Self> {
+        let file = File::create(path)?;
+        Ok(StoreFileWriter {
+            path: path.to_path_buf(),
+            writer: BufWriter::new(file),
+            block_size: options.block_size,
+            compression,
+            bloom_bits_per_key: options.bloom_bits_per_key,
+            offset: 0,
+            current_block: Vec::new(),
+            last_key: Vec::new(),
+            index: Vec::new(),
+            keys: Vec::new(),
+            properties: StoreFileProperties {
+                compression,
+                comparator: comparator.name().to_string(),
+                ..Default::default()
+            },
+            value_log: None,
+            value_log_threshold_in_bytes: options.value_log_threshold_in_bytes,
+            comparator: comparator.clone(),
+        })
+    }
+
+    /// Values over the value log threshold are written to value_log rather than the store file
+    pub(crate) fn with_value_log(mut self, value_log: ValueLogWriter) -> Self {
+        self.value_log = Some(value_log);
+        self
+    }
+
+    fn uses_value_log_segment(&mut self, segment_id: u64) {
+        if !self.properties.value_log_segment_ids.contains(&segment_id) {
+            self.properties.value_log_segment_ids.push(segment_id);
+        }
+    }
+
+    /// Keys must be added in ascending order, and only once each
+    pub(crate) fn add(&mut self, key: &[u8], entry: &TableEntry) -> io::Result<()> {
+        if self.properties.entry_count > 0
+            && self.comparator.compare(key, &self.last_key) != KeyOrdering::Greater
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Keys must be added to {:?} in ascending order", self.path),
+            ));
+        }
+        let mut pointer = None;
+        let (kind, value, expires_at) = match entry {
+            TableEntry::Tombstone => {
+                self.properties.tombstone_count += 1;
+                (TOMBSTONE_RECORD, None, NO_EXPIRY)
+            }
+            TableEntry::Populated { value, expires_at } => {
+                match (&mut self.value_log, self.value_log_threshold_in_bytes) {
+                    (Some(value_log), Some(threshold)) if value.len() as u64 >= threshold => {
+                        pointer = Some(value_log.append(value)?);
+                        (VALUE_POINTER_RECORD, None, *expires_at)
+                    }
+                    _ => (VALUE_RECORD, Some(value.as_slice()), *expires_at),
+                }
+            }
+            TableEntry::InValueLog {
+                pointer: existing,
+                expires_at,
+            } => {
+                pointer = Some(*existing);
+                (VALUE_POINTER_RECORD, None, *expires_at)
+            }
+            TableEntry::RangeTombstone { .. } => {
+                unreachable!("Range tombstones are added with add_range_tombstone")
+            }
+        };
+        let encoded_pointer = pointer.map(|pointer| pointer.encode());
+        let value = match &encoded_pointer {
+            Some(encoded_pointer) => Some(encoded_pointer.as_slice()),
+            None => value,
+        };
+        let value_size = value.map_or(0, |value| value.len());
+        self.current_block.push(kind);
+        Store::append_kv_to_file(&mut self.current_block, key, expires_at, value);
+        let value_size = match pointer {
+            Some(pointer) => {
+                self.uses_value_log_segment(pointer.segment_id);
+                pointer.size as usize
+            }
+            None => value_size,
+        };
+
+        if self.properties.entry_count == 0 {
+            self.properties.smallest_key = key.to_vec();
+        }
+        self.properties.entry_count += 1;
+        self.properties.raw_key_size += key.len() as u64;
+        self.properties.raw_value_size += value_size as u64;
+        self.last_key = key.to_vec();
+        if self.bloom_bits_per_key > 0 {
+            self.keys.push(key.to_vec());
+        }
+
+        if self.current_block.len() >= self.block_size {
+            self.finish_data_block()?;
+        }
+        Ok(())
+    }
+
+    /// Hides the range in store files older than this one. Can be added at any point, since range
+    /// tombstones are kept apart from the data blocks
+    pub(crate) fn add_range_tombstone(&mut self, tombstone: RangeTombstone) {
+        self.properties.range_tombstones.push(tombstone);
+    }
+
+    /// Returns the codec tag and contents to write for a block
+    fn compress_block(contents: &[u8], compression: Compression) -> io::Result<(u8, Vec<u8>)> {
+        let (tag, compressed) = match compression {
+            Compression::None => return Ok((NO_COMPRESSION_TAG, contents.to_vec())),
+            Compression::Lz4 => (LZ4_TAG, lz4_flex::compress_prepend_size(contents)),
+            Compression::Zstd => (ZSTD_TAG, zstd::encode_all(contents, 0)?),
+        };
+        if compressed.len() > contents.len() - contents.len() / MIN_COMPRESSION_SAVING_DIVISOR {
+            // Not worth paying for decompression on every read
+            return Ok((NO_COMPRESSION_TAG, contents.to_vec()));
+        }
+        Ok((tag, compressed))
+    }
+
+    fn write_block(
+        &mut self,
+        contents: &[u8],
+        compression: Compression,
+    ) -> io::Result<BlockHandle> {
+        let (tag, contents) = Self::compress_block(contents, compression)?;
+        let handle = BlockHandle {
+            offset: self.offset,
+            size: contents.len() as u64,
+        };
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(&contents);
+        hasher.update(&[tag]);
+        self.writer.write_all(&contents)?;
+        self.writer.write_all(&[tag])?;
+        self.writer.write_all(&hasher.finalize().to_le_bytes())?;
+        self.offset += (contents.len() + BLOCK_TRAILER_SIZE) as u64;
+        Ok(handle)
+    }
+
+    fn finish_data_block(&mut self) -> io::Result<()> {
+        if self.current_block.is_empty() {
+            return Ok(());
+        }
+        let block = std::mem::take(&mut self.current_block);
+        let handle = self.write_block(&block, self.compression)?;
+        self.index.push(IndexEntry {
+            last_key: self.last_key.clone(),
+            handle,
+        });
+        self.properties.data_block_count += 1;
+        Ok(())
+    }
+
+    /// Writes out everything after the data blocks, and makes sure the file is on disk
+    pub fn finish(mut self) -> io::Result<()> {
+        if let Some(value_log) = self.value_log.take() {
+            value_log.finish()?;
+        }
+        self.finish_data_block()?;
+        self.properties.largest_key = self.last_key.clone();
+
+        let filter = if self.bloom_bits_per_key > 0 {
+            let filter = BloomFilter::build(
+                self.keys.iter().map(|key| key.as_slice()),
+                self.bloom_bits_per_key,
+            );
+            filter.encode()
+        } else {
+            Vec::new()
+        };
+        let filter_handle = self.write_block(&filter, Compression::None)?;
+
+        let mut index = Vec::new();
+        for index_entry in &self.index {
+            index.extend_from_slice(&(index_entry.last_key.len() as u32).to_le_bytes());
+            index.extend_from_slice(&index_entry.last_key);
+            index_entry.handle.encode_into(&mut index);
+        }
+        let index_handle = self.write_block(&index, Compression::None)?;
+
+        let properties = serde_json::to_vec(&self.properties)?;
+        let properties_handle = self.write_block(&properties, Compression::None)?;
+
+        let mut footer = Vec::with_capacity(FOOTER_SIZE);
+        filter_handle.encode_into(&mut footer);
+        index_handle.encode_into(&mut footer);
+        properties_handle.encode_into(&mut footer);
+        footer.extend_from_slice(&STORE_FILE_FORMAT_VERSION.to_le_bytes());
+        footer.extend_from_slice(&STORE_FILE_MAGIC.to_le_bytes());
+        self.writer.write_all(&footer)?;
+
+        self.writer.flush()?;
+        self.writer.get_ref().sync_all()
+    }
+}
+
+/// Caches shared by every store file in a Store, along with how the files should be read
+pub(crate) struct StoreFileCaches {
+    pub(crate) blocks: BlockCache,
+    pub(crate) files: FileCache,
+    mmap_reads: bool,
+}
+
+impl StoreFileCaches {
+    pub(crate) fn new(options: &StoreOptions) -> Self {
+        StoreFileCaches {
+            blocks: BlockCache::new(options.block_cache_size_in_bytes),
+            files: FileCache::new(options.max_open_files),
+            mmap_reads: options.mmap_reads,
+        }
+    }
+}
+
+/// A data block, either read into memory or still in a store file's mapping
+enum Block<'a> {
+    Read(Arc<Vec<u8>>),
+    Mapped(&'a [u8]),
+}
+
+impl Deref for Block<'_> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        match self {
+            Block::Read(block) => block,
+            Block::Mapped(block) => block,
+        }
+    }
+}
+
+/// An immutable store file along with its index. Readers hold on to these through an `Arc`, so a
+/// compaction can replace a file without pulling it out from under a read that is still using it
+pub(crate) struct StoreFile {
+    pub(crate) id: u64,
+    pub(crate) path: PathBuf,
+    pub(crate) size_in_bytes: u64,
+    index: Vec<IndexEntry>,
+    filter: Option<BloomFilter>,
+    pub(crate) properties: StoreFileProperties,
+    /// Read only stores keep every file they use open, so reads keep working after the Store
+    /// that has the directory open for writing deletes the file
+    pinned_file: Option<File>,
+    /// Kept alive for as long as this file might need to read values out of them
+    value_log_segments: Vec<Arc<ValueLogSegment>>,
+    /// The whole file, when the store is opened with mmap reads. The mapping stays valid after
+    /// the file is deleted, and isn't unmapped until the last reader holding this file is done
+    mmap: Option<Mmap>,
+    caches: Arc<StoreFileCaches>,
+    /// What this file's blocks and handle are cached under, since `id` is only unique within a
+    /// column family
+    cache_id: u64,
+    comparator: Arc<dyn Comparator>,
+    obsolete: AtomicBool,
+}
+
+impl StoreFile {
+    /// Only reads the footer and the blocks it points at, the data blocks are read as needed
+    pub(crate) fn open(
+        id: u64,
+        path: PathBuf,
+        pin_file: bool,
+        caches: &Arc<StoreFileCaches>,
+        value_log: &ValueLog,
+        comparator: &Arc<dyn Comparator>,
+    ) -> io::Result<Self> {
+        let file = File::open(&path)?;
+        let file_size = file.metadata()?.len();
+        if file_size < FOOTER_SIZE as u64 {
+            return Err(corrupt_store_file(&path, "too small to have a footer"));
+        }
+        let mut footer = [0; FOOTER_SIZE];
+        file.read_exact_at(&mut footer, file_size - FOOTER_SIZE as u64)?;
+        let magic = u64::from_le_bytes(footer[FOOTER_SIZE - 8..].try_into().unwrap());
+        if magic != STORE_FILE_MAGIC {
+            return Err(corrupt_store_file(&path, "bad magic number"));
+        }
+        let version = u32::from_le_bytes(
+            footer[BLOCK_HANDLE_SIZE * 3..BLOCK_HANDLE_SIZE * 3 + 4]
+                .try_into()
+                .unwrap(),
+        );
+        if version != STORE_FILE_FORMAT_VERSION {
+            return Err(corrupt_store_file(
+                &path,
+                &format!("unsupported format version {}", version),
+            ));
+        }
+        let filter_handle = BlockHandle::decode(&footer[0..BLOCK_HANDLE_SIZE]);
+        let index_handle = BlockHandle::decode(&footer[BLOCK_HANDLE_SIZE..BLOCK_HANDLE_SIZE * 2]);
+        let properties_handle =
+            BlockHandle::decode(&footer[BLOCK_HANDLE_SIZE * 2..BLOCK_HANDLE_SIZE * 3]);
+
+        let filter_block = Self::read_block_from(&file, &path, filter_handle)?;
+        let filter = BloomFilter::decode(&filter_block);
+
+        let index_block = Self::read_block_from(&file, &path, index_handle)?;
+        let mut index = Vec::new();
+        let mut offset = 0;
+        while offset < index_block.len() {
+            if offset + 4 > index_block.len() {
+                return Err(corrupt_store_file(&path, "truncated index entry"));
+            }
+            let key_size =
+                u32::from_le_bytes(index_block[offset..offset + 4].try_into().unwrap()) as usize;
+            offset += 4;
+            if offset + key_size + BLOCK_HANDLE_SIZE > index_block.len() {
+                return Err(corrupt_store_file(&path, "truncated index entry"));
+            }
+            let last_key = index_block[offset..offset + key_size].to_vec();
+            offset += key_size;
+            let handle = BlockHandle::decode(&index_block[offset..offset + BLOCK_HANDLE_SIZE]);
+            offset += BLOCK_HANDLE_SIZE;
+            index.push(IndexEntry { last_key, handle });
+        }
+
+        let properties_block = Self::read_block_from(&file, &path, properties_handle)?;
+        let properties: StoreFileProperties = serde_json::from_slice(&properties_block)
+            .map_err(|_| corrupt_store_file(&path, "unreadable properties block"))?;
+        check_comparator_name(
+            &format!("Store file {:?}", path),
+            &properties.comparator,
+            comparator.as_ref(),
+        )?;
+        let value_log_segments = properties
+            .value_log_segment_ids
+            .iter()
+            .map(|id| value_log.segment(*id))
+            .collect::<io::Result<_>>()?;
+
+        let mmap = if caches.mmap_reads {
+            // Safe as long as nothing writes to the file while it's mapped. Store files are never
+            // changed once written, only deleted, which leaves existing mappings alone
+            Some(unsafe { Mmap::map(&file)? })
+        } else {
+            None
+        };
+        let pinned_file = if pin_file && mmap.is_none() {
+            Some(file)
+        } else {
+            None
+        };
+        Ok(StoreFile {
+            id,
+            path,
+            size_in_bytes: file_size,
+            index,
+            filter,
+            properties,
+            pinned_file,
+            value_log_segments,
+            mmap,
+            caches: caches.clone(),
+            cache_id: caches.blocks.new_file_id(),
+            comparator: comparator.clone(),
+            obsolete: AtomicBool::new(false),
+        })
+    }
+
+    /// Whether the file ends in this format's magic number, unlike store files from before they
+    /// had footers
+    pub(crate) fn has_magic_number(path: &Path) -> io::Result<bool> {
+        let file = File::open(path)?;
+        let file_size = file.metadata()?.len();
+        if file_size < 8 {
+            return Ok(false);
+        }
+        let mut magic = [0; 8];
+        file.read_exact_at(&mut magic, file_size - 8)?;
+        Ok(u64::from_le_bytes(magic) == STORE_FILE_MAGIC)
+    }
+
+    /// Checks the crc32 of a block read along with its trailer, and splits off its codec tag
+    fn verify_block<'a>(
+        raw_block: &'a [u8],
+        path: &Path,
+        handle: BlockHandle,
+    ) -> io::Result<(u8, &'a [u8])> {
+        let (block, checksum) = raw_block.split_at(raw_block.len() - 4);
+        let checksum = u32::from_le_bytes(checksum.try_into().unwrap());
+        if crc32fast::hash(block) != checksum {
+            return Err(corrupt_store_file(
+                path,
+                &format!("checksum mismatch in block at offset {}", handle.offset),
+            ));
+        }
+        let (contents, tag) = block.split_at(block.len() - 1);
+        Ok((tag[0], contents))
+    }
+
+    fn decompress_block(
+        tag: u8,
+        contents: &[u8],
+        path: &Path,
+        handle: BlockHandle,
+    ) -> io::Result<Vec<u8>> {
+        let decompression_failed = || {
+            corrupt_store_file(
+                path,
+                &format!("block at offset {} won't decompress", handle.offset),
+            )
+        };
+        match tag {
+            NO_COMPRESSION_TAG => Ok(contents.to_vec()),
+            LZ4_TAG => {
+                lz4_flex::decompress_size_prepended(contents).map_err(|_| decompression_failed())
+            }
+            ZSTD_TAG => zstd::decode_all(contents).map_err(|_| decompression_failed()),
+            _ => Err(corrupt_store_file(
+                path,
+                &format!(
+                    "unknown codec tag {} for block at offset {}",
+                    tag, handle.offset
+                ),
+            )),
+        }
+    }
+
+    /// Returns the block's contents, decompressed
+    fn read_block_from(file: &File, path: &Path, handle: BlockHandle) -> io::Result<Vec<u8>> {
+        // Handles come from the footer and index, so a damaged one mustn't get a huge buffer
+        // allocated for it
+        let end = handle
+            .offset
+            .checked_add(handle.size)
+            .and_then(|end| end.checked_add(BLOCK_TRAILER_SIZE as u64));
+        let file_size = file.metadata()?.len();
+        if end.is_none_or(|end| end > file_size) {
+            return Err(corrupt_store_file(
+                path,
+                &format!("block at offset {} runs past the end", handle.offset),
+            ));
+        }
+        let mut raw_block = vec![0; handle.size as usize + BLOCK_TRAILER_SIZE];
+        file.read_exact_at(&mut raw_block, handle.offset)?;
+        let (tag, contents) = Self::verify_block(&raw_block, path, handle)?;
+        if tag == NO_COMPRESSION_TAG {
+            // Saves copying the contents out
+            raw_block.truncate(contents.len());
+            return Ok(raw_block);
+        }
+        Self::decompress_block(tag, contents, path, handle)
+    }
+
+    /// Uncompressed blocks are read straight out of the mapping. Compressed blocks still have to
+    /// be decompressed, so those go through the block cache like any other read
+    fn mapped_block<'a>(
+        &self,
+        mmap: &'a Mmap,
+        handle: BlockHandle,
+        fill_cache: bool,
+    ) -> io::Result<Block<'a>> {
+        let end = (handle.offset as usize)
+            .checked_add(handle.size as usize)
+            .and_then(|end| end.checked_add(BLOCK_TRAILER_SIZE));
+        let Some(end) = end.filter(|end| *end <= mmap.len()) else {
+            return Err(corrupt_store_file(
+                &self.path,
+                &format!("block at offset {} runs past the end", handle.offset),
+            ));
+        };
+        let raw_block = &mmap[handle.offset as usize..end];
+        let (tag, contents) = Self::verify_block(raw_block, &self.path, handle)?;
+        if tag == NO_COMPRESSION_TAG {
+            return Ok(Block::Mapped(contents));
+        }
+        if let Some(block) = self.caches.blocks.get(self.cache_id, handle.offset) {
+            return Ok(Block::Read(block));
+        }
+        let block = Arc::new(Self::decompress_block(tag, contents, &self.path, handle)?);
+        if fill_cache {
+            self.caches
+                .blocks
+                .insert(self.cache_id, handle.offset, block.clone());
+        }
+        Ok(Block::Read(block))
+    }
+
+    /// Reads through the block cache, only adding the block to it if fill_cache is set
+    fn read_block(&self, handle: BlockHandle, fill_cache: bool) -> io::Result<Block<'_>> {
+        if let Some(mmap) = &self.mmap {
+            return self.mapped_block(mmap, handle, fill_cache);
+        }
+        if fill_cache {
+            if let Some(block) = self.caches.blocks.get(self.cache_id, handle.offset) {
+                return Ok(Block::Read(block));
+            }
+        }
+        let block = Arc::new(match &self.pinned_file {
+            Some(file) => Self::read_block_from(file, &self.path, handle)?,
+            None => {
+                let file = self.caches.files.get_or_open(self.cache_id, &self.path)?;
+                Self::read_block_from(&file, &self.path, handle)?
+            }
+        });
+        if fill_cache {
+            self.caches
+                .blocks
+                .insert(self.cache_id, handle.offset, block.clone());
+        }
+        Ok(Block::Read(block))
+    }
+
+    /// Calls f with every entry in the block, in key order. Stops early if f returns false
+    fn for_each_in_block(
+        &self,
+        block: &[u8],
+        f: &mut dyn FnMut(Vec<u8>, TableEntry) -> bool,
+    ) -> io::Result<()> {
+        let mut offset = 0;
+        while offset < block.len() {
+            let kind = block[offset];
+            offset += 1;
+            let kv = Store::parse_key_value_from_bytes(&mut offset, block)
+                .map_err(|e| corrupt_store_file(&self.path, &e.to_string()))?;
+            let entry = match kind {
+                TOMBSTONE_RECORD => TableEntry::Tombstone,
+                VALUE_RECORD => TableEntry::Populated {
+                    value: kv.value,
+                    expires_at: kv.expires_at,
+                },
+                VALUE_POINTER_RECORD => TableEntry::InValueLog {
+                    pointer: ValuePointer::decode(&kv.value).ok_or_else(|| {
+                        corrupt_store_file(&self.path, "unreadable value pointer")
+                    })?,
+                    expires_at: kv.expires_at,
+                },
+                kind => {
+                    return Err(corrupt_store_file(
+                        &self.path,
+                        &format!("unknown record kind {}", kind),
+                    ))
+                }
+            };
+            if !f(kv.key, entry) {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads a value this file points to out of the value log
+    pub(crate) fn read_value(&self, pointer: &ValuePointer) -> io::Result<Vec<u8>> {
+        let segment = self
+            .value_log_segments
+            .iter()
+            .find(|segment| segment.id == pointer.segment_id)
+            .ok_or_else(|| {
+                corrupt_store_file(
+                    &self.path,
+                    &format!(
+                        "points into value log segment {} without listing it",
+                        pointer.segment_id
+                    ),
+                )
+            })?;
+        segment.read(pointer)
+    }
+
+    pub(crate) fn value_log_segments(&self) -> &[Arc<ValueLogSegment>] {
+        &self.value_log_segments
+    }
+
+    pub(crate) fn get(&self, key: &[u8]) -> io::Result<Option<TableEntry>> {
+        if self.properties.entry_count == 0
+            || self.comparator.compare(key, &self.properties.smallest_key) == KeyOrdering::Less
+        {
+            return Ok(None);
+        }
+        if let Some(filter) = &self.filter {
+            if !filter.may_contain(key) {
+                return Ok(None);
+            }
+        }
+        // The first block whose last key isn't before our key is the only one that can have it
+        let block_index = self.index.partition_point(|index_entry| {
+            self.comparator.compare(&index_entry.last_key, key) == KeyOrdering::Less
+        });
+        let Some(index_entry) = self.index.get(block_index) else {
+            return Ok(None);
+        };
+
+        let block = self.read_block(index_entry.handle, true)?;
+        let mut found = None;
+        self.for_each_in_block(&block, &mut |entry_key, entry| {
+            if self.comparator.compare(&entry_key, key) == KeyOrdering::Less {
+                return true;
+            }
+            if entry_key == key {
+                found = Some(entry);
+            }
+            false
+        })?;
+        Ok(found)
+    }
+
+    pub(crate) fn stats(&self) -> StoreFileStats {
+        StoreFileStats {
+            file_id: self.id,
+            size_in_bytes: self.size_in_bytes,
+            entry_count: self.properties.entry_count,
+            tombstone_count: self.properties.tombstone_count,
+            range_tombstone_count: self.properties.range_tombstones.len() as u64,
+            smallest_key: self.properties.smallest_key.clone(),
+            largest_key: self.properties.largest_key.clone(),
+        }
+    }
+
+    /// Bytes of data blocks that may hold keys from start up to, but not including, end. Goes by
+    /// the index alone, so blocks only partly in the range count in full
+    pub(crate) fn approximate_size_of_range(&self, start: &[u8], end: &[u8]) -> u64 {
+        let mut size = 0;
+        let mut previous_last_key: Option<&[u8]> = None;
+        for index_entry in &self.index {
+            // The block holds the keys after the previous block's last key, up to its own
+            let ends_before_range =
+                self.comparator.compare(&index_entry.last_key, start) == KeyOrdering::Less;
+            let starts_after_range = previous_last_key.is_some_and(|previous_last_key| {
+                self.comparator.compare(previous_last_key, end) != KeyOrdering::Less
+            });
+            if starts_after_range {
+                break;
+            }
+            if !ends_before_range {
+                size += index_entry.handle.size;
+            }
+            previous_last_key = Some(&index_entry.last_key);
+        }
+        return size;
+    }
+
+    /// Whether one of this file's range tombstones hides the key in older store files
+    pub(crate) fn range_deletes(&self, key: &[u8]) -> bool {
+        self.properties
+            .range_tombstones
+            .iter()
+            .any(|tombstone| tombstone.covers(key, self.comparator.as_ref()))
+    }
+
+    /// Calls f with every entry in the file, in key order. Skips the block cache, so reading a
+    /// whole file for a compaction doesn't push out the blocks that reads are using
+    pub(crate) fn for_each_entry(&self, f: &mut dyn FnMut(Vec<u8>, TableEntry)) -> io::Result<()> {
+        for index_entry in &self.index {
+            let block = self.read_block(index_entry.handle, false)?;
+            self.for_each_in_block(&block, &mut |key, entry| {
+                f(key, entry);
+                true
+            })?;
+        }
+        Ok(())
+    }
+
+    /// Every entry in the file, in key order. Only one block is held in memory at a time, and the
+    /// block cache is skipped same as in for_each_entry
+    pub(crate) fn entries(&self) -> StoreFileEntries<'_> {
+        StoreFileEntries {
+            file: self,
+            next_block: 0,
+            block_entries: Vec::new().into_iter(),
+        }
+    }
+
+    /// Reads every entry in the file, checking each data block's checksum, that keys are in order,
+    /// that the index, bloom filter and properties agree with the data blocks and that values in
+    /// the value log are readable. Calls f with every entry that is intact, in key order, and
+    /// returns what's wrong with the rest of the file. Entries out of order, and those whose value
+    /// can't be read, are left out along with every entry in a damaged block
+    pub(crate) fn verify_entries(&self, f: &mut dyn FnMut(Vec<u8>, TableEntry)) -> Vec<String> {
+        let mut problems = Vec::new();
+        let mut last_key: Option<Vec<u8>> = None;
+        let mut entry_count = 0;
+        for index_entry in &self.index {
+            let block = match self.read_block(index_entry.handle, false) {
+                Ok(block) => block,
+                Err(e) => {
+                    problems.push(e.to_string());
+                    continue;
+                }
+            };
+            let mut block_last_key = None;
+            let result = self.for_each_in_block(&block, &mut |key, entry| {
+                entry_count += 1;
+                block_last_key = Some(key.clone());
+                if let Some(last_key) = &last_key {
+                    if self.comparator.compare(&key, last_key) != KeyOrdering::Greater {
+                        problems.push(format!("key {:?} is out of order", key));
+                        return true;
+                    }
+                }
+                if let Some(filter) = &self.filter {
+                    if !filter.may_contain(&key) {
+                        problems.push(format!("the bloom filter is missing key {:?}", key));
+                    }
+                }
+                if let TableEntry::InValueLog { pointer, .. } = &entry {
+                    if let Err(e) = self.read_value(pointer) {
+                        problems.push(e.to_string());
+                        return true;
+                    }
+                }
+                last_key = Some(key.clone());
+                f(key, entry);
+                true
+            });
+            if let Err(e) = result {
+                problems.push(e.to_string());
+            } else if block_last_key.as_ref() != Some(&index_entry.last_key) {
+                problems.push(format!(
+                    "the index doesn't match the block at offset {}",
+                    index_entry.handle.offset
+                ));
+            }
+        }
+        if entry_count != self.properties.entry_count {
+            problems.push(format!(
+                "has {} entries, but its properties say {}",
+                entry_count, self.properties.entry_count
+            ));
+        }
+        return problems;
+    }
+
+    /// The file is deleted from disk once the last reader using it is done
+    pub(crate) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+pub(crate) struct StoreFileEntries<'a> {
+    file: &'a StoreFile,
+    next_block: usize,
+    block_entries: std::vec::IntoIter<(Vec<u8>, TableEntry)>,
+}
+
+impl Iterator for StoreFileEntries<'_> {
+    type Item = io::Result<(Vec<u8>, TableEntry)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some(entry) = self.block_entries.next() {
+                return Some(Ok(entry));
+            }
+            let index_entry = self.file.index.get(self.next_block)?;
+            self.next_block += 1;
+            let mut entries = Vec::new();
+            let read = self
+                .file
+                .read_block(index_entry.handle, false)
+                .and_then(|block| {
+                    self.file.for_each_in_block(&block, &mut |key, entry| {
+                        entries.push((key, entry));
+                        true
+                    })
+                });
+            if let Err(e) = read {
+                // Nothing after a damaged block is returned either
+                self.next_block = self.file.index.len();
+                return Some(Err(e));
+            }
+            self.block_entries = entries.into_iter();
+        }
+    }
+}
+
+impl Drop for StoreFile {
+    fn drop(&mut self) {
+        self.caches.files.evict(self.cache_id);
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                eprintln!(
+                    "Failed to remove compacted store file {:?}: {}",
+                    self.path, e
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::comparator::BytewiseComparator;
+
+    const TEMP_TEST_FILE_DIR: &str = "./tmp_test_files/";
+
+    #[test]
+    fn store_files_are_read_back_block_by_block() {
+        let test_dir = Path::new(TEMP_TEST_FILE_DIR).join("store-file/blocks");
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let path = test_dir.join("1.store.kv");
+
+        // Values from 10 on go to the value log
+        let options = StoreOptions::new()
+            .block_size(64)
+            .value_log_threshold_in_bytes(2);
+        let caches = Arc::new(StoreFileCaches::new(&StoreOptions::new()));
+        let value_log = ValueLog::open(&test_dir, false, &caches).unwrap();
+        let bytewise: Arc<dyn Comparator> = Arc::new(BytewiseComparator);
+        let mut writer = StoreFileWriter::create(&path, &options, Compression::None, &bytewise)
+            .unwrap()
+            .with_value_log(value_log.new_segment());
+        for i in 0..100_u32 {
+            let entry = if i % 10 == 0 {
+                TableEntry::Tombstone
+            } else {
+                TableEntry::Populated {
+                    value: i.to_string().into_bytes(),
+                    expires_at: NO_EXPIRY,
+                }
+            };
+            writer.add(&i.to_be_bytes(), &entry).unwrap();
+        }
+        assert!(writer
+            .add(&0_u32.to_be_bytes(), &TableEntry::Tombstone)
+            .is_err());
+        writer.finish().unwrap();
+
+        let store_file =
+            StoreFile::open(1, path.clone(), false, &caches, &value_log, &bytewise).unwrap();
+        assert!(store_file.properties.data_block_count > 1);
+        assert_eq!(store_file.properties.value_log_segment_ids, vec![1]);
+        assert_eq!(store_file.properties.entry_count, 100);
+        assert_eq!(store_file.properties.tombstone_count, 10);
+        assert_eq!(store_file.properties.largest_key, 99_u32.to_be_bytes());
+        for i in 0..100_u32 {
+            let entry = store_file.get(&i.to_be_bytes()).unwrap();
+            match entry {
+                Some(TableEntry::Tombstone) => assert_eq!(i % 10, 0),
+                Some(TableEntry::Populated { value, .. }) => {
+                    assert!(i < 10);
+                    assert_eq!(value, i.to_string().into_bytes())
+                }
+                Some(TableEntry::InValueLog { pointer, .. }) => {
+                    assert!(i >= 10);
+                    let value = store_file.read_value(&pointer).unwrap();
+                    assert_eq!(value, i.to_string().into_bytes())
+                }
+                entry => panic!("Key {} read back as {:?}", i, entry),
+            }
+        }
+        assert!(store_file.get(&100_u32.to_be_bytes()).unwrap().is_none());
+        let mut entry_count = 0;
+        store_file
+            .for_each_entry(&mut |_, _| entry_count += 1)
+            .unwrap();
+        assert_eq!(entry_count, 100);
+
+        // Flip a byte in the first data block
+        let mut bytes = fs::read(&path).unwrap();
+        bytes[5] ^= 0xff;
+        fs::write(&path, bytes).unwrap();
+        let store_file = StoreFile::open(1, path, false, &caches, &value_log, &bytewise).unwrap();
+        let err = store_file.get(&1_u32.to_be_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        // Make the first record's key run past the end of its block, and fix up the checksum so
+        // only parsing the block can tell
+        let handle = store_file.index[0].handle;
+        let mut bytes = fs::read(&store_file.path).unwrap();
+        bytes[5] ^= 0xff;
+        bytes[1] = 0x7f;
+        let checked_end = (handle.offset + handle.size) as usize + 1;
+        let checksum = crc32fast::hash(&bytes[handle.offset as usize..checked_end]);
+        bytes[checked_end..checked_end + 4].copy_from_slice(&checksum.to_le_bytes());
+        fs::write(&store_file.path, bytes).unwrap();
+        let store_file = StoreFile::open(
+            1,
+            store_file.path.clone(),
+            false,
+            &caches,
+            &value_log,
+            &bytewise,
+        )
+        .unwrap();
+        let err = store_file.get(&1_u32.to_be_bytes()).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let problems = store_file.verify_entries(&mut |_, _| {});
+        assert!(problems.iter().any(|problem| problem.contains("truncated")));
+    }
+
+    #[test]
+    fn compressed_blocks_are_smaller_and_read_back_the_same() {
+        let test_dir = Path::new(TEMP_TEST_FILE_DIR).join("store-file/compression");
+        let _ = fs::remove_dir_all(&test_dir);
+        fs::create_dir_all(&test_dir).unwrap();
+        let value = r#"{"x": 5, "string": "Hello, storage!"}"#.repeat(20).into_bytes();
+        let bytewise: Arc<dyn Comparator> = Arc::new(BytewiseComparator);
+
+        let caches = Arc::new(StoreFileCaches::new(
+            &StoreOptions::new().block_cache_size_in_bytes(0),
+        ));
+        let value_log = ValueLog::open(&test_dir, false, &caches).unwrap();
+        let mut file_sizes = Vec::new();
+        for (file_id, compression) in [Compression::None, Compression::Lz4, Compression::Zstd]
+            .into_iter()
+            .enumerate()
+        {
+            let path = test_dir.join(format!("{}.store.kv", file_id));
+            let mut writer =
+                StoreFileWriter::create(&path, &StoreOptions::new(), compression, &bytewise)
+                    .unwrap();
+            for i in 0..100_u32 {
+                let entry = TableEntry::Populated {
+                    value: value.clone(),
+                    expires_at: NO_EXPIRY,
+                };
+                writer.add(&i.to_be_bytes(), &entry).unwrap();
+            }
+            writer.finish().unwrap();
+
+            let store_file = StoreFile::open(
+                file_id as u64,
+                path.clone(),
+                false,
+                &caches,
+                &value_log,
+                &bytewise,
+            )
+            .unwrap();
+            assert_eq!(store_file.properties.compression, compression);
+            for i in 0..100_u32 {
+                match store_file.get(&i.to_be_bytes()).unwrap() {
+                    Some(TableEntry::Populated { value: read, .. }) => assert_eq!(read, value),
+                    entry => panic!("Key {} read back as {:?}", i, entry),
+                }
+            }
+            file_sizes.push(fs::metadata(&path).unwrap().len());
+        }
+        assert!(file_sizes[1] < file_sizes[0] / 2);
+        assert!(file_sizes[2] < file_sizes[0] / 2);
+    }
+}
@@ -0,0 +1,150 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::{
+    comparator::{BytewiseComparator, Comparator},
+    store_file::{StoreFile, StoreFileWriter},
+    sync_dir, wal, Store, StoreOptions, TableEntry, NO_EXPIRY, STORE_FILENAME_SUFFIX,
+    WRITE_AHEAD_LOG_FILENAME,
+};
+
+/// Where an upgraded store file is written before it replaces the original
+const UPGRADE_TEMP_FILENAME: &str = "upgrade.temp";
+
+/// Stores from before the manifest existed wrote their store files, and their WAL, as runs of
+/// records laid out as:
+///     key_size (4 bytes)
+///     key (key_size bytes)
+///     value_size (4 bytes)
+///     value (value_size bytes)
+///
+/// with every number little endian, no checksums, and an empty value for a tombstone. Returns the
+/// records that fit in bytes, and where the last of them ends
+pub(crate) fn parse_baseline_records(bytes: &[u8]) -> (Vec<(Vec<u8>, TableEntry)>, usize) {
+    let mut records = Vec::new();
+    let mut offset = 0;
+    while let Some((key, value_offset)) = read_sized(bytes, offset) {
+        let Some((value, end)) = read_sized(bytes, value_offset) else {
+            break;
+        };
+        let entry = if value.is_empty() {
+            TableEntry::Tombstone
+        } else {
+            TableEntry::Populated {
+                value: value.to_vec(),
+                expires_at: NO_EXPIRY,
+            }
+        };
+        records.push((key.to_vec(), entry));
+        offset = end;
+    }
+    return (records, offset);
+}
+
+/// Reads a 4 byte size and that many bytes after it, returning them and where they end
+fn read_sized(bytes: &[u8], offset: usize) -> Option<(&[u8], usize)> {
+    let size_bytes = bytes.get(offset..offset.checked_add(4)?)?;
+    let size = u32::from_le_bytes(size_bytes.try_into().unwrap()) as usize;
+    let end = (offset + 4).checked_add(size)?;
+    Some((bytes.get(offset + 4..end)?, end))
+}
+
+/// Stores in the original format are only read to upgrade them, which happens when they are opened
+/// for writing
+pub(crate) fn needs_upgrade_error(dir: &Path) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!(
+            "The store in {:?} is in the original format, and has to be opened for writing once to upgrade it",
+            dir
+        ),
+    )
+}
+
+/// Upgrades a store from before the manifest existed, before anything else reads it. Every step
+/// can be run again, so an upgrade that crashed part way through is finished by the next open
+pub(crate) fn upgrade_baseline_store(dir: &Path, options: &StoreOptions) -> io::Result<()> {
+    upgrade_baseline_store_files(dir, options)?;
+    upgrade_baseline_wal(dir, options)
+}
+
+/// Rewrites each store file under its own id, so they still shadow each other the same way. Files
+/// that already end in this format's magic number were upgraded before a crash
+fn upgrade_baseline_store_files(dir: &Path, options: &StoreOptions) -> io::Result<()> {
+    for path in Store::get_store_files(dir)? {
+        if StoreFile::has_magic_number(&path)? {
+            continue;
+        }
+        let bytes = fs::read(&path)?;
+        let (records, parsed_size) = parse_baseline_records(&bytes);
+        if parsed_size != bytes.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "Store file {:?} is corrupt: it has no footer, and isn't in the original format either",
+                    path
+                ),
+            ));
+        }
+        write_store_file(dir, &path, records, options)?;
+    }
+    sync_dir(dir)
+}
+
+/// Moves what the WAL holds into a store file newer than all the others, since the WAL is read in
+/// this format from now on. The WAL is only emptied once that store file is in place
+fn upgrade_baseline_wal(dir: &Path, options: &StoreOptions) -> io::Result<()> {
+    let wal_path = dir.join(WRITE_AHEAD_LOG_FILENAME);
+    // A store that only lost its manifest already has a WAL in this format, which its checksums
+    // tell apart from the original one
+    let contents = wal::read_wal(wal_path.clone())?;
+    if contents.corrupt_batch.is_none() && contents.intact_size_in_bytes == contents.size_in_bytes {
+        return Ok(());
+    }
+    let bytes = match fs::read(&wal_path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    // A record torn by a crash was never acknowledged, so it is dropped like a torn batch would be
+    let (records, _) = parse_baseline_records(&bytes);
+    if !records.is_empty() {
+        let highest_file_id = Store::get_store_files(dir)?
+            .iter()
+            .map(|path| Store::file_id_from_path(path))
+            .max()
+            .unwrap_or(1);
+        let path = dir.join((highest_file_id + 1).to_string() + STORE_FILENAME_SUFFIX);
+        write_store_file(dir, &path, records, options)?;
+        sync_dir(dir)?;
+    }
+    let wal = File::options().write(true).open(&wal_path)?;
+    wal.set_len(0)?;
+    wal.sync_all()
+}
+
+/// Writes records in the original format to a store file at path, replacing whatever is there
+fn write_store_file(
+    dir: &Path,
+    path: &Path,
+    records: Vec<(Vec<u8>, TableEntry)>,
+    options: &StoreOptions,
+) -> io::Result<()> {
+    // There were no other comparators back then
+    let comparator: Arc<dyn Comparator> = Arc::new(BytewiseComparator);
+    let temp_path = dir.join(UPGRADE_TEMP_FILENAME);
+    // Compaction didn't keep keys in order, and later records for a key replace earlier ones
+    let entries: BTreeMap<_, _> = records.into_iter().collect();
+    let mut writer =
+        StoreFileWriter::create(&temp_path, options, options.compression, &comparator)?;
+    for (key, entry) in &entries {
+        writer.add(key, entry)?;
+    }
+    writer.finish()?;
+    fs::rename(&temp_path, path)
+}
@@ -0,0 +1,269 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    os::unix::prelude::FileExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+};
+
+use crate::store_file::StoreFileCaches;
+
+const VALUE_LOG_FILENAME_SUFFIX: &str = ".vlog";
+
+/// Size of the crc32 after every value
+const VALUE_TRAILER_SIZE: u64 = 4;
+
+pub(crate) const VALUE_POINTER_SIZE: usize = 24;
+
+/// Where a value is in the value log
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ValuePointer {
+    pub(crate) segment_id: u64,
+    pub(crate) offset: u64,
+    pub(crate) size: u64,
+}
+
+impl ValuePointer {
+    pub(crate) fn encode(&self) -> [u8; VALUE_POINTER_SIZE] {
+        let mut bytes = [0; VALUE_POINTER_SIZE];
+        bytes[0..8].copy_from_slice(&self.segment_id.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.size.to_le_bytes());
+        return bytes;
+    }
+
+    /// Returns None if bytes isn't an encoded pointer
+    pub(crate) fn decode(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != VALUE_POINTER_SIZE {
+            return None;
+        }
+        Some(ValuePointer {
+            segment_id: u64::from_le_bytes(bytes[0..8].try_into().unwrap()),
+            offset: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            size: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        })
+    }
+
+    /// How much of its segment the value takes up
+    pub(crate) fn size_in_segment(&self) -> u64 {
+        self.size + VALUE_TRAILER_SIZE
+    }
+}
+
+fn corrupt_value_log(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Value log segment {:?} is corrupt: {}", path, reason),
+    )
+}
+
+/// One append only file of values. Like store files, segments are shared through an `Arc` by the
+/// store files that point into them, so a segment is only deleted once no read can need it
+pub(crate) struct ValueLogSegment {
+    pub(crate) id: u64,
+    pub(crate) path: PathBuf,
+    /// Read only stores keep every segment they use open, same as their store files
+    pinned_file: Option<File>,
+    /// Other stores read through the same file handle cache as their store files, under an id
+    /// from the block cache
+    caches: Arc<StoreFileCaches>,
+    cache_id: u64,
+    obsolete: AtomicBool,
+}
+
+impl ValueLogSegment {
+    pub(crate) fn read(&self, pointer: &ValuePointer) -> io::Result<Vec<u8>> {
+        let mut value = vec![0; pointer.size_in_segment() as usize];
+        match &self.pinned_file {
+            Some(file) => file.read_exact_at(&mut value, pointer.offset)?,
+            None => self
+                .caches
+                .files
+                .get_or_open(self.cache_id, &self.path)?
+                .read_exact_at(&mut value, pointer.offset)?,
+        }
+        let checksum = u32::from_le_bytes(value[pointer.size as usize..].try_into().unwrap());
+        value.truncate(pointer.size as usize);
+        if crc32fast::hash(&value) != checksum {
+            return Err(corrupt_value_log(
+                &self.path,
+                &format!("checksum mismatch for value at offset {}", pointer.offset),
+            ));
+        }
+        return Ok(value);
+    }
+
+    pub(crate) fn size_in_bytes(&self) -> io::Result<u64> {
+        match &self.pinned_file {
+            Some(file) => Ok(file.metadata()?.len()),
+            None => Ok(fs::metadata(&self.path)?.len()),
+        }
+    }
+
+    /// The segment is deleted from disk once the last store file using it is gone
+    pub(crate) fn mark_obsolete(&self) {
+        self.obsolete.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Drop for ValueLogSegment {
+    fn drop(&mut self) {
+        self.caches.files.evict(self.cache_id);
+        if self.obsolete.load(Ordering::SeqCst) {
+            if let Err(e) = fs::remove_file(&self.path) {
+                eprintln!(
+                    "Failed to remove garbage value log segment {:?}: {}",
+                    self.path, e
+                );
+            }
+        }
+    }
+}
+
+/// The value log segments of one column family. Values at least
+/// `StoreOptions::value_log_threshold_in_bytes` big are written here when their store file is
+/// written, and the store file only holds a pointer to them, so compactions can move the pointer
+/// around without rewriting the value
+pub(crate) struct ValueLog {
+    dir: PathBuf,
+    pin_files: bool,
+    caches: Arc<StoreFileCaches>,
+    /// Whichever segments are in use by a store file. Entries are left behind when a segment is
+    /// dropped, and replaced if it's used again
+    segments: Mutex<HashMap<u64, Weak<ValueLogSegment>>>,
+    last_segment_id: AtomicU64,
+}
+
+impl ValueLog {
+    pub(crate) fn open(
+        dir: &Path,
+        pin_files: bool,
+        caches: &Arc<StoreFileCaches>,
+    ) -> io::Result<Self> {
+        let last_segment_id = Self::segment_ids_in_dir(dir)?
+            .into_iter()
+            .max()
+            .unwrap_or(0);
+        Ok(ValueLog {
+            dir: dir.to_path_buf(),
+            pin_files,
+            caches: caches.clone(),
+            segments: Mutex::new(HashMap::new()),
+            last_segment_id: AtomicU64::new(last_segment_id),
+        })
+    }
+
+    fn segment_path(dir: &Path, id: u64) -> PathBuf {
+        dir.join(id.to_string() + VALUE_LOG_FILENAME_SUFFIX)
+    }
+
+    pub(crate) fn segment_ids_in_dir(dir: &Path) -> io::Result<Vec<u64>> {
+        let mut ids = Vec::new();
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            // Read only stores don't create directories for column families with no data yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(ids),
+            Err(e) => return Err(e),
+        };
+        for entry in entries {
+            let filename = entry?.file_name().to_string_lossy().to_string();
+            if let Some(id) = filename.strip_suffix(VALUE_LOG_FILENAME_SUFFIX) {
+                if let Ok(id) = id.parse() {
+                    ids.push(id);
+                }
+            }
+        }
+        return Ok(ids);
+    }
+
+    /// Shares the segment with any other store file already using it
+    pub(crate) fn segment(&self, id: u64) -> io::Result<Arc<ValueLogSegment>> {
+        let mut segments = self.segments.lock().unwrap();
+        if let Some(segment) = segments.get(&id).and_then(|segment| segment.upgrade()) {
+            return Ok(segment);
+        }
+        let path = Self::segment_path(&self.dir, id);
+        let pinned_file = if self.pin_files {
+            Some(File::open(&path)?)
+        } else {
+            if !path.exists() {
+                return Err(io::Error::new(
+                    io::ErrorKind::NotFound,
+                    format!("Value log segment {:?} is missing", path),
+                ));
+            }
+            None
+        };
+        let segment = Arc::new(ValueLogSegment {
+            id,
+            path,
+            pinned_file,
+            caches: self.caches.clone(),
+            cache_id: self.caches.blocks.new_file_id(),
+            obsolete: AtomicBool::new(false),
+        });
+        segments.insert(id, Arc::downgrade(&segment));
+        return Ok(segment);
+    }
+
+    /// Deletes segments no store file points into, left behind by a flush or compaction that
+    /// crashed before its store file was in place
+    pub(crate) fn remove_unused_segments(&self, used_ids: &[u64]) -> io::Result<()> {
+        for id in Self::segment_ids_in_dir(&self.dir)? {
+            if !used_ids.contains(&id) {
+                fs::remove_file(Self::segment_path(&self.dir, id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The segment is only created once a value is added to it
+    pub(crate) fn new_segment(&self) -> ValueLogWriter {
+        let id = self.last_segment_id.fetch_add(1, Ordering::SeqCst) + 1;
+        ValueLogWriter {
+            id,
+            path: Self::segment_path(&self.dir, id),
+            writer: None,
+            offset: 0,
+        }
+    }
+}
+
+/// Appends values to a new segment, each followed by a crc32 of the value
+pub(crate) struct ValueLogWriter {
+    pub(crate) id: u64,
+    path: PathBuf,
+    writer: Option<BufWriter<File>>,
+    offset: u64,
+}
+
+impl ValueLogWriter {
+    pub(crate) fn append(&mut self, value: &[u8]) -> io::Result<ValuePointer> {
+        if self.writer.is_none() {
+            self.writer = Some(BufWriter::new(File::create(&self.path)?));
+        }
+        let writer = self.writer.as_mut().unwrap();
+        writer.write_all(value)?;
+        writer.write_all(&crc32fast::hash(value).to_le_bytes())?;
+        let pointer = ValuePointer {
+            segment_id: self.id,
+            offset: self.offset,
+            size: value.len() as u64,
+        };
+        self.offset += pointer.size_in_segment();
+        return Ok(pointer);
+    }
+
+    /// Makes sure every value is on disk, before any store file pointing at them is
+    pub(crate) fn finish(self) -> io::Result<()> {
+        if let Some(mut writer) = self.writer {
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+        }
+        Ok(())
+    }
+}
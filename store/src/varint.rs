@@ -0,0 +1,79 @@
+use std::io::{self, Write};
+
+/// Most a u64 can take up, at 7 bits a byte
+const MAX_VARINT_SIZE: usize = 10;
+
+/// Writes value as an LEB128 varint: 7 bits at a time, lowest first, with the top bit of every
+/// byte but the last set. Returns how many bytes were written
+pub(crate) fn write_varint(writer: &mut impl Write, mut value: u64) -> io::Result<usize> {
+    let mut bytes = [0; MAX_VARINT_SIZE];
+    let mut size = 0;
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            bytes[size] = byte;
+            size += 1;
+            break;
+        }
+        bytes[size] = byte | 0x80;
+        size += 1;
+    }
+    writer.write_all(&bytes[..size])?;
+    return Ok(size);
+}
+
+/// Returns None if bytes ends part way through the varint, or it's too long to be a u64
+pub(crate) fn read_varint(bytes: &[u8], offset: &mut usize) -> Option<u64> {
+    let mut value: u64 = 0;
+    for (i, byte) in bytes
+        .get(*offset..)?
+        .iter()
+        .take(MAX_VARINT_SIZE)
+        .enumerate()
+    {
+        value |= ((byte & 0x7f) as u64) << (7 * i);
+        if byte & 0x80 == 0 {
+            *offset += i + 1;
+            return Some(value);
+        }
+    }
+    return None;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn varints_round_trip_and_stay_small() {
+        for value in [
+            0,
+            1,
+            127,
+            128,
+            300,
+            u32::MAX as u64,
+            u32::MAX as u64 + 1,
+            u64::MAX,
+        ] {
+            let mut bytes = Vec::new();
+            let size = write_varint(&mut bytes, value).unwrap();
+            assert_eq!(size, bytes.len());
+            let mut offset = 0;
+            assert_eq!(read_varint(&bytes, &mut offset), Some(value));
+            assert_eq!(offset, bytes.len());
+        }
+
+        let mut bytes = Vec::new();
+        write_varint(&mut bytes, 127).unwrap();
+        assert_eq!(bytes.len(), 1);
+        write_varint(&mut bytes, u64::MAX).unwrap();
+        assert_eq!(bytes.len(), 1 + MAX_VARINT_SIZE);
+
+        // Cut off part way through
+        let mut offset = 0;
+        assert_eq!(read_varint(&[0x80, 0x80], &mut offset), None);
+        assert_eq!(offset, 0);
+    }
+}
@@ -0,0 +1,189 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::PathBuf,
+};
+
+use crate::{Store, TableEntry, NO_EXPIRY};
+
+const PUT_ENTRY: u8 = 0;
+const TOMBSTONE_ENTRY: u8 = 1;
+/// Written with the start of the range as its key, and the end as its value
+const RANGE_TOMBSTONE_ENTRY: u8 = 2;
+
+/// Size of the payload size and checksum that precede every batch
+const BATCH_HEADER_SIZE: usize = 12;
+
+pub(crate) struct WalEntry {
+    pub(crate) column_family_id: u32,
+    pub(crate) key: Vec<u8>,
+    pub(crate) entry: TableEntry,
+}
+
+/// Appends a batch of entries to the WAL as a single record:
+///     payload_size (8 bytes)
+///     crc32 of the payload (4 bytes)
+///     payload, made of one of these per entry:
+///         column_family_id (4 bytes)
+///         entry kind (1 byte)
+///         key value record, as written to store files
+///
+/// A batch that was only partially written when we crashed fails its checksum, so none of it gets
+/// replayed. Returns how many bytes were appended
+pub(crate) fn append_batch<'a>(
+    writer: &mut BufWriter<File>,
+    entries: impl Iterator<Item = (u32, &'a [u8], &'a TableEntry)>,
+) -> io::Result<u64> {
+    let mut payload = Vec::new();
+    for (column_family_id, key, entry) in entries {
+        payload.write_all(&column_family_id.to_le_bytes())?;
+        match entry {
+            TableEntry::Tombstone => {
+                payload.write_all(&[TOMBSTONE_ENTRY])?;
+                Store::append_kv_to_file(&mut payload, key, NO_EXPIRY, None);
+            }
+            TableEntry::Populated { value, expires_at } => {
+                payload.write_all(&[PUT_ENTRY])?;
+                Store::append_kv_to_file(&mut payload, key, *expires_at, Some(value));
+            }
+            TableEntry::RangeTombstone { end } => {
+                payload.write_all(&[RANGE_TOMBSTONE_ENTRY])?;
+                Store::append_kv_to_file(&mut payload, key, NO_EXPIRY, Some(end));
+            }
+            TableEntry::InValueLog { .. } => {
+                unreachable!("Value pointers are never written to the WAL")
+            }
+        }
+    }
+
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&crc32fast::hash(&payload).to_le_bytes())?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+    Ok((BATCH_HEADER_SIZE + payload.len()) as u64)
+}
+
+pub(crate) fn restore_from_wal(write_ahead_log_path: PathBuf) -> io::Result<Vec<WalEntry>> {
+    let contents = read_wal(write_ahead_log_path)?;
+    if let Some(e) = contents.corrupt_batch {
+        return Err(e);
+    }
+    Ok(contents.entries)
+}
+
+/// Everything read from a WAL, along with how much of it was intact
+pub(crate) struct WalContents {
+    pub(crate) entries: Vec<WalEntry>,
+    /// Where the last intact batch ends. Anything after it was never replayed
+    pub(crate) intact_size_in_bytes: u64,
+    pub(crate) size_in_bytes: u64,
+    /// Why reading stopped at a batch whose checksum matches but that can't be parsed. A crash
+    /// can't leave one of those behind, so unlike a torn batch it isn't quietly dropped
+    pub(crate) corrupt_batch: Option<io::Error>,
+}
+
+pub(crate) fn read_wal(write_ahead_log_path: PathBuf) -> io::Result<WalContents> {
+    let mut write_ahead_log_file = match fs::File::options().read(true).open(write_ahead_log_path) {
+        Ok(file) => file,
+        Err(e) => match e.kind() {
+            io::ErrorKind::NotFound => {
+                // No WAL, nothing to restore!
+                return Ok(WalContents {
+                    entries: vec![],
+                    intact_size_in_bytes: 0,
+                    size_in_bytes: 0,
+                    corrupt_batch: None,
+                });
+            }
+            _ => return Err(e),
+        },
+    };
+
+    let mut buffer = Vec::new();
+    write_ahead_log_file.read_to_end(&mut buffer)?;
+    let mut byte_offset = 0;
+
+    let mut recovered_entries = Vec::new();
+    let mut corrupt_batch = None;
+
+    while byte_offset + BATCH_HEADER_SIZE <= buffer.len() {
+        let payload_size =
+            u64::from_le_bytes(buffer[byte_offset..byte_offset + 8].try_into().unwrap());
+        let checksum = u32::from_le_bytes(
+            buffer[byte_offset + 8..byte_offset + 12]
+                .try_into()
+                .unwrap(),
+        );
+        let payload_start = byte_offset + BATCH_HEADER_SIZE;
+        let payload_end = usize::try_from(payload_size)
+            .ok()
+            .and_then(|payload_size| payload_start.checked_add(payload_size))
+            .filter(|payload_end| {
+                *payload_end <= buffer.len()
+                    && crc32fast::hash(&buffer[payload_start..*payload_end]) == checksum
+            });
+        let Some(payload_end) = payload_end else {
+            // Torn write from a crash part way through appending a batch. Nothing after it can
+            // have been acknowledged, so stop here
+            break;
+        };
+
+        // Only whole batches are replayed, so entries before a malformed one are left out too
+        match parse_batch(&buffer[payload_start..payload_end]) {
+            Ok(entries) => recovered_entries.extend(entries),
+            Err(e) => {
+                corrupt_batch = Some(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("The WAL batch at byte {} is corrupt: {}", byte_offset, e),
+                ));
+                break;
+            }
+        }
+        byte_offset = payload_end;
+    }
+
+    return Ok(WalContents {
+        entries: recovered_entries,
+        intact_size_in_bytes: byte_offset as u64,
+        size_in_bytes: buffer.len() as u64,
+        corrupt_batch,
+    });
+}
+
+/// Parses the entries out of a batch's payload
+fn parse_batch(payload: &[u8]) -> io::Result<Vec<WalEntry>> {
+    let mut entries = Vec::new();
+    let mut offset = 0;
+    while offset < payload.len() {
+        let Some(header) = payload.get(offset..offset + 5) else {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the entry at byte {} is truncated", offset),
+            ));
+        };
+        let column_family_id = u32::from_le_bytes(header[..4].try_into().unwrap());
+        let entry_kind = header[4];
+        offset += 5;
+        let kv = Store::parse_key_value_from_bytes(&mut offset, payload)?;
+        let entry = match entry_kind {
+            TOMBSTONE_ENTRY => TableEntry::Tombstone,
+            RANGE_TOMBSTONE_ENTRY => TableEntry::RangeTombstone { end: kv.value },
+            PUT_ENTRY => TableEntry::Populated {
+                value: kv.value,
+                expires_at: kv.expires_at,
+            },
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("an entry has unknown kind {}", entry_kind),
+                ))
+            }
+        };
+        entries.push(WalEntry {
+            column_family_id,
+            key: kv.key,
+            entry,
+        });
+    }
+    Ok(entries)
+}
@@ -0,0 +1,75 @@
+use std::time::Duration;
+
+use crate::{now_in_millis, TableEntry, NO_EXPIRY};
+
+pub(crate) struct BatchEntry {
+    pub(crate) column_family: String,
+    pub(crate) key: Vec<u8>,
+    pub(crate) entry: TableEntry,
+}
+
+/// A group of writes that are applied atomically, even across column families. Either every write
+/// in the batch survives a crash, or none of them do
+#[derive(Default)]
+pub struct WriteBatch {
+    pub(crate) entries: Vec<BatchEntry>,
+}
+
+impl WriteBatch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn put(&mut self, column_family: &str, key: &[u8], value: &[u8]) {
+        self.put_with_expiry(column_family, key, value, NO_EXPIRY);
+    }
+
+    pub fn put_with_ttl(&mut self, column_family: &str, key: &[u8], value: &[u8], ttl: Duration) {
+        let ttl_in_millis = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX);
+        let expires_at = now_in_millis().saturating_add(ttl_in_millis);
+        self.put_with_expiry(column_family, key, value, expires_at);
+    }
+
+    pub(crate) fn put_with_expiry(
+        &mut self,
+        column_family: &str,
+        key: &[u8],
+        value: &[u8],
+        expires_at: u64,
+    ) {
+        self.entries.push(BatchEntry {
+            column_family: column_family.to_string(),
+            key: key.to_vec(),
+            entry: TableEntry::Populated {
+                value: value.to_vec(),
+                expires_at,
+            },
+        });
+    }
+
+    pub fn remove(&mut self, column_family: &str, key: &[u8]) {
+        self.entries.push(BatchEntry {
+            column_family: column_family.to_string(),
+            key: key.to_vec(),
+            entry: TableEntry::Tombstone,
+        });
+    }
+
+    /// Removes every key from start up to, but not including, end, in the column family's key
+    /// order
+    pub fn delete_range(&mut self, column_family: &str, start: &[u8], end: &[u8]) {
+        self.entries.push(BatchEntry {
+            column_family: column_family.to_string(),
+            key: start.to_vec(),
+            entry: TableEntry::RangeTombstone { end: end.to_vec() },
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
@@ -1,8 +1,9 @@
-use std::{net::TcpStream, path::PathBuf, thread};
+use std::{net::TcpStream, path::PathBuf, thread, time::Duration};
 
 use client::Client;
 use common::connection::Connection;
 use server::Server;
+use store::{Store, StoreOptions};
 
 #[test]
 fn ping() {
@@ -36,6 +37,108 @@ fn put_and_get() {
     let mut client = Client::new(connection);
     let key = "The almighty Omega key".as_bytes();
     let value = "Woowee for tests".as_bytes().to_vec();
-    assert!(client.put(&key, value.clone()).is_ok());
-    assert_eq!(client.get(&key).unwrap(), Some(value));
+    assert!(client.put(key, value.clone()).is_ok());
+    assert_eq!(client.get(key).unwrap(), Some(value));
+}
+
+#[test]
+fn put_with_ttl_and_get() {
+    let server_addr = "127.0.0.1:3335";
+    let store_dir = PathBuf::from("tmp/put_with_ttl");
+    let mut server = Server::new(server_addr.parse().unwrap(), &store_dir, false);
+
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let server_connection = TcpStream::connect(server_addr).unwrap();
+    let connection = Connection::new(server_connection);
+    let mut client = Client::new(connection);
+    let live_key = "Session that lives".as_bytes();
+    let expired_key = "Session that already expired".as_bytes();
+    let value = "Session data".as_bytes().to_vec();
+    assert!(client
+        .put_with_ttl(live_key, value.clone(), Duration::from_secs(60 * 60))
+        .is_ok());
+    assert!(client
+        .put_with_ttl(expired_key, value.clone(), Duration::ZERO)
+        .is_ok());
+    assert_eq!(client.get(live_key).unwrap(), Some(value));
+    assert_eq!(client.get(expired_key).unwrap(), None);
+}
+
+#[test]
+fn oversized_puts_are_refused() {
+    let server_addr = "127.0.0.1:3336";
+    let store_dir = PathBuf::from("tmp/oversized_put");
+    let _ = std::fs::remove_dir_all(&store_dir);
+    let options = StoreOptions::new().max_value_size_in_bytes(16);
+    let mut server = Server::with_options(server_addr.parse().unwrap(), &store_dir, &options);
+
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let server_connection = TcpStream::connect(server_addr).unwrap();
+    let connection = Connection::new(server_connection);
+    let mut client = Client::new(connection);
+    let key = "Key with a huge value".as_bytes();
+    let err = client.put(key, vec![1; 17]).err().unwrap();
+    assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+    assert_eq!(client.get(key).unwrap(), None);
+
+    // The server carries on as normal afterwards
+    assert!(client.put(key, vec![1; 16]).is_ok());
+    assert_eq!(client.get(key).unwrap(), Some(vec![1; 16]));
+}
+
+#[test]
+fn failed_gets_return_errors() {
+    let server_addr = "127.0.0.1:3338";
+    let store_dir = PathBuf::from("tmp/failed_get");
+    let _ = std::fs::remove_dir_all(&store_dir);
+    let store = Store::open(&store_dir, &StoreOptions::new()).unwrap();
+    store.put(b"Damaged key", b"Lost value").unwrap();
+    store.close().unwrap();
+    let store_file = store_dir.join("2.store.kv");
+    let mut bytes = std::fs::read(&store_file).unwrap();
+    bytes[5] ^= 0xff;
+    std::fs::write(&store_file, bytes).unwrap();
+    let mut server = Server::new(server_addr.parse().unwrap(), &store_dir, true);
+
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let server_connection = TcpStream::connect(server_addr).unwrap();
+    let connection = Connection::new(server_connection);
+    let mut client = Client::new(connection);
+    assert!(client.get(b"Damaged key").is_err());
+
+    // The server carries on as normal afterwards
+    assert!(client.ping().is_ok());
+}
+
+#[test]
+fn stats() {
+    let server_addr = "127.0.0.1:3337";
+    let store_dir = PathBuf::from("tmp/stats");
+    let mut server = Server::new(server_addr.parse().unwrap(), &store_dir, false);
+
+    thread::spawn(move || {
+        server.run();
+    });
+
+    let server_connection = TcpStream::connect(server_addr).unwrap();
+    let connection = Connection::new(server_connection);
+    let mut client = Client::new(connection);
+    let key = "Counted key".as_bytes();
+    assert!(client.put(key, vec![1; 8]).is_ok());
+    assert_eq!(client.get(key).unwrap(), Some(vec![1; 8]));
+    let stats = client.stats().unwrap();
+    assert_eq!(stats["puts"], 1);
+    assert_eq!(stats["gets"], 1);
+    assert_eq!(stats["get_micros.count"], 1);
+    assert!(stats["wal_bytes_written"] > 0);
+    assert_eq!(stats["store_files.default"], 0);
 }